@@ -1,5 +1,3 @@
-use std::collections::HashSet;
-
 use assembunny::Vm;
 use std::io::Read;
 use std::str::FromStr;
@@ -9,23 +7,13 @@ fn main() {
     std::io::stdin().read_to_string(&mut input).unwrap();
     let vm = Vm::from_str(&input).unwrap();
 
-    for i in 0.. {
-        let mut vm = vm.clone();
-        vm.regs[0] = i;
-        let mut seq = Vec::new();
-        let mut seen = HashSet::new();
-        while let Some(i) = vm.next() {
-            if seen.contains(&vm) {
-                break;
-            } else {
-                seen.insert(vm.clone());
-            }
-            seq.push(i);
-        }
-        if seq.iter().zip([0, 1].iter().cycle()).all(|(a, b)| a == b) {
-            println!("Part 1: {}", i);
-            break;
-        }
-    }
+    let a = (0..)
+        .find(|&a| {
+            let mut vm = vm.clone();
+            vm.regs[0] = a;
+            vm.detect_cycle(1_000_000).is_some_and(|c| c.is_clock())
+        })
+        .unwrap();
+    println!("Part 1: {}", a);
     println!("Part 2: ☆");
 }
@@ -0,0 +1,28 @@
+use std::io::Read;
+use std::str::FromStr;
+
+use assembunny::{diff, Vm};
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let trials = args.next().map_or(1000, |s| s.parse().unwrap());
+    let seed = args.next().map_or(1, |s| s.parse().unwrap());
+
+    let mut input = String::new();
+    std::io::stdin().read_to_string(&mut input).unwrap();
+    let vm = Vm::from_str(&input).unwrap();
+
+    let report = diff::fuzz(&vm, trials, seed);
+    for d in &report.divergences {
+        println!("{}", d);
+    }
+    println!(
+        "{} trials, {} inconclusive, {} divergences",
+        report.trials,
+        report.inconclusive,
+        report.divergences.len()
+    );
+    if !report.divergences.is_empty() {
+        std::process::exit(1);
+    }
+}
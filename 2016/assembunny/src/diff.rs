@@ -0,0 +1,209 @@
+//! Differential testing, which runs the optimized VM side by side with a
+//! plain interpreter and reports any point where they disagree.
+use std::fmt;
+
+use crate::{Vm, RULES};
+
+/// Maximum number of outputs to compare, since `out` programs may run forever
+const MAX_OUTPUTS: usize = 16;
+
+/// Maximum number of steps for the reference VM to reach the next output
+const STEP_LIMIT: usize = 1_000_000;
+
+/// The state of a VM when it produces an output or halts
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Outcome {
+    pub out: Option<i32>,
+    pub ip: i32,
+    pub regs: [i32; 4],
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.out {
+            Some(o) => write!(f, "output {}", o)?,
+            None => write!(f, "halt")?,
+        }
+        write!(f, " at ip {} with registers {:?}", self.ip, self.regs)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Divergence {
+    /// Initial register values
+    pub input: [i32; 4],
+    /// Index of the first output (or halt) which differs
+    pub event: usize,
+    /// Outcome from the optimized VM, or `None` if it ran out of steps
+    pub optimized: Option<Outcome>,
+    pub reference: Outcome,
+    /// Rules which cause a divergence when enabled on their own
+    pub rules: Vec<&'static str>,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "Divergence with input {:?} at event {}",
+            self.input, self.event
+        )?;
+        writeln!(f, "  reference: {}", self.reference)?;
+        match &self.optimized {
+            Some(o) => writeln!(f, "  optimized: {}", o)?,
+            None => writeln!(f, "  optimized: ran out of steps")?,
+        }
+        write!(f, "  rules: {}", self.rules.join(", "))
+    }
+}
+
+/// Summary of a batch of differential tests
+#[derive(Clone, Debug, Default)]
+pub struct Report {
+    pub trials: usize,
+    /// Trials where the reference VM ran out of steps, so nothing was checked
+    pub inconclusive: usize,
+    pub divergences: Vec<Divergence>,
+}
+
+/// Runs until the next output or halt, giving up after `STEP_LIMIT` steps
+fn advance(vm: &mut Vm) -> Option<Outcome> {
+    let mut out = None;
+    for _ in 0..STEP_LIMIT {
+        if !vm.running() {
+            break;
+        }
+        out = vm.step();
+        if out.is_some() {
+            break;
+        }
+    }
+    if out.is_none() && vm.running() {
+        return None;
+    }
+    Some(Outcome {
+        out,
+        ip: vm.ip,
+        regs: vm.regs,
+    })
+}
+
+/// Compares `vm` (with its current rules) against a plain interpreter,
+/// starting from the given registers.  Returns `Ok(false)` if the reference
+/// interpreter ran out of steps before anything could be compared.
+fn compare(vm: &Vm, input: [i32; 4]) -> Result<bool, (usize, Option<Outcome>, Outcome)> {
+    let mut opt = vm.clone();
+    opt.regs = input;
+    let mut reference = opt.clone();
+    reference.set_rules(&[]);
+
+    for event in 0..MAX_OUTPUTS {
+        let r = match advance(&mut reference) {
+            Some(r) => r,
+            None => return Ok(event > 0),
+        };
+        let o = advance(&mut opt);
+        if o.as_ref() != Some(&r) || opt != reference {
+            return Err((event, o, r));
+        }
+        if r.out.is_none() {
+            break;
+        }
+    }
+    Ok(true)
+}
+
+/// Checks a single input, returning `Ok(false)` if the test was inconclusive
+pub fn check(vm: &Vm, input: [i32; 4]) -> Result<bool, Divergence> {
+    compare(vm, input).map_err(|(event, optimized, reference)| {
+        // Find which rules are responsible by trying them one at a time
+        let rules = RULES
+            .iter()
+            .filter(|r| {
+                let mut v = vm.clone();
+                v.set_rules(&[*r]);
+                compare(&v, input).is_err()
+            })
+            .map(|r| r.name)
+            .collect();
+        Divergence {
+            input,
+            event,
+            optimized,
+            reference,
+            rules,
+        }
+    })
+}
+
+/// Runs the given number of trials with pseudo-random register inputs
+pub fn fuzz(vm: &Vm, trials: usize, seed: u64) -> Report {
+    // xorshift64, which must be seeded with a non-zero value
+    let mut state = seed.max(1);
+    let mut rand = move || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state
+    };
+
+    let mut report = Report {
+        trials,
+        ..Report::default()
+    };
+    for _ in 0..trials {
+        let mut input = [0; 4];
+        for r in input.iter_mut() {
+            *r = (rand() % 11) as i32 - 1;
+        }
+        match check(vm, input) {
+            Ok(true) => (),
+            Ok(false) => report.inconclusive += 1,
+            Err(d) => report.divergences.push(d),
+        }
+    }
+    report
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fuzz_day12() {
+        // Shaped like a day 12 input (Fibonacci numbers with a few extra
+        // loops on top), with smaller constants
+        let vm: Vm = "cpy 1 a\ncpy 1 b\ncpy 6 d\njnz c 2\njnz 1 5\ncpy 3 c\n\
+                      inc d\ndec c\njnz c -2\ncpy a c\ninc a\ndec b\njnz b -2\n\
+                      cpy c b\ndec d\njnz d -6\ncpy 4 c\ncpy 5 d\ninc a\ndec d\n\
+                      jnz d -2\ndec c\njnz c -5"
+            .parse()
+            .unwrap();
+        let report = fuzz(&vm, 50, 12);
+        assert_eq!(report.inconclusive, 0);
+        assert!(report.divergences.is_empty(), "{}", report.divergences[0]);
+    }
+
+    #[test]
+    fn fuzz_day23() {
+        // Shaped like a day 23 input (a factorial, then a `tgl` loop which
+        // rewrites the end of the program), with smaller constants
+        let vm: Vm = "cpy a b\ndec b\ncpy a d\ncpy 0 a\ncpy b c\ninc a\ndec c\n\
+                      jnz c -2\ndec d\njnz d -5\ndec b\ncpy b c\ncpy c d\ndec d\n\
+                      inc c\njnz d -2\ntgl c\ncpy -16 c\njnz 1 c\ncpy 7 c\n\
+                      jnz 5 d\ninc a\ninc d\njnz d -2\ninc c\njnz c -5"
+            .parse()
+            .unwrap();
+        let report = fuzz(&vm, 50, 23);
+        assert!(report.inconclusive < report.trials);
+        assert!(report.divergences.is_empty(), "{}", report.divergences[0]);
+
+        // The puzzle's own input value must be checked
+        assert_eq!(
+            check(&vm, [7, 0, 0, 0]).map_err(|d| d.to_string()),
+            Ok(true)
+        );
+    }
+}
@@ -1,5 +1,40 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::{Hash, Hasher};
 use std::str::FromStr;
 
+pub mod diff;
+mod optimize;
+
+use crate::optimize::Op;
+pub use crate::optimize::{Rule, RULES};
+
+/// A syntax error in an assembunny program.  Lines and columns start at 1.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseError {
+    pub line: usize,
+    pub column: usize,
+    pub expected: &'static str,
+    pub found: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "line {}, column {}: expected {}, found ",
+            self.line, self.column, self.expected
+        )?;
+        if self.found.is_empty() {
+            write!(f, "end of line")
+        } else {
+            write!(f, "'{}'", self.found)
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
 #[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
 enum Value {
     Lit(i32),
@@ -16,19 +51,29 @@ impl Value {
 }
 
 impl FromStr for Value {
-    type Err = ();
-    fn from_str(s: &str) -> Result<Self, ()> {
+    type Err = ParseError;
+    fn from_str(s: &str) -> Result<Self, ParseError> {
         if let Ok(i) = i32::from_str(s) {
             Ok(Self::Lit(i))
-        } else if s.len() == 1 {
-            let c = s.chars().next().unwrap();
-            if c >= 'a' && c <= 'd' {
-                Ok(Self::Reg((c as u32 - b'a' as u32) as usize))
-            } else {
-                Err(())
-            }
         } else {
-            Err(())
+            Value::register(s).map_err(|e| ParseError {
+                expected: "integer or register (a-d)",
+                ..e
+            })
+        }
+    }
+}
+
+impl Value {
+    fn register(s: &str) -> Result<Self, ParseError> {
+        match s.as_bytes() {
+            [c @ b'a'..=b'd'] => Ok(Self::Reg((c - b'a') as usize)),
+            _ => Err(ParseError {
+                line: 1,
+                column: 1,
+                expected: "register (a-d)",
+                found: s.to_owned(),
+            }),
         }
     }
 }
@@ -48,55 +93,150 @@ enum Instruction {
 use Instruction::*;
 
 impl FromStr for Instruction {
-    type Err = ();
-    fn from_str(s: &str) -> Result<Self, ()> {
-        let mut itr = s.split(' ');
-        let op = itr.next();
-        if op.is_none() {
-            return Err(());
+    type Err = ParseError;
+    fn from_str(s: &str) -> Result<Self, ParseError> {
+        // Split into words, keeping track of their (1-indexed) columns
+        let mut words = vec![];
+        let mut start = None;
+        for (i, c) in s.char_indices().chain(std::iter::once((s.len(), ' '))) {
+            if !c.is_whitespace() {
+                start = start.or(Some(i));
+            } else if let Some(j) = start.take() {
+                words.push((s[..j].chars().count() + 1, &s[j..i]));
+            }
         }
+        let end = s.chars().count() + 1;
 
-        let op = op.unwrap();
-        let args = itr
-            .filter_map(|v| Value::from_str(v).ok())
-            .collect::<Vec<_>>();
-        match op {
-            "cpy" => Ok(cpy(args[0], args[1])),
-            "inc" => Ok(inc(args[0])),
-            "dec" => Ok(dec(args[0])),
-            "jnz" => Ok(jnz(args[0], args[1])),
-            "tgl" => Ok(tgl(args[0])),
-            "out" => Ok(out(args[0])),
-            _ => Err(()),
+        let (op, args) = match words.split_first() {
+            Some(((_, op), args)) => (*op, args),
+            None => {
+                return Err(ParseError {
+                    line: 1,
+                    column: end,
+                    expected: "instruction",
+                    found: String::new(),
+                })
+            }
+        };
+        let (form, count) = match op {
+            "cpy" => ("cpy x r", 2),
+            "inc" => ("inc r", 1),
+            "dec" => ("dec r", 1),
+            "jnz" => ("jnz x y", 2),
+            "tgl" => ("tgl x", 1),
+            "out" => ("out x", 1),
+            _ => {
+                return Err(ParseError {
+                    line: 1,
+                    column: words[0].0,
+                    expected: "one of cpy, inc, dec, jnz, tgl, out",
+                    found: op.to_owned(),
+                })
+            }
+        };
+        if args.len() != count {
+            let (column, found) = args
+                .get(count)
+                .map(|(c, w)| (*c, w.to_string()))
+                .unwrap_or((end, String::new()));
+            return Err(ParseError {
+                line: 1,
+                column,
+                expected: form,
+                found,
+            });
         }
+
+        // Registers are required as the target of cpy, inc, and dec, though
+        // tgl may later produce instructions which break this rule.
+        let at = |i: usize, r: Result<Value, ParseError>| {
+            r.map_err(|e| ParseError {
+                column: args[i].0,
+                ..e
+            })
+        };
+        let val = |i: usize| at(i, Value::from_str(args[i].1));
+        let reg = |i: usize| at(i, Value::register(args[i].1));
+        Ok(match op {
+            "cpy" => cpy(val(0)?, reg(1)?),
+            "inc" => inc(reg(0)?),
+            "dec" => dec(reg(0)?),
+            "jnz" => jnz(val(0)?, val(1)?),
+            "tgl" => tgl(val(0)?),
+            "out" => out(val(0)?),
+            _ => unreachable!(),
+        })
     }
 }
 
 ////////////////////////////////////////////////////////////////////////////////
 
-#[derive(Clone, Debug, Hash, Eq, PartialEq)]
+/// An assembunny interpreter.  Equality and hashing only consider the
+/// program and machine state, not which optimizations are enabled.
+#[derive(Clone, Debug)]
 pub struct Vm {
     instructions: Vec<Instruction>,
     ip: i32,
     pub regs: [i32; 4],
+
+    /// Optimization rules which are enabled for this VM
+    rules: Vec<&'static Rule>,
+    /// Optimized ops, indexed by the instruction at the head of their loop
+    ops: Vec<Option<Op>>,
+}
+
+impl PartialEq for Vm {
+    fn eq(&self, other: &Self) -> bool {
+        self.instructions == other.instructions && self.ip == other.ip && self.regs == other.regs
+    }
+}
+
+impl Eq for Vm {}
+
+impl Hash for Vm {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.instructions.hash(state);
+        self.ip.hash(state);
+        self.regs.hash(state);
+    }
 }
 
 impl FromStr for Vm {
-    type Err = ();
-    fn from_str(s: &str) -> Result<Self, ()> {
-        let instructions = s
-            .lines()
-            .filter_map(|line| Instruction::from_str(&line).ok())
-            .collect::<Vec<_>>();
-        Ok(Vm {
+    type Err = Vec<ParseError>;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Vm::parse(s)
+    }
+}
+
+impl Vm {
+    /// Parses a program, skipping blank lines and reporting every line
+    /// which isn't a valid instruction
+    pub fn parse(s: &str) -> Result<Self, Vec<ParseError>> {
+        let mut instructions = vec![];
+        let mut errors = vec![];
+        for (i, line) in s.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            match Instruction::from_str(line) {
+                Ok(instruction) => instructions.push(instruction),
+                Err(e) => errors.push(ParseError { line: i + 1, ..e }),
+            }
+        }
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        let mut vm = Vm {
             instructions,
             ip: 0,
             regs: [0; 4],
-        })
+            rules: RULES.iter().collect(),
+            ops: vec![],
+        };
+        vm.optimize();
+        Ok(vm)
     }
-}
 
-impl Vm {
     fn get(&self, v: Value) -> i32 {
         match v {
             Value::Lit(i) => i,
@@ -104,82 +244,27 @@ impl Vm {
         }
     }
 
-    /*
-     *  Peephole optimizer looking for particular patterns which we can
-     *  optimize into more efficient versions */
-    fn peephole(&mut self, start: i32, end: i32) -> bool {
-        use Value::*;
-        match self.instructions[start as usize..=end as usize] {
-            [cpy(Reg(a), Reg(b)), inc(Reg(c)), dec(Reg(b1)), jnz(Reg(b2), Lit(-2)), dec(Reg(d)), jnz(Reg(d1), Lit(-5))]
-                if b == b1
-                    && b == b2
-                    && d == d1
-                    && a != b
-                    && a != c
-                    && a != d
-                    && b != c
-                    && b != d
-                    && c != d =>
-            {
-                self.regs[c] += self.regs[a] * self.regs[d];
-                self.regs[b] = 0;
-                self.regs[d] = 0;
-                self.ip += 1;
-                true
-            }
-
-            [cpy(Lit(a), Reg(b)), inc(Reg(c)), dec(Reg(b1)), jnz(Reg(b2), Lit(-2)), dec(Reg(d)), jnz(Reg(d1), Lit(-5))]
-                if b == b1 && b == b2 && d == d1 && b != c && b != d && c != d =>
-            {
-                self.regs[c] += a * self.regs[d];
-                self.regs[b] = 0;
-                self.regs[d] = 0;
-                self.ip += 1;
-                true
-            }
-
-            [inc(Reg(a)), dec(Reg(b)), jnz(Reg(b1), Lit(-2))] if b == b1 && a != b => {
-                self.regs[a] += self.regs[b];
-                self.regs[b] = 0;
-                self.ip += 1;
-                true
-            }
-
-            [dec(Reg(a)), inc(Reg(b)), jnz(Reg(a1), Lit(-2))] if a == a1 && a != b => {
-                self.regs[b] += self.regs[a];
-                self.regs[a] = 0;
-                self.ip += 1;
-                true
-            }
-
-            [jnz(Reg(a), Lit(2)), jnz(Lit(1), Lit(4)), dec(Reg(b)), dec(Reg(a1)), jnz(Lit(1), Lit(-4))]
-                if a != b && a == a1 =>
-            {
-                self.regs[b] -= self.regs[a];
-                self.regs[a] = 0;
-                self.ip += 1;
-                true
-            }
-
-            [jnz(Reg(a), Lit(2)), jnz(Lit(1), Lit(6)), dec(Reg(a1)), dec(Reg(b)), jnz(Reg(b1), Lit(-4))]
-                if a == a1 && b == b1 && a != b =>
-            {
-                if self.regs[a] < self.regs[b] {
-                    self.regs[b] -= self.regs[a];
-                    self.regs[a] = 0;
-                    self.ip += 3;
-                } else {
-                    self.regs[a] -= self.regs[b];
-                    self.regs[b] = 0;
-                    self.ip += 1;
-                }
-                true
-            }
+    /// Selects which optimization rules are used.  Passing an empty slice
+    /// gives a plain interpreter, which is useful as a reference.
+    pub fn set_rules(&mut self, rules: &[&'static Rule]) {
+        self.rules = rules.to_vec();
+        self.optimize();
+    }
 
-            _ => false,
-        }
+    /// Rebuilds the table of optimized ops from the current program
+    fn optimize(&mut self) {
+        self.ops = (0..self.instructions.len())
+            .map(|i| {
+                self.rules
+                    .iter()
+                    .find_map(|r| r.find(&self.instructions[i..]))
+            })
+            .collect();
     }
 
+    /// Executes a single instruction.  `tgl` can produce invalid
+    /// instructions (such as `cpy 1 2`) which the parser would reject;
+    /// per the puzzle rules, these are skipped.
     fn apply(&mut self, i: Instruction) -> Option<i32> {
         match i {
             cpy(a, b) => {
@@ -205,15 +290,7 @@ impl Vm {
             }
             jnz(a, b) => {
                 if self.get(a) != 0 {
-                    // This is the jump target
-                    let target = self.ip + self.get(b);
-
-                    // If b is a literal value, then try to optimize the loop
-                    if target < self.ip && b.reg() == None && self.peephole(target, self.ip) {
-                        // Optimization succeeded!
-                    } else {
-                        self.ip = target;
-                    }
+                    self.ip += self.get(b);
                 } else {
                     self.ip += 1;
                 };
@@ -231,6 +308,10 @@ impl Vm {
                         out(a) => inc(a),
                     };
                     self.instructions[target as usize] = i;
+
+                    // The program has changed, so any loop which touched
+                    // this instruction may no longer match its rule
+                    self.optimize();
                 }
                 self.ip += 1;
                 None
@@ -241,20 +322,223 @@ impl Vm {
             }
         }
     }
+
+    /// Checks whether the instruction pointer is still within the program
+    pub fn running(&self) -> bool {
+        self.ip >= 0 && (self.ip as usize) < self.instructions.len()
+    }
+
+    /// Executes a single instruction (or optimized op), returning a value
+    /// if one was output.  Must only be called while the VM is running.
+    pub fn step(&mut self) -> Option<i32> {
+        let ip = self.ip as usize;
+        if let Some(op) = self.ops[ip] {
+            if let Some(offset) = op.exec(&mut self.regs) {
+                self.ip += offset;
+                return None;
+            }
+        }
+        self.apply(self.instructions[ip])
+    }
+
     pub fn run(&mut self) {
-        while self.ip >= 0 && (self.ip as usize) < self.instructions.len() {
-            let i = self.instructions[self.ip as usize];
-            self.apply(i);
+        while self.running() {
+            self.step();
         }
     }
 
+    #[allow(clippy::should_implement_trait)]
     pub fn next(&mut self) -> Option<i32> {
-        while self.ip >= 0 && (self.ip as usize) < self.instructions.len() {
-            let i = self.instructions[self.ip as usize];
-            if let Some(o) = self.apply(i) {
+        while self.running() {
+            if let Some(o) = self.step() {
                 return Some(o);
             }
         }
         None
     }
+
+    /// Returns an iterator over values from `out`, which ends if the
+    /// program halts
+    pub fn run_outputs(&mut self) -> Outputs<'_> {
+        Outputs(self)
+    }
+
+    /// Runs the program, hashing the full machine state after every `out`.
+    /// If a state repeats, then the outputs since its first appearance will
+    /// repeat forever.  Returns `None` if the program halts or runs for
+    /// `max_steps` without a repeated state.
+    pub fn detect_cycle(&self, max_steps: usize) -> Option<Cycle> {
+        let mut vm = self.clone();
+        let mut outputs = vec![];
+        let mut seen = HashMap::new();
+        for _ in 0..max_steps {
+            if !vm.running() {
+                return None;
+            }
+            if let Some(o) = vm.step() {
+                outputs.push(o);
+                if let Some(&i) = seen.get(&vm) {
+                    let period = outputs.split_off(i);
+                    return Some(Cycle::new(outputs, period));
+                }
+                seen.insert(vm.clone(), outputs.len());
+            }
+        }
+        None
+    }
+}
+
+pub struct Outputs<'a>(&'a mut Vm);
+
+impl Iterator for Outputs<'_> {
+    type Item = i32;
+    fn next(&mut self) -> Option<i32> {
+        self.0.next()
+    }
+}
+
+/// An infinite output sequence, made of `prefix` then `period` repeated
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Cycle {
+    pub prefix: Vec<i32>,
+    pub period: Vec<i32>,
+}
+
+impl Cycle {
+    /// Builds a cycle with the shortest possible prefix and period
+    fn new(mut prefix: Vec<i32>, mut period: Vec<i32>) -> Self {
+        // Shift the start of the period back as far as possible
+        while !prefix.is_empty() && prefix.last() == period.last() {
+            prefix.pop();
+            period.rotate_right(1);
+        }
+        // Then find the shortest repeating unit
+        let n = (1..=period.len())
+            .find(|n| {
+                period.len().is_multiple_of(*n)
+                    && period.iter().zip(&period[*n..]).all(|(a, b)| a == b)
+            })
+            .unwrap_or(0);
+        period.truncate(n);
+        Cycle { prefix, period }
+    }
+
+    /// Checks whether this sequence is the clock signal `0, 1, 0, 1, ...`
+    pub fn is_clock(&self) -> bool {
+        self.period.len().is_multiple_of(2)
+            && self
+                .prefix
+                .iter()
+                .chain(&self.period)
+                .zip([0, 1].iter().cycle())
+                .all(|(a, b)| a == b)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_errors() {
+        let errs = Vm::parse("cpy 1 a\ncpy 1 e\n\nmov a b\njnz a\ninc 3\ndec a b").unwrap_err();
+        let pos = errs
+            .iter()
+            .map(|e| (e.line, e.column, e.found.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            pos,
+            vec![
+                (2, 7, "e"),
+                (4, 1, "mov"),
+                (5, 6, ""),
+                (6, 5, "3"),
+                (7, 7, "b")
+            ]
+        );
+        assert_eq!(errs[0].expected, "register (a-d)");
+        assert_eq!(errs[2].expected, "jnz x y");
+        assert_eq!(
+            errs[2].to_string(),
+            "line 5, column 6: expected jnz x y, found end of line"
+        );
+        assert_eq!(
+            errs[1].to_string(),
+            "line 4, column 1: expected one of cpy, inc, dec, jnz, tgl, out, found 'mov'"
+        );
+    }
+
+    #[test]
+    fn parse_values() {
+        let vm = Vm::parse("cpy -12 a\njnz c -2\n  out   d  \n").unwrap();
+        assert_eq!(
+            vm.instructions,
+            vec![
+                cpy(Value::Lit(-12), Value::Reg(0)),
+                jnz(Value::Reg(2), Value::Lit(-2)),
+                out(Value::Reg(3)),
+            ]
+        );
+        let errs = Vm::parse("jnz x 2").unwrap_err();
+        assert_eq!(errs[0].expected, "integer or register (a-d)");
+        assert_eq!(errs[0].column, 5);
+    }
+
+    #[test]
+    fn toggle_example() {
+        let mut vm = Vm::parse("cpy 2 a\ntgl a\ntgl a\ntgl a\ncpy 1 a\ndec a\ndec a").unwrap();
+        vm.run();
+        assert_eq!(vm.regs[0], 3);
+    }
+
+    #[test]
+    fn toggle_invalid() {
+        // Toggling `jnz 1 2` gives `cpy 1 2`, which must be skipped when
+        // executed; `tgl c` toggles itself into `inc c` without running it.
+        for rules in &[vec![], RULES.iter().collect::<Vec<_>>()] {
+            let mut vm = Vm::parse("cpy 3 a\ntgl a\ninc b\ntgl c\njnz 1 2\ninc b\ninc b").unwrap();
+            vm.set_rules(rules);
+            vm.run();
+            assert_eq!(vm.instructions[4], cpy(Value::Lit(1), Value::Lit(2)));
+            assert_eq!(vm.instructions[3], inc(Value::Reg(2)));
+            assert_eq!(vm.regs, [3, 3, 0, 0]);
+        }
+    }
+
+    #[test]
+    fn cycles() {
+        let clock = "cpy a d\ncpy 4 c\ncpy 633 b\ninc d\ndec b\njnz b -2\ndec c\njnz c -5\n\
+                     cpy d a\njnz 0 0\ncpy a b\ncpy 0 a\ncpy 2 c\njnz b 2\njnz 1 6\ndec b\n\
+                     dec c\njnz c -4\ninc a\njnz 1 -7\ncpy 2 b\njnz c 2\njnz 1 4\ndec b\n\
+                     dec c\njnz 1 -4\njnz 0 0\nout b\njnz a -19\njnz 1 -21";
+        let mut vm = Vm::parse(clock).unwrap();
+        vm.regs[0] = 198;
+        let c = vm.detect_cycle(100_000).unwrap();
+        assert!(c.prefix.is_empty());
+        assert_eq!(c.period, vec![0, 1]);
+        assert!(c.is_clock());
+        assert_eq!(
+            vm.run_outputs().take(5).collect::<Vec<_>>(),
+            vec![0, 1, 0, 1, 0]
+        );
+
+        vm.regs[0] = 197;
+        assert!(!vm.detect_cycle(100_000).unwrap().is_clock());
+
+        // A prefix before the repeating part, and a program which halts
+        let mut vm = Vm::parse("out 5\nout 0\nout 1\njnz 1 -2").unwrap();
+        let c = vm.detect_cycle(100).unwrap();
+        assert_eq!(c.prefix, vec![5]);
+        assert_eq!(c.period, vec![0, 1]);
+        assert!(!c.is_clock());
+        assert_eq!(
+            vm.run_outputs().take(4).collect::<Vec<_>>(),
+            vec![5, 0, 1, 0]
+        );
+        let mut vm = Vm::parse("out 0\nout 1").unwrap();
+        assert_eq!(vm.detect_cycle(100), None);
+        assert_eq!(vm.run_outputs().collect::<Vec<_>>(), vec![0, 1]);
+    }
 }
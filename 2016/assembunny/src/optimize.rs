@@ -0,0 +1,302 @@
+//! Peephole optimizer.
+//!
+//! Each rule looks for a particular loop at the start of a slice of the
+//! program and rewrites it into a single internal op.  The VM keeps one
+//! (optional) op per instruction, and runs it when execution reaches the
+//! head of the loop.  If the op's preconditions don't hold (for example, a
+//! loop counter which would have to wrap around before reaching zero), the
+//! VM runs the original instructions instead.
+//!
+//! Since `tgl` can rewrite any instruction, the whole table of ops is
+//! rebuilt whenever the program changes.
+use crate::Instruction::{self, *};
+use crate::Value::{self, *};
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub(crate) enum Op {
+    /// `dst += src; src = 0`, replacing a loop of `len` instructions
+    Add { src: usize, dst: usize, len: i32 },
+    /// `dst -= src; src = 0`
+    Sub { src: usize, dst: usize },
+    /// `dst += a * n; tmp = 0; n = 0`
+    Mul {
+        a: Value,
+        n: usize,
+        tmp: usize,
+        dst: usize,
+    },
+    /// `dst += src; tmp = 0`, where `tmp` held a copy of `src`
+    Copy { src: Value, tmp: usize, dst: usize },
+    /// `r = 0`, by counting towards zero in steps of `step`
+    Zero { r: usize, step: i32 },
+    /// Subtracts the smaller of `a` and `b` from both, exiting at a
+    /// different place depending on which one reached zero first
+    Compare { a: usize, b: usize },
+}
+
+impl Op {
+    /// Runs the op, returning the offset from the loop head at which to
+    /// continue, or `None` if the op doesn't apply to these registers
+    pub(crate) fn exec(&self, regs: &mut [i32; 4]) -> Option<i32> {
+        let get = |regs: &[i32; 4], v: Value| match v {
+            Lit(i) => i,
+            Reg(r) => regs[r],
+        };
+        match *self {
+            Op::Add { src, dst, len } if regs[src] > 0 => {
+                regs[dst] += regs[src];
+                regs[src] = 0;
+                Some(len)
+            }
+            Op::Sub { src, dst } if regs[src] >= 0 => {
+                regs[dst] -= regs[src];
+                regs[src] = 0;
+                Some(5)
+            }
+            Op::Mul { a, n, tmp, dst } if get(regs, a) > 0 && regs[n] > 0 => {
+                regs[dst] += get(regs, a) * regs[n];
+                regs[tmp] = 0;
+                regs[n] = 0;
+                Some(6)
+            }
+            Op::Copy { src, tmp, dst } if get(regs, src) > 0 => {
+                regs[dst] += get(regs, src);
+                regs[tmp] = 0;
+                Some(4)
+            }
+            Op::Zero { r, step } if (regs[r] < 0) != (step < 0) && regs[r] != 0 => {
+                regs[r] = 0;
+                Some(2)
+            }
+            Op::Compare { a, b } if regs[a] >= 0 && regs[b] > 0 => {
+                if regs[a] < regs[b] {
+                    regs[b] -= regs[a];
+                    regs[a] = 0;
+                    Some(7)
+                } else {
+                    regs[a] -= regs[b];
+                    regs[b] = 0;
+                    Some(5)
+                }
+            }
+            _ => None,
+        }
+    }
+}
+
+/// A single optimization, which looks for a pattern at the start of the
+/// given instructions
+#[derive(Debug)]
+pub struct Rule {
+    pub name: &'static str,
+    find: fn(&[Instruction]) -> Option<Op>,
+}
+
+impl Rule {
+    pub(crate) fn find(&self, code: &[Instruction]) -> Option<Op> {
+        (self.find)(code)
+    }
+}
+
+/// Every rule, in the order that they're tried.  Longer patterns come
+/// first, since they may contain shorter ones.
+pub static RULES: &[Rule] = &[
+    Rule {
+        name: "mul",
+        find: mul,
+    },
+    Rule {
+        name: "compare",
+        find: compare,
+    },
+    Rule {
+        name: "sub",
+        find: sub,
+    },
+    Rule {
+        name: "copy",
+        find: copy,
+    },
+    Rule {
+        name: "add",
+        find: add,
+    },
+    Rule {
+        name: "zero",
+        find: zero,
+    },
+];
+
+/// Checks whether the given registers are all distinct
+fn distinct(regs: &[usize]) -> bool {
+    regs.iter()
+        .enumerate()
+        .all(|(i, a)| regs[i + 1..].iter().all(|b| a != b))
+}
+
+fn mul(code: &[Instruction]) -> Option<Op> {
+    match *code {
+        [cpy(a, Reg(tmp)), inc(Reg(dst)), dec(Reg(t1)), jnz(Reg(t2), Lit(-2)), dec(Reg(n)), jnz(Reg(n1), Lit(-5)), ..]
+            if tmp == t1
+                && tmp == t2
+                && n == n1
+                && distinct(&[tmp, dst, n])
+                && a.reg().into_iter().all(|a| distinct(&[a, tmp, dst, n])) =>
+        {
+            Some(Op::Mul { a, n, tmp, dst })
+        }
+        _ => None,
+    }
+}
+
+fn copy(code: &[Instruction]) -> Option<Op> {
+    match *code {
+        [cpy(src, Reg(tmp)), inc(Reg(dst)), dec(Reg(t1)), jnz(Reg(t2), Lit(-2)), ..]
+            if tmp == t1
+                && tmp == t2
+                && tmp != dst
+                && src.reg().into_iter().all(|s| distinct(&[s, tmp, dst])) =>
+        {
+            Some(Op::Copy { src, tmp, dst })
+        }
+        _ => None,
+    }
+}
+
+fn add(code: &[Instruction]) -> Option<Op> {
+    match *code {
+        [inc(Reg(dst)), dec(Reg(src)), jnz(Reg(s1), Lit(-2)), ..] if src == s1 && src != dst => {
+            Some(Op::Add { src, dst, len: 3 })
+        }
+        [dec(Reg(src)), inc(Reg(dst)), jnz(Reg(s1), Lit(-2)), ..] if src == s1 && src != dst => {
+            Some(Op::Add { src, dst, len: 3 })
+        }
+        _ => None,
+    }
+}
+
+fn sub(code: &[Instruction]) -> Option<Op> {
+    match *code {
+        [jnz(Reg(src), Lit(2)), jnz(Lit(1), Lit(4)), dec(Reg(dst)), dec(Reg(s1)), jnz(Lit(1), Lit(-4)), ..]
+            if src == s1 && src != dst =>
+        {
+            Some(Op::Sub { src, dst })
+        }
+        _ => None,
+    }
+}
+
+fn compare(code: &[Instruction]) -> Option<Op> {
+    match *code {
+        [jnz(Reg(a), Lit(2)), jnz(Lit(1), Lit(6)), dec(Reg(a1)), dec(Reg(b)), jnz(Reg(b1), Lit(-4)), ..]
+            if a == a1 && b == b1 && a != b =>
+        {
+            Some(Op::Compare { a, b })
+        }
+        _ => None,
+    }
+}
+
+fn zero(code: &[Instruction]) -> Option<Op> {
+    match *code {
+        [dec(Reg(r)), jnz(Reg(r1), Lit(-1)), ..] if r == r1 => Some(Op::Zero { r, step: -1 }),
+        [inc(Reg(r)), jnz(Reg(r1), Lit(-1)), ..] if r == r1 => Some(Op::Zero { r, step: 1 }),
+        _ => None,
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{diff, Vm};
+
+    /// Checks that a program behaves the same with only the named rule
+    /// enabled as it does in a plain interpreter, for every input with
+    /// registers in `-1..=3` which passes the given filter (used to skip
+    /// inputs where the original loop wouldn't terminate)
+    fn equivalent(rule: &str, program: &str, halts: fn([i32; 4]) -> bool) {
+        let mut vm: Vm = program.parse().unwrap();
+        let r = RULES.iter().find(|r| r.name == rule).unwrap();
+        vm.set_rules(&[r]);
+        assert!(vm.ops[0].is_some(), "{} doesn't match {:?}", rule, program);
+
+        let mut checked = 0;
+        for i in 0..5i32.pow(4) {
+            let input = [i % 5 - 1, i / 5 % 5 - 1, i / 25 % 5 - 1, i / 125 - 1];
+            if !halts(input) {
+                continue;
+            }
+            match diff::check(&vm, input) {
+                Ok(true) => checked += 1,
+                Ok(false) => panic!("{} timed out on {:?}", rule, input),
+                Err(d) => panic!("{}", d),
+            }
+        }
+        assert!(checked > 0);
+    }
+
+    #[test]
+    fn mul() {
+        let program = "cpy b c\ninc a\ndec c\njnz c -2\ndec d\njnz d -5";
+        equivalent("mul", program, |r| r[1] > 0 && r[3] > 0);
+        let program = "cpy 3 c\ninc a\ndec c\njnz c -2\ndec d\njnz d -5";
+        equivalent("mul", program, |r| r[3] > 0);
+    }
+
+    #[test]
+    fn compare() {
+        // Exits to `inc c` if b reaches zero first, or `inc d` if a does
+        let program = "jnz a 2\njnz 1 6\ndec a\ndec b\njnz b -4\ninc c\njnz 1 2\ninc d";
+        equivalent("compare", program, |r| r[1] > 0 || (r[1] < 0 && r[0] > 0));
+    }
+
+    #[test]
+    fn sub() {
+        let program = "jnz b 2\njnz 1 4\ndec a\ndec b\njnz 1 -4";
+        equivalent("sub", program, |r| r[1] >= 0);
+    }
+
+    #[test]
+    fn copy() {
+        let program = "cpy b c\ninc a\ndec c\njnz c -2";
+        equivalent("copy", program, |r| r[1] > 0);
+        let program = "cpy 2 c\ninc a\ndec c\njnz c -2";
+        equivalent("copy", program, |_| true);
+    }
+
+    #[test]
+    fn add() {
+        equivalent("add", "inc a\ndec b\njnz b -2", |r| r[1] > 0);
+        equivalent("add", "dec b\ninc a\njnz b -2", |r| r[1] > 0);
+    }
+
+    #[test]
+    fn zero() {
+        equivalent("zero", "dec a\njnz a -1", |r| r[0] > 0);
+        equivalent("zero", "inc a\njnz a -1", |r| r[0] < 0);
+    }
+
+    #[test]
+    fn zero_extremes() {
+        // Counting towards zero works from any value, but counting away from
+        // it would overflow, so the op must not apply
+        let down = Op::Zero { r: 0, step: -1 };
+        let up = Op::Zero { r: 0, step: 1 };
+        for (op, v, ok) in &[
+            (down, i32::MAX, true),
+            (down, i32::MIN, false),
+            (down, 0, false),
+            (up, i32::MIN, true),
+            (up, i32::MAX, false),
+            (up, 0, false),
+        ] {
+            let mut regs = [*v, 0, 0, 0];
+            assert_eq!(op.exec(&mut regs).is_some(), *ok, "{:?} on {}", op, v);
+            if *ok {
+                assert_eq!(regs[0], 0);
+            }
+        }
+    }
+}
@@ -1,65 +1,22 @@
+use elfcode::infer::{infer, Sample};
+use elfcode::{Instruction, Op};
 use regex::Regex;
-use std::collections::{HashMap, HashSet};
-
-#[derive(Debug, Eq, PartialEq)]
-struct Registers([usize; 4]);
-
-#[allow(non_camel_case_types)]
-#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
-enum Op {
-    addr,
-    addi,
-    mulr,
-    muli,
-    banr,
-    bani,
-    borr,
-    bori,
-    setr,
-    seti,
-    gtir,
-    gtri,
-    gtrr,
-    eqir,
-    eqri,
-    eqrr,
-}
-
-use crate::Op::*;
-
-impl Op {
-    fn eval(&self, s: &Registers, a: usize, b: usize, c: usize) -> Registers {
-        let mut out = s.0.clone();
-        out[c] = match self {
-            addr => out[a] + out[b],
-            addi => out[a] + b,
-            mulr => out[a] * out[b],
-            muli => out[a] * b,
-            banr => out[a] & out[b],
-            bani => out[a] & b,
-            borr => out[a] | out[b],
-            bori => out[a] | b,
-            setr => out[a],
-            seti => a,
-            gtir => (a > out[b]) as usize,
-            gtri => (out[a] > b) as usize,
-            gtrr => (out[a] > out[b]) as usize,
-            eqir => (a == out[b]) as usize,
-            eqri => (out[a] == b) as usize,
-            eqrr => (out[a] == out[b]) as usize,
-        };
-        return Registers(out);
-    }
-}
+use std::collections::HashMap;
 
 #[derive(Debug)]
 struct MachineCode([usize; 4]);
 
 impl MachineCode {
-    fn eval(&self, s: &Registers, map: &HashMap<usize, Op>) -> Registers {
-        map.get(&self.0[0])
-            .expect("Could not find opcode")
-            .eval(s, self.0[1], self.0[2], self.0[3])
+    fn eval(&self, s: &[usize; 4], map: &HashMap<usize, Op>) -> [usize; 4] {
+        let mut out = *s;
+        Instruction {
+            op: *map.get(&self.0[0]).expect("Could not find opcode"),
+            a: self.0[1],
+            b: self.0[2],
+            c: self.0[3],
+        }
+        .eval(&mut out);
+        out
     }
 }
 
@@ -83,55 +40,34 @@ fn main() {
         })
         .collect::<Vec<Option<[usize; 4]>>>();
 
-    let mut table: HashMap<usize, HashSet<Op>> = HashMap::new();
-    let mut geq3 = 0;
+    let mut samples = vec![];
     for chunk in lines.split(|p| p.is_none()) {
         if chunk.len() == 3 {
-            let initial_state = Registers(chunk[0].unwrap());
-            let machine_code = MachineCode(chunk[1].unwrap());
-            let final_state = Registers(chunk[2].unwrap());
-
-            let op_num = machine_code.0[0];
-            let mut matches = HashSet::new();
-            for op in &[
-                addr, addi, mulr, muli, banr, bani, borr, bori, setr, seti, gtir, gtri, gtrr, eqir,
-                eqri, eqrr,
-            ] {
-                let mut map = HashMap::new();
-                map.insert(op_num, *op);
-                let out = machine_code.eval(&initial_state, &map);
-
-                if out == final_state {
-                    matches.insert(*op);
-                }
-            }
-            geq3 += (matches.len() >= 3) as usize;
-
-            table
-                .entry(op_num)
-                .and_modify(|t| *t = t.intersection(&matches).cloned().collect())
-                .or_insert(matches);
+            samples.push(Sample {
+                before: chunk[0].unwrap().to_vec(),
+                code: chunk[1].unwrap(),
+                after: chunk[2].unwrap().to_vec(),
+            });
 
         // The last chunk is longer, and contains a program to execute
-        } else if chunk.len() != 0 {
+        } else if !chunk.is_empty() {
+            let geq3 = samples
+                .iter()
+                .filter(|s| s.candidates(&Op::ALL).len() >= 3)
+                .count();
             println!("Part 1: {}", geq3);
 
             // Build the canonical table of numbers to opcodes
-            let mut canonical = HashMap::new();
-            while let Some(i) = table.iter().filter(|i| i.1.len() == 1).next() {
-                let op = i.1.iter().next().unwrap().clone();
-                canonical.insert(i.0.clone(), op);
-
-                for (_, v) in table.iter_mut() {
-                    v.remove(&op);
-                }
-            }
+            let canonical = match infer(&Op::ALL, &samples) {
+                Ok(c) => c,
+                Err(e) => panic!("{}", e),
+            };
 
-            let mut state = Registers([0, 0, 0, 0]);
-            for line in chunk.into_iter() {
+            let mut state = [0, 0, 0, 0];
+            for line in chunk.iter() {
                 state = MachineCode(line.unwrap()).eval(&state, &canonical);
             }
-            println!("Part 2: {}", state.0[0]);
+            println!("Part 2: {}", state[0]);
         }
     }
 }
@@ -0,0 +1,122 @@
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+use elfcode::{decompile, Kind, Program, Source};
+
+/// Returns the Rust expression for one argument of an instruction
+fn arg(s: Source, v: usize) -> String {
+    match s {
+        Source::Register => format!("r{}", v),
+        Source::Immediate | Source::Unused => format!("{}", v),
+    }
+}
+
+fn main() -> Result<(), std::io::Error> {
+    let input_dir = std::env::var_os("CARGO_MANIFEST_DIR").unwrap();
+    let input_path = Path::new(&input_dir).join("input");
+    println!(
+        "cargo:rerun-if-changed=build.rs
+cargo:rerun-if-changed={}",
+        input_path.as_os_str().to_str().unwrap()
+    );
+
+    let out_dir = std::env::var_os("OUT_DIR").unwrap();
+    let dest_path = Path::new(&out_dir).join("gen.rs");
+    let mut f = std::fs::File::create(dest_path)?;
+
+    let text = fs::read_to_string(input_path)?;
+    let program: Program = text.parse().expect("Could not parse program");
+    let ip = program.ip.expect("Program must have an #ip directive");
+    let tape = &program.instructions;
+    // If every jump can be resolved, then only jump targets need their own
+    // arm in the generated `match` and straight-line code can be merged into
+    // a single arm.  Otherwise, every instruction is a possible target.
+    let leader = decompile::leaders(&program).unwrap_or_else(|_| vec![true; tape.len() + 1]);
+
+    let regs = "[r0, r1, r2, r3, r4, r5]";
+    writeln!(
+        f,
+        "type Registers = [usize; 6];
+
+#[allow(clippy::all, unused_assignments, unused_mut, unused_variables)]
+mod program {{
+    use super::Registers;
+
+    /// Runs the program from the given registers.  The callback is invoked
+    /// just before each breakpoint instruction (with the same registers as
+    /// `elfcode::Vm::run` would stop with); if it returns true, then the
+    /// program exits.
+    pub fn run(regs: Registers, callback: &mut impl FnMut(&Registers) -> bool) -> Registers {{
+        let [mut r0, mut r1, mut r2, mut r3, mut r4, mut r5] = regs;
+        let mut ip = 0;
+        let mut first = true;
+        loop {{
+            match ip {{"
+    )?;
+
+    for (i, line) in tape.iter().enumerate() {
+        if leader[i] {
+            writeln!(f, "                {} => {{", i)?;
+        }
+        let (sa, sb) = line.op.sources();
+        let (a, b) = (arg(sa, line.a), arg(sb, line.b));
+        let value = match line.op.kind() {
+            Kind::Add => format!("{} + {}", a, b),
+            Kind::Mul => format!("{} * {}", a, b),
+            Kind::And => format!("{} & {}", a, b),
+            Kind::Or => format!("{} | {}", a, b),
+            Kind::Set => a,
+            Kind::Gt => format!("({} > {}) as usize", a, b),
+            Kind::Eq => format!("({} == {}) as usize", a, b),
+        };
+        // Like `elfcode::Vm::run`, stop just before a breakpoint instruction
+        // runs, except for the instruction where execution starts
+        if program.breakpoints.contains(&i) {
+            let first = if i == 0 {
+                "!std::mem::take(&mut first) && "
+            } else {
+                ""
+            };
+            writeln!(
+                f,
+                "                    if {}callback(&{}) {{
+                        ip = {};
+                        break;
+                    }}",
+                first,
+                regs,
+                tape.len()
+            )?;
+        }
+        writeln!(
+            f,
+            "                    r{0} = {1};
+                    r{2} = {3}; // {4}",
+            ip, i, line.c, value, line
+        )?;
+
+        let jump = line.c == ip;
+        if jump {
+            writeln!(f, "                    ip = r{} + 1;", ip)?;
+        } else if leader[i + 1] {
+            writeln!(f, "                    ip = {};", i + 1)?;
+        }
+        if jump || leader[i + 1] {
+            writeln!(f, "                }}")?;
+        }
+    }
+    writeln!(
+        f,
+        "                _ => break,
+            }}
+        }}
+        r{} = ip;
+        {}
+    }}
+}}",
+        ip, regs
+    )?;
+
+    Ok(())
+}
@@ -1,85 +1,7 @@
 use std::collections::HashSet;
 use std::io::{self, Read};
 
-#[derive(Debug, Eq, PartialEq)]
-struct Registers([usize; 6]);
-
-#[allow(non_camel_case_types)]
-#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
-enum Op {
-    addr,
-    addi,
-    mulr,
-    muli,
-    banr,
-    bani,
-    borr,
-    bori,
-    setr,
-    seti,
-    gtir,
-    gtri,
-    gtrr,
-    eqir,
-    eqri,
-    eqrr,
-}
-use crate::Op::*;
-
-impl Op {
-    fn from_str(s: &str) -> Op {
-        match s {
-            "addr" => addr,
-            "addi" => addi,
-            "mulr" => mulr,
-            "muli" => muli,
-            "banr" => banr,
-            "bani" => bani,
-            "borr" => borr,
-            "bori" => bori,
-            "setr" => setr,
-            "seti" => seti,
-            "gtir" => gtir,
-            "gtri" => gtri,
-            "gtrr" => gtrr,
-            "eqir" => eqir,
-            "eqri" => eqri,
-            "eqrr" => eqrr,
-            _ => unimplemented!(),
-        }
-    }
-}
-
-#[derive(Debug)]
-struct Instruction {
-    op: Op,
-    a: usize,
-    b: usize,
-    c: usize,
-}
-
-impl Instruction {
-    fn eval(&self, s: &mut Registers) {
-        s.0[self.c] = match self.op {
-            addr => s.0[self.a] + s.0[self.b],
-            addi => s.0[self.a] + self.b,
-            mulr => s.0[self.a] * s.0[self.b],
-            muli => s.0[self.a] * self.b,
-            banr => s.0[self.a] & s.0[self.b],
-            bani => s.0[self.a] & self.b,
-            borr => s.0[self.a] | s.0[self.b],
-            bori => s.0[self.a] | self.b,
-            setr => s.0[self.a],
-            seti => self.a,
-            gtir => (self.a > s.0[self.b]) as usize,
-            gtri => (s.0[self.a] > self.b) as usize,
-            gtrr => (s.0[self.a] > s.0[self.b]) as usize,
-            eqir => (self.a == s.0[self.b]) as usize,
-            eqri => (s.0[self.a] == self.b) as usize,
-            eqrr => (s.0[self.a] == s.0[self.b]) as usize,
-        };
-    }
-}
+use elfcode::{Program, Stop, Vm};
 
 // Optimized implementation of the weird calculation,
 // tracking the value of reg[3] at the termination
@@ -116,46 +38,16 @@ fn main() {
     let mut buffer = String::new();
     io::stdin().read_to_string(&mut buffer).unwrap();
 
-    let mut ip_reg = 0;
-    let tape = buffer
-        .lines()
-        .filter_map(|line| {
-            let words = line.split(' ').collect::<Vec<_>>();
-            if words[0] == "#ip" {
-                ip_reg = str::parse::<usize>(words[1]).unwrap();
-                None
-            } else {
-                let op = Op::from_str(words[0]);
-                let a = str::parse::<usize>(words[1]).unwrap();
-                let b = str::parse::<usize>(words[2]).unwrap();
-                let c = str::parse::<usize>(words[3]).unwrap();
-                Some(Instruction {
-                    op: op,
-                    a: a,
-                    b: b,
-                    c: c,
-                })
-            }
-        })
-        .collect::<Vec<Instruction>>();
+    let program: Program = buffer.parse().unwrap();
 
     // Run until we hit line 28 for the first time, which is
     // our termination condition (if reg[3] == reg[0]).  We
     // then pull out reg[3]'s value, which is the value for reg[0]
     // that will cause the earliest possible termination.
-    let mut state = Registers([0, 0, 0, 0, 0, 0]);
-    let mut ip = 0;
-    loop {
-        if ip >= tape.len() {
-            break;
-        }
-        state.0[ip_reg] = ip;
-        tape[ip].eval(&mut state);
-        ip = state.0[ip_reg] + 1;
-        if ip == 28 {
-            println!("Part 1: {}", state.0[3]);
-            break;
-        }
+    let mut vm = Vm::new(program);
+    vm.add_breakpoint(28);
+    if let Stop::Breakpoint(_) = vm.run() {
+        println!("Part 1: {}", vm.regs[3]);
     }
 
     part2();
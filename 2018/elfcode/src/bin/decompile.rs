@@ -0,0 +1,25 @@
+use std::io::{self, Read};
+
+use elfcode::decompile::decompile_with;
+use elfcode::Program;
+
+/// Reads a program from stdin and prints it as pseudocode.  Arguments give
+/// known initial register values, starting from r0 (e.g. `1 0 0 0 0 0`).
+fn main() {
+    let mut init = [None; 6];
+    for (r, arg) in std::env::args().skip(1).take(6).enumerate() {
+        init[r] = Some(arg.parse().expect("Invalid register value"));
+    }
+
+    let mut buffer = String::new();
+    io::stdin().read_to_string(&mut buffer).unwrap();
+    let program: Program = buffer.parse().unwrap();
+
+    match decompile_with(&program, init) {
+        Ok(d) => print!("{}", d),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,1482 @@
+//! Decompiler, which lifts an `#ip`-bound program into structured pseudocode
+//!
+//! Writes to the bound register are recognized as jumps: either to a fixed
+//! target, or conditional on a comparison in the previous instruction.  The
+//! resulting control-flow graph is structured into loops and labelled blocks
+//! (following Ramsey's "Beyond Relooper"), then cleaned up and searched for
+//! idioms which can be replaced with native code.
+use std::collections::BTreeSet;
+use std::fmt;
+
+use crate::{Instruction, Kind, Program, Registers, Source};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Expr {
+    Reg(usize),
+    Imm(usize),
+    /// A binary operation, using any `Kind` other than `Set`
+    Bin(Kind, Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    fn bin(kind: Kind, a: Expr, b: Expr) -> Self {
+        Expr::Bin(kind, Box::new(a), Box::new(b))
+    }
+
+    pub fn eval(&self, regs: &Registers) -> usize {
+        match self {
+            Expr::Reg(r) => regs[*r],
+            Expr::Imm(i) => *i,
+            Expr::Bin(k, a, b) => {
+                let (a, b) = (a.eval(regs), b.eval(regs));
+                match k {
+                    Kind::Add => a + b,
+                    Kind::Mul => a * b,
+                    Kind::And => a & b,
+                    Kind::Or => a | b,
+                    Kind::Set => a,
+                    Kind::Gt => (a > b) as usize,
+                    Kind::Eq => (a == b) as usize,
+                }
+            }
+        }
+    }
+
+    /// Returns the constant value of this expression, if it has one
+    fn constant(&self) -> Option<usize> {
+        match self {
+            Expr::Reg(_) => None,
+            Expr::Imm(i) => Some(*i),
+            Expr::Bin(..) => {
+                if self.uses(None) == 0 {
+                    Some(self.eval(&[0; 6]))
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    /// Counts uses of the given register (or of any register, if `None`)
+    fn uses(&self, reg: Option<usize>) -> usize {
+        match self {
+            Expr::Reg(r) => (reg.is_none() || reg == Some(*r)) as usize,
+            Expr::Imm(_) => 0,
+            Expr::Bin(_, a, b) => a.uses(reg) + b.uses(reg),
+        }
+    }
+
+    /// Adds every register read by this expression to the set
+    fn registers(&self, out: &mut BTreeSet<usize>) {
+        match self {
+            Expr::Reg(r) => {
+                out.insert(*r);
+            }
+            Expr::Imm(_) => (),
+            Expr::Bin(_, a, b) => {
+                a.registers(out);
+                b.registers(out);
+            }
+        }
+    }
+
+    fn substitute(&mut self, reg: usize, e: &Expr) {
+        match self {
+            Expr::Reg(r) if *r == reg => *self = e.clone(),
+            Expr::Reg(_) | Expr::Imm(_) => (),
+            Expr::Bin(_, a, b) => {
+                a.substitute(reg, e);
+                b.substitute(reg, e);
+            }
+        }
+    }
+
+    fn precedence(&self) -> usize {
+        match self {
+            Expr::Reg(_) | Expr::Imm(_) => 10,
+            Expr::Bin(k, ..) => match k {
+                Kind::Mul => 9,
+                Kind::Add => 8,
+                Kind::And => 7,
+                Kind::Or => 5,
+                Kind::Gt | Kind::Eq => 3,
+                Kind::Set => 10,
+            },
+        }
+    }
+}
+
+fn symbol(k: Kind) -> &'static str {
+    match k {
+        Kind::Add => "+",
+        Kind::Mul => "*",
+        Kind::And => "&",
+        Kind::Or => "|",
+        Kind::Gt => ">",
+        Kind::Eq => "==",
+        Kind::Set => "=",
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Expr::Reg(r) => write!(f, "r{}", r),
+            Expr::Imm(i) => write!(f, "{}", i),
+            Expr::Bin(k, a, b) => {
+                let p = self.precedence();
+                let side = |f: &mut fmt::Formatter, e: &Expr, strict| {
+                    let q = e.precedence();
+                    if q < p || (strict && q == p) {
+                        write!(f, "({})", e)
+                    } else {
+                        write!(f, "{}", e)
+                    }
+                };
+                side(f, a, false)?;
+                write!(f, " {} ", symbol(*k))?;
+                // Comparisons don't chain, and we avoid relying on
+                // associativity for the right-hand side
+                side(f, b, true)
+            }
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// A branch condition, which is true when `expr` is non-zero (or zero, if
+/// the condition is negated)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Cond {
+    pub expr: Expr,
+    pub negated: bool,
+}
+
+impl Cond {
+    pub fn eval(&self, regs: &Registers) -> bool {
+        (self.expr.eval(regs) != 0) != self.negated
+    }
+
+    fn negate(self) -> Self {
+        Cond {
+            expr: self.expr,
+            negated: !self.negated,
+        }
+    }
+}
+
+impl fmt::Display for Cond {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (&self.expr, self.negated) {
+            (Expr::Bin(Kind::Gt, a, b), true) => {
+                write!(f, "{} <= {}", a, b)
+            }
+            (Expr::Bin(Kind::Eq, a, b), true) => {
+                write!(f, "{} != {}", a, b)
+            }
+            (e @ Expr::Bin(Kind::Gt, ..), false) | (e @ Expr::Bin(Kind::Eq, ..), false) => {
+                write!(f, "{}", e)
+            }
+            (e, false) => write!(f, "{} != 0", e),
+            (e, true) => write!(f, "{} == 0", e),
+        }
+    }
+}
+
+/// A loop which has been recognized and can be run natively
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Idiom {
+    /// `q = n / k`, computed by counting `q` up until `(q + 1) * k > n`.
+    /// Only matched when `k` is a non-zero constant, since the loop never
+    /// ends when `k` is zero.
+    Div { q: usize, n: Expr, k: Expr },
+    /// `acc += sum of divisors of n`, computed by testing every pair `i * j`
+    /// with `i, j` in `1..=n`.  Both counters end at `n + 1`, or at 2 when
+    /// `n` is 0 (since the loop body always runs once).
+    DivisorSum {
+        acc: usize,
+        n: Expr,
+        i: usize,
+        j: usize,
+    },
+}
+
+impl Idiom {
+    pub fn eval(&self, regs: &mut Registers) {
+        match self {
+            Idiom::Div { q, n, k } => regs[*q] = n.eval(regs) / k.eval(regs),
+            Idiom::DivisorSum { acc, n, i, j } => {
+                let n_ = n.eval(regs);
+                let mut sum = 0;
+                let mut d = 1;
+                while d * d <= n_ {
+                    if n_ % d == 0 {
+                        sum += d;
+                        if d * d != n_ {
+                            sum += n_ / d;
+                        }
+                    }
+                    d += 1;
+                }
+                regs[*acc] += sum;
+                regs[*i] = n_.max(1) + 1;
+                regs[*j] = n_.max(1) + 1;
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Stmt {
+    /// `reg = expr`, from the instruction at `at`
+    Assign {
+        reg: usize,
+        expr: Expr,
+        at: usize,
+    },
+    /// Conditional, from the jump instruction at `at`
+    If {
+        cond: Cond,
+        then: Vec<Stmt>,
+        otherwise: Vec<Stmt>,
+        at: usize,
+    },
+    /// Infinite loop, which is exited with `Break`
+    Loop {
+        label: usize,
+        body: Vec<Stmt>,
+    },
+    /// Labelled block, which can be exited early with `Break`
+    Block {
+        label: usize,
+        body: Vec<Stmt>,
+    },
+    /// Jumps to the end of the labelled loop or block
+    Break(usize),
+    /// Jumps to the top of the labelled loop
+    Continue(usize),
+    Halt,
+    Native(Idiom),
+}
+
+/// Control flow out of a statement, used when running the pseudocode
+enum Flow {
+    Next,
+    Break(usize),
+    Continue(usize),
+    Halt,
+}
+
+fn run(stmts: &[Stmt], regs: &mut Registers) -> Flow {
+    for s in stmts {
+        let flow = match s {
+            Stmt::Assign { reg, expr, .. } => {
+                regs[*reg] = expr.eval(regs);
+                Flow::Next
+            }
+            Stmt::If {
+                cond,
+                then,
+                otherwise,
+                ..
+            } => {
+                if cond.eval(regs) {
+                    run(then, regs)
+                } else {
+                    run(otherwise, regs)
+                }
+            }
+            Stmt::Loop { label, body } => loop {
+                match run(body, regs) {
+                    Flow::Next => (),
+                    Flow::Continue(t) if t == *label => (),
+                    Flow::Break(t) if t == *label => break Flow::Next,
+                    f => break f,
+                }
+            },
+            Stmt::Block { label, body } => match run(body, regs) {
+                Flow::Break(t) if t == *label => Flow::Next,
+                f => f,
+            },
+            Stmt::Break(t) => Flow::Break(*t),
+            Stmt::Continue(t) => Flow::Continue(*t),
+            Stmt::Halt => Flow::Halt,
+            Stmt::Native(i) => {
+                i.eval(regs);
+                Flow::Next
+            }
+        };
+        if let Flow::Next = flow {
+            continue;
+        }
+        return flow;
+    }
+    Flow::Next
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DecompileError {
+    /// The instruction writes the bound register with a value that isn't
+    /// a constant or a comparison result
+    ComputedJump(usize),
+    /// The control-flow graph can't be structured (a loop has two entries)
+    Irreducible(usize),
+}
+
+impl fmt::Display for DecompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecompileError::ComputedJump(i) => {
+                write!(f, "Unresolved computed jump at instruction {}", i)
+            }
+            DecompileError::Irreducible(i) => {
+                write!(f, "Irreducible control flow into instruction {}", i)
+            }
+        }
+    }
+}
+
+/// How control leaves an instruction
+#[derive(Clone, Debug)]
+enum Jump {
+    Next,
+    Goto(usize),
+    Branch(Cond, usize, usize),
+}
+
+/// A basic block in the control-flow graph
+struct Node {
+    start: usize,
+    end: usize,
+    /// Successor node indices for `Jump::Goto` / `Jump::Branch`, or `None`
+    /// if the target is outside the program (so the VM halts)
+    succ: Vec<Option<usize>>,
+}
+
+/// Register values which are known before an instruction runs
+type Known = [Option<usize>; 6];
+
+struct Decompiler<'a> {
+    program: &'a Program,
+    /// Value written by each instruction, or `None` if it's unreachable
+    values: Vec<Option<Expr>>,
+    jumps: Vec<Jump>,
+    nodes: Vec<Node>,
+    rpo: Vec<usize>,
+    idom: Vec<usize>,
+    headers: Vec<bool>,
+    merges: Vec<bool>,
+    live_out: Vec<BTreeSet<usize>>,
+}
+
+/// Decompiled program, which can be printed as pseudocode or executed
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Decompiled {
+    pub body: Vec<Stmt>,
+}
+
+/// Decompiles a program into structured code, making no assumptions about
+/// the initial register values.  Registers other than `r0` are assumed to be
+/// dead when the program halts.
+pub fn decompile(program: &Program) -> Result<Decompiled, DecompileError> {
+    decompile_with(program, [None; 6])
+}
+
+/// Decompiles a program, specialized for the given initial register values.
+///
+/// Known values are propagated through the program, which can resolve
+/// jumps that depend on the input (e.g. `addr 5 0 5` in day 19) and folds
+/// setup code down to constants.
+pub fn decompile_with(program: &Program, init: Known) -> Result<Decompiled, DecompileError> {
+    Decompiler::new(program, init)?.build()
+}
+
+/// Finds leaders, i.e. instructions which start a basic block, by resolving
+/// every jump in the program.
+///
+/// The result has one entry per instruction, plus a final entry (always set)
+/// for the end of the program.  Every jump target is a leader, as is every
+/// instruction after a write to the bound register.
+pub fn leaders(program: &Program) -> Result<Vec<bool>, DecompileError> {
+    let (_, _, leader) = Decompiler::resolve(program, [None; 6])?;
+    Ok(leader)
+}
+
+/// Reachable instructions' values, jumps, and leaders
+type Resolved = (Vec<Option<Expr>>, Vec<Jump>, Vec<bool>);
+
+impl<'a> Decompiler<'a> {
+    fn new(program: &'a Program, init: Known) -> Result<Self, DecompileError> {
+        let n = program.len();
+        let (values, jumps, leader) = Self::resolve(program, init)?;
+
+        let starts = (0..n)
+            .filter(|i| leader[*i] && values[*i].is_some())
+            .collect::<Vec<_>>();
+        let node_of = |i: usize| starts.binary_search(&i).ok();
+        let nodes = starts
+            .iter()
+            .map(|&start| {
+                let mut end = start + 1;
+                while end < n && !leader[end] && values[end].is_some() {
+                    end += 1;
+                }
+                let succ = match &jumps[end - 1] {
+                    Jump::Next => vec![node_of(end)],
+                    Jump::Goto(t) => vec![node_of(*t)],
+                    Jump::Branch(_, t, f) => vec![node_of(*t), node_of(*f)],
+                };
+                Node { start, end, succ }
+            })
+            .collect::<Vec<_>>();
+
+        let mut d = Decompiler {
+            program,
+            values,
+            jumps,
+            nodes,
+            rpo: vec![],
+            idom: vec![],
+            headers: vec![],
+            merges: vec![],
+            live_out: vec![],
+        };
+        d.analyze()?;
+        d.liveness();
+        Ok(d)
+    }
+
+    /// Resolves jumps and finds leaders (see [`leaders`])
+    fn resolve(program: &Program, init: Known) -> Result<Resolved, DecompileError> {
+        let n = program.len();
+
+        // Propagate known values through the program, finding reachable
+        // instructions and resolving jumps as we go.  Knowledge only ever
+        // decreases at each instruction, so this terminates.
+        let mut state: Vec<Option<Known>> = vec![None; n];
+        let mut jumps = vec![Jump::Next; n];
+        let mut todo = vec![];
+        if n > 0 {
+            state[0] = Some(init);
+            todo.push(0);
+        }
+        while let Some(i) = todo.pop() {
+            let known = state[i].unwrap();
+            let e = Self::value(program, i, &known);
+            let c = program.instructions[i].c;
+            let mut out = known;
+            jumps[i] = if Some(c) == program.ip {
+                Self::jump(program, i, &e)?
+            } else {
+                out[c] = e.constant();
+                Jump::Next
+            };
+            for t in Self::targets(&jumps[i], i).filter(|t| *t < n) {
+                let merged = match state[t] {
+                    None => out,
+                    Some(s) => {
+                        let mut m = s;
+                        for (m, o) in m.iter_mut().zip(out.iter()) {
+                            if m != o {
+                                *m = None;
+                            }
+                        }
+                        m
+                    }
+                };
+                if state[t] != Some(merged) {
+                    state[t] = Some(merged);
+                    todo.push(t);
+                }
+            }
+        }
+        let values = (0..n)
+            .map(|i| state[i].map(|k| Self::value(program, i, &k)))
+            .collect::<Vec<_>>();
+
+        // Find leaders.  Unreachable jumps aren't resolved, but still end
+        // their block.
+        let mut leader = vec![false; n + 1];
+        leader[0] = true;
+        leader[n] = true;
+        for (i, j) in jumps.iter().enumerate() {
+            if values[i].is_some() && !matches!(j, Jump::Next) {
+                for t in Self::targets(j, i) {
+                    leader[t.min(n)] = true;
+                }
+            }
+            if Some(program.instructions[i].c) == program.ip {
+                leader[i + 1] = true;
+            }
+        }
+        // A conditional jump relies on the comparison just before it, so
+        // it must not be reachable from anywhere else.
+        for (i, j) in jumps.iter().enumerate() {
+            if let Jump::Branch(..) = j {
+                if leader[i] {
+                    return Err(DecompileError::ComputedJump(i));
+                }
+            }
+        }
+        Ok((values, jumps, leader))
+    }
+
+    /// Returns the value written by an instruction, with reads of the bound
+    /// register replaced by the instruction's address and known registers
+    /// replaced by their values
+    fn value(program: &Program, i: usize, known: &Known) -> Expr {
+        let Instruction { op, a, b, .. } = program.instructions[i];
+        let arg = |s, v: usize| match s {
+            Source::Register if Some(v) == program.ip => Expr::Imm(i),
+            Source::Register => match known[v] {
+                Some(k) => Expr::Imm(k),
+                None => Expr::Reg(v),
+            },
+            Source::Immediate | Source::Unused => Expr::Imm(v),
+        };
+        let (sa, sb) = op.sources();
+        let e = match op.kind() {
+            Kind::Set => arg(sa, a),
+            k => Expr::bin(k, arg(sa, a), arg(sb, b)),
+        };
+        match e.constant() {
+            Some(c) => Expr::Imm(c),
+            None => e,
+        }
+    }
+
+    /// Converts a write to the bound register into a jump
+    fn jump(program: &Program, i: usize, e: &Expr) -> Result<Jump, DecompileError> {
+        if let Some(c) = e.constant() {
+            return Ok(Jump::Goto(c + 1));
+        }
+        // Look for `ip = ip + flag`, where `flag` was just set by a comparison
+        if let Expr::Bin(Kind::Add, a, b) = e {
+            let flag = match (&**a, &**b) {
+                (Expr::Imm(j), Expr::Reg(r)) | (Expr::Reg(r), Expr::Imm(j)) if *j == i => Some(*r),
+                _ => None,
+            };
+            if let Some(r) = flag {
+                if i > 0 {
+                    let prev = program.instructions[i - 1];
+                    let k = prev.op.kind();
+                    if prev.c == r && (k == Kind::Gt || k == Kind::Eq) {
+                        let cond = Cond {
+                            expr: Expr::Reg(r),
+                            negated: false,
+                        };
+                        return Ok(Jump::Branch(cond, i + 2, i + 1));
+                    }
+                }
+            }
+        }
+        Err(DecompileError::ComputedJump(i))
+    }
+
+    /// Returns the instructions that may run after instruction `i`
+    fn targets(j: &Jump, i: usize) -> impl Iterator<Item = usize> {
+        let (a, b) = match j {
+            Jump::Next => (i + 1, None),
+            Jump::Goto(t) => (*t, None),
+            Jump::Branch(_, t, f) => (*t, Some(*f)),
+        };
+        std::iter::once(a).chain(b)
+    }
+
+    fn succ(&self, x: usize) -> impl Iterator<Item = usize> + '_ {
+        self.nodes[x].succ.iter().filter_map(|s| *s)
+    }
+
+    /// Computes reverse postorder, dominators, loop headers and merge nodes
+    fn analyze(&mut self) -> Result<(), DecompileError> {
+        let n = self.nodes.len();
+        let mut order = vec![];
+        let mut seen = vec![false; n];
+        if n > 0 {
+            let mut stack = vec![(0, 0)];
+            seen[0] = true;
+            while let Some((x, k)) = stack.pop() {
+                if let Some(y) = self.succ(x).nth(k) {
+                    stack.push((x, k + 1));
+                    if !seen[y] {
+                        seen[y] = true;
+                        stack.push((y, 0));
+                    }
+                } else {
+                    order.push(x);
+                }
+            }
+        }
+        order.reverse();
+        let mut rpo = vec![usize::MAX; n];
+        for (i, x) in order.iter().enumerate() {
+            rpo[*x] = i;
+        }
+
+        let mut preds = vec![vec![]; n];
+        for &x in &order {
+            for y in self.succ(x) {
+                preds[y].push(x);
+            }
+        }
+
+        // Cooper, Harvey, and Kennedy's iterative dominator algorithm
+        let mut idom = vec![usize::MAX; n];
+        if n > 0 {
+            idom[0] = 0;
+        }
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for &x in order.iter().skip(1) {
+                let mut new = usize::MAX;
+                for &p in &preds[x] {
+                    if idom[p] == usize::MAX {
+                        continue;
+                    }
+                    new = if new == usize::MAX {
+                        p
+                    } else {
+                        let (mut a, mut b) = (p, new);
+                        while a != b {
+                            while rpo[a] > rpo[b] {
+                                a = idom[a];
+                            }
+                            while rpo[b] > rpo[a] {
+                                b = idom[b];
+                            }
+                        }
+                        a
+                    };
+                }
+                if idom[x] != new {
+                    idom[x] = new;
+                    changed = true;
+                }
+            }
+        }
+
+        let dominates = |a: usize, mut b: usize| loop {
+            if a == b {
+                return true;
+            } else if b == 0 {
+                return false;
+            }
+            b = idom[b];
+        };
+        let mut headers = vec![false; n];
+        let mut merges = vec![false; n];
+        for &y in &order {
+            let mut forward = 0;
+            for &p in &preds[y] {
+                if rpo[p] >= rpo[y] {
+                    if !dominates(y, p) {
+                        return Err(DecompileError::Irreducible(self.nodes[y].start));
+                    }
+                    headers[y] = true;
+                } else {
+                    forward += 1;
+                }
+            }
+            merges[y] = forward >= 2;
+        }
+
+        self.rpo = rpo;
+        self.idom = idom;
+        self.headers = headers;
+        self.merges = merges;
+        Ok(())
+    }
+
+    /// Computes the set of live registers after each instruction
+    fn liveness(&mut self) {
+        let n = self.program.len();
+        let exit = std::iter::once(0).collect::<BTreeSet<usize>>();
+        let mut live_in = vec![BTreeSet::new(); n];
+        let mut live_out = vec![BTreeSet::new(); n];
+
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for i in (0..n).rev() {
+                let value = match &self.values[i] {
+                    Some(v) => v,
+                    None => continue,
+                };
+                let mut out = BTreeSet::new();
+                for s in Self::targets(&self.jumps[i], i) {
+                    out.extend(live_in.get(s).unwrap_or(&exit).iter().cloned());
+                }
+                let mut inp = out.clone();
+                match &self.jumps[i] {
+                    Jump::Next => {
+                        inp.remove(&self.program.instructions[i].c);
+                        value.registers(&mut inp);
+                    }
+                    Jump::Goto(_) => (),
+                    Jump::Branch(cond, ..) => cond.expr.registers(&mut inp),
+                }
+                if inp != live_in[i] || out != live_out[i] {
+                    live_in[i] = inp;
+                    live_out[i] = out;
+                    changed = true;
+                }
+            }
+        }
+        self.live_out = live_out;
+    }
+
+    fn build(&self) -> Result<Decompiled, DecompileError> {
+        let mut body = if self.nodes.is_empty() {
+            vec![]
+        } else {
+            self.do_tree(0)
+        };
+        body.push(Stmt::Halt);
+        simplify(&mut body);
+        while self.propagate(&mut body) {}
+        simplify(&mut body);
+        idioms(&mut body);
+        simplify(&mut body);
+        Ok(Decompiled { body })
+    }
+
+    fn do_tree(&self, x: usize) -> Vec<Stmt> {
+        let mut merges = (0..self.nodes.len())
+            .filter(|&y| y != x && self.idom[y] == x && self.merges[y])
+            .collect::<Vec<_>>();
+        merges.sort_by_key(|&y| std::cmp::Reverse(self.rpo[y]));
+        let code = self.node_within(x, &merges);
+        if self.headers[x] {
+            vec![Stmt::Loop {
+                label: self.nodes[x].start,
+                body: code,
+            }]
+        } else {
+            code
+        }
+    }
+
+    fn node_within(&self, x: usize, merges: &[usize]) -> Vec<Stmt> {
+        if let Some((&y, rest)) = merges.split_first() {
+            let mut out = vec![Stmt::Block {
+                label: self.nodes[y].start,
+                body: self.node_within(x, rest),
+            }];
+            out.extend(self.do_tree(y));
+            return out;
+        }
+
+        let node = &self.nodes[x];
+        let mut out = vec![];
+        for i in node.start..node.end {
+            if Some(self.program.instructions[i].c) != self.program.ip {
+                out.push(Stmt::Assign {
+                    reg: self.program.instructions[i].c,
+                    expr: self.values[i].clone().unwrap(),
+                    at: i,
+                });
+            }
+        }
+        let last = node.end - 1;
+        match &self.jumps[last] {
+            Jump::Next | Jump::Goto(_) => out.extend(self.branch(x, node.succ[0])),
+            Jump::Branch(cond, ..) => out.push(Stmt::If {
+                cond: cond.clone(),
+                then: self.branch(x, node.succ[0]),
+                otherwise: self.branch(x, node.succ[1]),
+                at: last,
+            }),
+        }
+        out
+    }
+
+    fn branch(&self, x: usize, y: Option<usize>) -> Vec<Stmt> {
+        let y = match y {
+            Some(y) => y,
+            None => return vec![Stmt::Halt],
+        };
+        let label = self.nodes[y].start;
+        if self.rpo[y] <= self.rpo[x] {
+            vec![Stmt::Continue(label)]
+        } else if self.merges[y] {
+            vec![Stmt::Break(label)]
+        } else {
+            self.do_tree(y)
+        }
+    }
+
+    /// Removes dead writes and forwards single-use values into the following
+    /// statement, when the register being written is dead afterwards.
+    /// Returns true if anything changed.
+    fn propagate(&self, stmts: &mut Vec<Stmt>) -> bool {
+        let mut changed = false;
+        for s in stmts.iter_mut() {
+            changed |= match s {
+                Stmt::If {
+                    then, otherwise, ..
+                } => self.propagate(then) | self.propagate(otherwise),
+                Stmt::Loop { body, .. } | Stmt::Block { body, .. } => self.propagate(body),
+                _ => false,
+            };
+        }
+        // Remove writes to dead registers
+        let n = stmts.len();
+        stmts.retain(|s| match s {
+            Stmt::Assign { reg, at, .. } => self.live_out[*at].contains(reg),
+            _ => true,
+        });
+        changed |= stmts.len() != n;
+
+        let mut i = 0;
+        while i + 1 < stmts.len() {
+            let (reg, expr) = match &stmts[i] {
+                Stmt::Assign { reg, expr, .. } => (*reg, expr.clone()),
+                _ => {
+                    i += 1;
+                    continue;
+                }
+            };
+            let ok = match &stmts[i + 1] {
+                Stmt::Assign {
+                    reg: r,
+                    expr: e,
+                    at,
+                } => e.uses(Some(reg)) == 1 && (*r == reg || !self.live_out[*at].contains(&reg)),
+                Stmt::If { cond, at, .. } => {
+                    cond.expr.uses(Some(reg)) == 1 && !self.live_out[*at].contains(&reg)
+                }
+                _ => false,
+            };
+            if ok {
+                match &mut stmts[i + 1] {
+                    Stmt::Assign { expr: e, .. } => e.substitute(reg, &expr),
+                    Stmt::If { cond, .. } => cond.expr.substitute(reg, &expr),
+                    _ => unreachable!(),
+                }
+                stmts.remove(i);
+                changed = true;
+            } else {
+                i += 1;
+            }
+        }
+        changed
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Checks whether a statement list never falls through to its end
+fn jumps_away(stmts: &[Stmt]) -> bool {
+    match stmts.last() {
+        Some(Stmt::Break(_)) | Some(Stmt::Continue(_)) | Some(Stmt::Halt) => true,
+        Some(Stmt::If {
+            then, otherwise, ..
+        }) => jumps_away(then) && jumps_away(otherwise),
+        _ => false,
+    }
+}
+
+/// Counts `Break` statements which target the given label
+fn breaks(stmts: &[Stmt], label: usize) -> usize {
+    stmts
+        .iter()
+        .map(|s| match s {
+            Stmt::Break(t) => (*t == label) as usize,
+            Stmt::If {
+                then, otherwise, ..
+            } => breaks(then, label) + breaks(otherwise, label),
+            Stmt::Loop { body, .. } | Stmt::Block { body, .. } => breaks(body, label),
+            _ => 0,
+        })
+        .sum()
+}
+
+fn retarget(stmts: &mut [Stmt], from: usize, to: usize) {
+    for s in stmts {
+        match s {
+            Stmt::Break(t) if *t == from => *t = to,
+            Stmt::If {
+                then, otherwise, ..
+            } => {
+                retarget(then, from, to);
+                retarget(otherwise, from, to);
+            }
+            Stmt::Loop { body, .. } | Stmt::Block { body, .. } => retarget(body, from, to),
+            _ => (),
+        }
+    }
+}
+
+/// Removes a jump to `target` in tail position, since falling off the end
+/// of the statement list has the same effect
+fn drop_tail(stmts: &mut Vec<Stmt>, target: &Stmt) {
+    match stmts.last_mut() {
+        Some(s) if s == target => {
+            stmts.pop();
+        }
+        Some(Stmt::If {
+            then, otherwise, ..
+        }) => {
+            drop_tail(then, target);
+            drop_tail(otherwise, target);
+        }
+        _ => (),
+    }
+}
+
+/// Checks whether any jump targets the given label
+fn mentions(stmts: &[Stmt], label: usize) -> bool {
+    stmts.iter().any(|s| match s {
+        Stmt::Break(t) | Stmt::Continue(t) => *t == label,
+        Stmt::If {
+            then, otherwise, ..
+        } => mentions(then, label) || mentions(otherwise, label),
+        Stmt::Loop { body, .. } | Stmt::Block { body, .. } => mentions(body, label),
+        _ => false,
+    })
+}
+
+/// If a loop has no breaks, then its last `if c { ... }` which always jumps
+/// away can become `if c { break }`, with the branch moved after the loop.
+/// Returns the moved statements.
+fn hoist_exit(body: &mut [Stmt], label: usize) -> Vec<Stmt> {
+    if breaks(body, label) > 0 {
+        return vec![];
+    }
+    for s in body.iter_mut().rev() {
+        if let Stmt::If {
+            then, otherwise, ..
+        } = s
+        {
+            if otherwise.is_empty() && jumps_away(then) && !mentions(then, label) {
+                return std::mem::replace(then, vec![Stmt::Break(label)]);
+            }
+        }
+    }
+    vec![]
+}
+
+/// Tidies up structured code, removing redundant jumps and blocks
+fn simplify(stmts: &mut Vec<Stmt>) {
+    let mut out = Vec::with_capacity(stmts.len());
+    for mut s in stmts.drain(..) {
+        match &mut s {
+            Stmt::Loop { label, body } => {
+                simplify(body);
+                drop_tail(body, &Stmt::Continue(*label));
+            }
+            Stmt::Block { label, body } => {
+                simplify(body);
+                drop_tail(body, &Stmt::Break(*label));
+            }
+            Stmt::If {
+                then, otherwise, ..
+            } => {
+                simplify(then);
+                simplify(otherwise);
+            }
+            _ => (),
+        }
+        match s {
+            // Blocks without any breaks are just a sequence of statements
+            Stmt::Block { label, body } if breaks(&body, label) == 0 => {
+                out.extend(body);
+            }
+            // Blocks that end with a loop can break out of the loop instead,
+            // if all of their breaks are within that loop
+            Stmt::Block { label, mut body }
+                if matches!(body.last(), Some(Stmt::Loop { body: b, .. })
+                            if breaks(b, label) == breaks(&body, label)) =>
+            {
+                let inner = match body.last() {
+                    Some(Stmt::Loop { label, .. }) => *label,
+                    _ => unreachable!(),
+                };
+                retarget(&mut body, label, inner);
+                out.extend(body);
+            }
+            Stmt::If {
+                cond,
+                then,
+                otherwise,
+                at,
+            } => {
+                // Prefer an empty else branch, and hoist the else branch out
+                // when the then branch always jumps away
+                let (cond, then, otherwise) = if then.is_empty() {
+                    (cond.negate(), otherwise, then)
+                } else {
+                    (cond, then, otherwise)
+                };
+                if !otherwise.is_empty() && jumps_away(&then) {
+                    out.push(Stmt::If {
+                        cond,
+                        then,
+                        otherwise: vec![],
+                        at,
+                    });
+                    out.extend(otherwise);
+                } else if !then.is_empty() {
+                    out.push(Stmt::If {
+                        cond,
+                        then,
+                        otherwise,
+                        at,
+                    });
+                }
+            }
+            Stmt::Loop { label, mut body } => {
+                let exit = hoist_exit(&mut body, label);
+                out.push(Stmt::Loop { label, body });
+                out.extend(exit);
+            }
+            s => out.push(s),
+        }
+    }
+    // Anything after an unconditional jump is unreachable
+    if let Some(i) = out
+        .iter()
+        .position(|s| matches!(s, Stmt::Break(_) | Stmt::Continue(_) | Stmt::Halt))
+    {
+        out.truncate(i + 1);
+    }
+    *stmts = out;
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+fn is_incr(s: &Stmt, r: usize) -> bool {
+    match s {
+        Stmt::Assign {
+            reg,
+            expr: Expr::Bin(Kind::Add, a, b),
+            ..
+        } if *reg == r => {
+            matches!((&**a, &**b), (Expr::Reg(x), Expr::Imm(1)) | (Expr::Imm(1), Expr::Reg(x)) if *x == r)
+        }
+        _ => false,
+    }
+}
+
+fn is_set(s: &Stmt, r: usize, v: usize) -> bool {
+    matches!(s, Stmt::Assign { reg, expr: Expr::Imm(x), .. } if *reg == r && *x == v)
+}
+
+/// Matches `if a > b { break label }` or `if b <= a`, returning `(a, b)`
+fn exit_if_gt(s: &Stmt, label: usize) -> Option<(&Expr, &Expr)> {
+    match s {
+        Stmt::If {
+            cond:
+                Cond {
+                    expr: Expr::Bin(Kind::Gt, a, b),
+                    negated: false,
+                },
+            then,
+            otherwise,
+            ..
+        } if then == &[Stmt::Break(label)] && otherwise.is_empty() => Some((a, b)),
+        _ => None,
+    }
+}
+
+/// Matches `(q + 1) * k` in either order, returning `k`
+fn succ_times(e: &Expr, q: usize) -> Option<&Expr> {
+    let inc = |e: &Expr| match e {
+        Expr::Bin(Kind::Add, a, b) => {
+            matches!((&**a, &**b), (Expr::Reg(x), Expr::Imm(1)) | (Expr::Imm(1), Expr::Reg(x)) if *x == q)
+        }
+        _ => false,
+    };
+    match e {
+        Expr::Bin(Kind::Mul, a, b) if inc(a) && b.uses(Some(q)) == 0 => Some(b),
+        Expr::Bin(Kind::Mul, a, b) if inc(b) && a.uses(Some(q)) == 0 => Some(a),
+        _ => None,
+    }
+}
+
+/// Matches a do-while loop which counts `r` up until `r > n`, returning
+/// `(r, n, body)` where body excludes the increment and test
+fn counted(s: &Stmt) -> Option<(usize, &Expr, &[Stmt])> {
+    match s {
+        Stmt::Loop { label, body } if body.len() >= 2 => {
+            let (test, rest) = body.split_last().unwrap();
+            let (incr, inner) = rest.split_last().unwrap();
+            match exit_if_gt(test, *label) {
+                Some((Expr::Reg(r), n)) if is_incr(incr, *r) && n.uses(Some(*r)) == 0 => {
+                    Some((*r, n, inner))
+                }
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Checks for `if i * j == n { acc += i }`
+fn divisor_test(s: &[Stmt], i: usize, j: usize, n: &Expr) -> Option<usize> {
+    let (cond, then) = match s {
+        [Stmt::If {
+            cond:
+                Cond {
+                    expr: Expr::Bin(Kind::Eq, a, b),
+                    negated: false,
+                },
+            then,
+            otherwise,
+            ..
+        }] if otherwise.is_empty() => {
+            let prod = |e: &Expr| match e {
+                Expr::Bin(Kind::Mul, x, y) => {
+                    let (x, y) = (&**x, &**y);
+                    (x, y) == (&Expr::Reg(i), &Expr::Reg(j))
+                        || (x, y) == (&Expr::Reg(j), &Expr::Reg(i))
+                }
+                _ => false,
+            };
+            let c = (prod(a) && **b == *n) || (prod(b) && **a == *n);
+            (c, then)
+        }
+        _ => return None,
+    };
+    match then.as_slice() {
+        [Stmt::Assign {
+            reg,
+            expr: Expr::Bin(Kind::Add, a, b),
+            ..
+        }] if cond && *reg != i && *reg != j && n.uses(Some(*reg)) == 0 => {
+            let ok = (**a == Expr::Reg(*reg) && **b == Expr::Reg(i))
+                || (**b == Expr::Reg(*reg) && **a == Expr::Reg(i));
+            if ok {
+                Some(*reg)
+            } else {
+                None
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Matches `q = 0; loop { if (q + 1) * k > n { break }; q += 1 }`
+fn div_loop(init: &Stmt, s: &Stmt) -> Option<Idiom> {
+    let (label, test, incr) = match s {
+        Stmt::Loop { label, body } if body.len() == 2 => (*label, &body[0], &body[1]),
+        _ => return None,
+    };
+    let q = match init {
+        Stmt::Assign { reg, .. } => *reg,
+        _ => return None,
+    };
+    let (lhs, n) = exit_if_gt(test, label)?;
+    let k = succ_times(lhs, q)?;
+    let nonzero = k.constant().is_some_and(|k| k != 0);
+    if nonzero && is_set(init, q, 0) && is_incr(incr, q) && n.uses(Some(q)) == 0 {
+        Some(Idiom::Div {
+            q,
+            n: n.clone(),
+            k: k.clone(),
+        })
+    } else {
+        None
+    }
+}
+
+/// Matches `i = 1; loop { j = 1; loop { if i * j == n { acc += i };
+/// j += 1; if j > n { break } }; i += 1; if i > n { break } }`
+fn divisor_loop(init: &Stmt, s: &Stmt) -> Option<Idiom> {
+    let (i, n, body) = counted(s)?;
+    let (init_j, inner) = match body {
+        [a, b] => (a, b),
+        _ => return None,
+    };
+    let (j, n2, test) = counted(inner)?;
+    let acc = divisor_test(test, i, j, n)?;
+    if n == n2 && n.uses(Some(j)) == 0 && i != j && is_set(init, i, 1) && is_set(init_j, j, 1) {
+        Some(Idiom::DivisorSum {
+            acc,
+            n: n.clone(),
+            i,
+            j,
+        })
+    } else {
+        None
+    }
+}
+
+/// Replaces recognized loops with native implementations
+fn idioms(stmts: &mut Vec<Stmt>) {
+    for s in stmts.iter_mut() {
+        match s {
+            Stmt::If {
+                then, otherwise, ..
+            } => {
+                idioms(then);
+                idioms(otherwise);
+            }
+            Stmt::Loop { body, .. } | Stmt::Block { body, .. } => idioms(body),
+            _ => (),
+        }
+    }
+    let mut i = 0;
+    while i + 1 < stmts.len() {
+        let native =
+            div_loop(&stmts[i], &stmts[i + 1]).or_else(|| divisor_loop(&stmts[i], &stmts[i + 1]));
+        if let Some(native) = native {
+            stmts.splice(i..i + 2, std::iter::once(Stmt::Native(native)));
+        } else {
+            i += 1;
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+impl Decompiled {
+    /// Runs the decompiled program (including native idioms) until it halts
+    pub fn run(&self, regs: &mut Registers) {
+        run(&self.body, regs);
+    }
+
+    fn write(
+        &self,
+        f: &mut fmt::Formatter,
+        stmts: &[Stmt],
+        depth: usize,
+        loops: &mut Vec<usize>,
+    ) -> fmt::Result {
+        let indent = "    ".repeat(depth);
+        let loop_name = |t: &usize, loops: &Vec<usize>| {
+            if loops.last() == Some(t) {
+                String::new()
+            } else if loops.contains(t) {
+                format!(" 'l{}", t)
+            } else {
+                format!(" 'b{}", t)
+            }
+        };
+        for s in stmts {
+            match s {
+                Stmt::Assign { reg, expr, .. } => {
+                    // Use compound assignment when the register is updated
+                    match expr {
+                        Expr::Bin(k, a, b) if ![Kind::Gt, Kind::Eq].contains(k) => {
+                            if **a == Expr::Reg(*reg) {
+                                writeln!(f, "{}r{} {}= {}", indent, reg, symbol(*k), b)?
+                            } else if **b == Expr::Reg(*reg) {
+                                writeln!(f, "{}r{} {}= {}", indent, reg, symbol(*k), a)?
+                            } else {
+                                writeln!(f, "{}r{} = {}", indent, reg, expr)?
+                            }
+                        }
+                        _ => writeln!(f, "{}r{} = {}", indent, reg, expr)?,
+                    }
+                }
+                Stmt::If {
+                    cond,
+                    then,
+                    otherwise,
+                    ..
+                } => {
+                    writeln!(f, "{}if {} {{", indent, cond)?;
+                    self.write(f, then, depth + 1, loops)?;
+                    if !otherwise.is_empty() {
+                        writeln!(f, "{}}} else {{", indent)?;
+                        self.write(f, otherwise, depth + 1, loops)?;
+                    }
+                    writeln!(f, "{}}}", indent)?;
+                }
+                Stmt::Loop { label, body } => {
+                    // Labels are only needed for jumps from nested loops
+                    let mut outer = loops.clone();
+                    outer.push(*label);
+                    let name = if nested_jumps(body, *label, false) {
+                        format!("'l{}: ", label)
+                    } else {
+                        String::new()
+                    };
+                    loops.push(*label);
+                    match body.split_first() {
+                        Some((test, rest)) if exit_test(test, *label).is_some() => {
+                            let cond = exit_test(test, *label).unwrap();
+                            writeln!(f, "{}{}while {} {{", indent, name, cond)?;
+                            self.write(f, rest, depth + 1, loops)?;
+                        }
+                        _ => {
+                            writeln!(f, "{}{}loop {{", indent, name)?;
+                            self.write(f, body, depth + 1, loops)?;
+                        }
+                    }
+                    loops.pop();
+                    writeln!(f, "{}}}", indent)?;
+                }
+                Stmt::Block { label, body } => {
+                    writeln!(f, "{}'b{}: {{", indent, label)?;
+                    self.write(f, body, depth + 1, loops)?;
+                    writeln!(f, "{}}}", indent)?;
+                }
+                Stmt::Break(t) => writeln!(f, "{}break{}", indent, loop_name(t, loops))?,
+                Stmt::Continue(t) => writeln!(f, "{}continue{}", indent, loop_name(t, loops))?,
+                Stmt::Halt => writeln!(f, "{}halt", indent)?,
+                Stmt::Native(Idiom::Div { q, n, k, .. }) => {
+                    writeln!(f, "{}r{} = {} / {}  // native", indent, q, n, k)?
+                }
+                Stmt::Native(Idiom::DivisorSum { acc, n, .. }) => {
+                    writeln!(f, "{}r{} += sum_of_divisors({})  // native", indent, acc, n)?
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Matches `if c { break label }`, returning the loop condition `!c`
+fn exit_test(s: &Stmt, label: usize) -> Option<Cond> {
+    match s {
+        Stmt::If {
+            cond,
+            then,
+            otherwise,
+            ..
+        } if then == &[Stmt::Break(label)] && otherwise.is_empty() => Some(cond.clone().negate()),
+        _ => None,
+    }
+}
+
+/// Checks whether any jump to `label` is inside a nested loop
+fn nested_jumps(stmts: &[Stmt], label: usize, nested: bool) -> bool {
+    stmts.iter().any(|s| match s {
+        Stmt::Break(t) | Stmt::Continue(t) => nested && *t == label,
+        Stmt::If {
+            then, otherwise, ..
+        } => nested_jumps(then, label, nested) || nested_jumps(otherwise, label, nested),
+        Stmt::Loop { body, .. } => nested_jumps(body, label, true),
+        Stmt::Block { body, .. } => nested_jumps(body, label, nested),
+        _ => false,
+    })
+}
+
+impl fmt::Display for Decompiled {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.write(f, &self.body, 0, &mut vec![])
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Vm;
+
+    fn check(text: &str, init: Known, inputs: &[Registers]) -> String {
+        let p: Program = text.parse().unwrap();
+        let d = decompile_with(&p, init).unwrap();
+        for regs in inputs {
+            let mut vm = Vm::new(p.clone());
+            vm.regs = *regs;
+            vm.run();
+            let mut out = *regs;
+            d.run(&mut out);
+            assert_eq!(out[0], vm.regs[0], "mismatch for {:?}", regs);
+        }
+        d.to_string()
+    }
+
+    #[test]
+    fn structure() {
+        let text = "#ip 5\nseti 0 0 0\ngtrr 1 2 3\naddr 3 5 5\naddi 5 1 5\n\
+                    seti 99 0 5\naddr 0 1 0\naddi 1 1 1\nseti 0 0 5";
+        let inputs = [[0, 3, 6, 0, 0, 0], [0, 7, 6, 0, 0, 0]];
+        assert_eq!(
+            check(text, [None; 6], &inputs),
+            "r0 = 0\nwhile r1 <= r2 {\n    r0 += r1\n    r1 += 1\n}\nhalt\n"
+        );
+    }
+
+    #[test]
+    fn div() {
+        let text = "#ip 3\nseti 0 0 1\naddi 1 1 5\nmuli 5 7 5\ngtrr 5 2 5\n\
+                    addr 5 3 3\naddi 3 1 3\nseti 8 0 3\naddi 1 1 1\nseti 0 0 3\n\
+                    setr 1 0 0";
+        let inputs = [[0, 0, 50, 0, 0, 0], [0, 0, 6, 0, 0, 0], [0, 0, 7, 0, 0, 0]];
+        assert_eq!(
+            check(text, [None; 6], &inputs),
+            "r1 = r2 / 7  // native\nr0 = r1\nhalt\n"
+        );
+
+        // Dividing by a register could loop forever, so it isn't replaced
+        let text = text.replace("muli 5 7 5", "mulr 5 4 5");
+        let inputs = [[0, 0, 50, 0, 7, 0], [0, 0, 6, 0, 3, 0]];
+        assert!(!check(&text, [None; 6], &inputs).contains("native"));
+    }
+
+    #[test]
+    fn divisor_sum() {
+        // The same structure as day 19, with the target in r2
+        let text = "#ip 5\nseti 1 0 3\nseti 1 0 1\nmulr 3 1 4\neqrr 4 2 4\n\
+                    addr 4 5 5\naddi 5 1 5\naddr 3 0 0\naddi 1 1 1\ngtrr 1 2 4\n\
+                    addr 5 4 5\nseti 1 0 5\naddi 3 1 3\ngtrr 3 2 4\naddr 4 5 5\n\
+                    seti 0 0 5";
+        let inputs = [[0, 0, 12, 0, 0, 0], [5, 0, 1, 0, 0, 0], [3, 0, 0, 0, 0, 0]];
+        assert_eq!(
+            check(text, [None; 6], &inputs),
+            "r0 += sum_of_divisors(r2)  // native\nhalt\n"
+        );
+
+        // The counters match the original loop, even when it runs once (r4
+        // is scratch, which the native version doesn't write)
+        let p: Program = text.parse().unwrap();
+        let d = decompile(&p).unwrap();
+        for n in [0, 1, 12] {
+            let mut vm = Vm::new(p.clone());
+            vm.regs[2] = n;
+            vm.run();
+            let mut regs = [0, 0, n, 0, 0, 0];
+            d.run(&mut regs);
+            assert_eq!(regs[..4], vm.regs[..4], "mismatch for n = {}", n);
+        }
+
+        // Specializing folds the target into a constant
+        let p: Program = text.parse().unwrap();
+        let d = decompile_with(&p, [Some(0), None, Some(28), None, None, None]).unwrap();
+        assert_eq!(
+            d.to_string(),
+            "r0 += sum_of_divisors(28)  // native\nhalt\n"
+        );
+        let mut regs = [0, 0, 28, 0, 0, 0];
+        d.run(&mut regs);
+        assert_eq!(regs[0], 56);
+    }
+
+    #[test]
+    fn leaders() {
+        // Same program as `structure`: the branch at 2 goes to 3 or 4, then
+        // 3 jumps to 5, 4 exits, and 7 jumps back to 1
+        let text = "#ip 5\nseti 0 0 0\ngtrr 1 2 3\naddr 3 5 5\naddi 5 1 5\n\
+                    seti 99 0 5\naddr 0 1 0\naddi 1 1 1\nseti 0 0 5";
+        let p: Program = text.parse().unwrap();
+        let leader = super::leaders(&p).unwrap();
+        let starts = (0..leader.len()).filter(|i| leader[*i]).collect::<Vec<_>>();
+        assert_eq!(starts, vec![0, 1, 3, 4, 5, 8]);
+
+        let p: Program = "#ip 1\naddr 2 1 1\nseti 0 0 0".parse().unwrap();
+        assert_eq!(super::leaders(&p), Err(DecompileError::ComputedJump(0)));
+    }
+
+    #[test]
+    fn errors() {
+        let p: Program = "#ip 1\naddr 2 1 1\nseti 0 0 0".parse().unwrap();
+        assert_eq!(decompile(&p), Err(DecompileError::ComputedJump(0)));
+
+        // Known values resolve the jump
+        let d = decompile_with(&p, [None, None, Some(0), None, None, None]).unwrap();
+        assert_eq!(d.to_string(), "r0 = 0\nhalt\n");
+
+        // Jumping directly onto a conditional jump isn't allowed
+        let p: Program = "#ip 1\neqrr 2 3 0\naddr 0 1 1\nseti 0 0 1".parse().unwrap();
+        assert_eq!(decompile(&p), Err(DecompileError::ComputedJump(1)));
+    }
+}
@@ -0,0 +1,334 @@
+//! Inference of opcode numbers from before / after samples, as in day 16
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+
+use crate::Op;
+
+/// An instruction set which can be used for inference
+pub trait Eval: Copy {
+    /// Applies the operation to the registers, writing to `c`.  Returns
+    /// `false` if the arguments are invalid for this operation.
+    fn apply(self, regs: &mut [usize], a: usize, b: usize, c: usize) -> bool;
+}
+
+impl Eval for Op {
+    fn apply(self, regs: &mut [usize], a: usize, b: usize, c: usize) -> bool {
+        if c >= regs.len() {
+            return false;
+        }
+        match self.try_eval(regs, a, b) {
+            Some(v) => {
+                regs[c] = v;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// An observation of a single instruction.  Any number of registers is
+/// allowed, so samples can be taken from day 16 (four registers) or from
+/// running the day 19 and 21 programs (six registers).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Sample {
+    pub before: Vec<usize>,
+    /// Opcode number, followed by the `a`, `b`, and `c` arguments
+    pub code: [usize; 4],
+    pub after: Vec<usize>,
+}
+
+impl Sample {
+    /// Checks whether the given operation explains this sample
+    pub fn matches<O: Eval>(&self, op: O) -> bool {
+        let mut regs = self.before.clone();
+        let [_, a, b, c] = self.code;
+        op.apply(&mut regs, a, b, c) && regs == self.after
+    }
+
+    /// Returns every operation which explains this sample
+    pub fn candidates<O: Eval>(&self, ops: &[O]) -> Vec<O> {
+        ops.iter().cloned().filter(|op| self.matches(*op)).collect()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum InferError<O> {
+    /// More than one mapping is consistent with the samples.  This lists
+    /// every opcode number with more than one possible operation.
+    Ambiguous(BTreeMap<usize, Vec<O>>),
+    /// No mapping is consistent with the samples.  This lists a set of
+    /// opcode numbers which can't be assigned, and the samples which use
+    /// them.
+    Contradiction {
+        opcodes: Vec<usize>,
+        samples: Vec<usize>,
+    },
+}
+
+impl<O: fmt::Display> fmt::Display for InferError<O> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InferError::Ambiguous(c) => {
+                write!(f, "Ambiguous opcodes:")?;
+                for (k, ops) in c {
+                    let ops = ops.iter().map(|o| o.to_string()).collect::<Vec<_>>();
+                    write!(f, " {} could be [{}];", k, ops.join(", "))?;
+                }
+                Ok(())
+            }
+            InferError::Contradiction { opcodes, samples } => write!(
+                f,
+                "Contradictory samples {:?} for opcodes {:?}",
+                samples, opcodes
+            ),
+        }
+    }
+}
+
+/// Possible operation indices for each opcode number
+type Domains = BTreeMap<usize, BTreeSet<usize>>;
+
+/// Finds an assignment of distinct operations to every opcode, using
+/// constraint propagation with backtracking.
+fn search(mut d: Domains) -> Option<BTreeMap<usize, usize>> {
+    // Propagate singletons, since each operation is used at most once
+    let mut done = BTreeSet::new();
+    while let Some((&k, v)) = d.iter().find(|(k, v)| v.len() == 1 && !done.contains(*k)) {
+        let v = *v.iter().next().unwrap();
+        done.insert(k);
+        for (_, s) in d.iter_mut().filter(|(j, _)| **j != k) {
+            s.remove(&v);
+            if s.is_empty() {
+                return None;
+            }
+        }
+    }
+    if d.values().any(|v| v.is_empty()) {
+        return None;
+    }
+
+    // Branch on the opcode with the fewest remaining options
+    match d
+        .iter()
+        .filter(|(_, v)| v.len() > 1)
+        .min_by_key(|(_, v)| v.len())
+    {
+        None => Some(
+            d.into_iter()
+                .map(|(k, v)| (k, *v.iter().next().unwrap()))
+                .collect(),
+        ),
+        Some((&k, v)) => v.clone().into_iter().find_map(|choice| {
+            let mut d = d.clone();
+            d.insert(k, std::iter::once(choice).collect());
+            search(d)
+        }),
+    }
+}
+
+/// Solves for the mapping from opcode numbers to operations, given a set of
+/// samples.  Each operation may be assigned to at most one opcode number.
+pub fn infer<O: Eval>(ops: &[O], samples: &[Sample]) -> Result<HashMap<usize, O>, InferError<O>> {
+    // Intersect candidates for each opcode number, stopping early if a
+    // group of samples can't be explained by any single operation.
+    let mut domains = Domains::new();
+    let mut seen: BTreeMap<usize, Vec<(usize, BTreeSet<usize>)>> = BTreeMap::new();
+    for (i, s) in samples.iter().enumerate() {
+        let k = s.code[0];
+        let c = (0..ops.len())
+            .filter(|j| s.matches(ops[*j]))
+            .collect::<BTreeSet<_>>();
+        let d = domains.entry(k).or_insert_with(|| (0..ops.len()).collect());
+        *d = d.intersection(&c).cloned().collect();
+        if d.is_empty() {
+            // Walk back through earlier samples to find a small conflicting
+            // set which includes this one.
+            let mut out = vec![i];
+            let mut acc = c;
+            for (j, prev) in seen.get(&k).into_iter().flatten().rev() {
+                if acc.is_empty() {
+                    break;
+                }
+                let next = acc.intersection(prev).cloned().collect::<BTreeSet<_>>();
+                if next.len() < acc.len() {
+                    out.push(*j);
+                    acc = next;
+                }
+            }
+            out.sort_unstable();
+            return Err(InferError::Contradiction {
+                opcodes: vec![k],
+                samples: out,
+            });
+        }
+        seen.entry(k).or_default().push((i, c));
+    }
+
+    let uses = |opcodes: &[usize]| {
+        (0..samples.len())
+            .filter(|i| opcodes.contains(&samples[*i].code[0]))
+            .collect::<Vec<_>>()
+    };
+
+    let solution = match search(domains.clone()) {
+        Some(s) => s,
+        None => {
+            // Shrink the set of opcodes while it remains unsolvable, which
+            // leaves a minimal group that can't be assigned distinct ops.
+            let mut core = domains;
+            for k in core.keys().cloned().collect::<Vec<_>>() {
+                let mut smaller = core.clone();
+                smaller.remove(&k);
+                if search(smaller.clone()).is_none() {
+                    core = smaller;
+                }
+            }
+            let opcodes = core.keys().cloned().collect::<Vec<_>>();
+            let samples = uses(&opcodes);
+            return Err(InferError::Contradiction { opcodes, samples });
+        }
+    };
+
+    // Check every other candidate to see whether it's part of some solution
+    let mut ambiguous = BTreeMap::new();
+    for (k, d) in &domains {
+        let possible = d
+            .iter()
+            .filter(|v| {
+                solution[k] == **v || {
+                    let mut fixed = domains.clone();
+                    fixed.insert(*k, std::iter::once(**v).collect());
+                    search(fixed).is_some()
+                }
+            })
+            .map(|v| ops[*v])
+            .collect::<Vec<_>>();
+        if possible.len() > 1 {
+            ambiguous.insert(*k, possible);
+        }
+    }
+    if !ambiguous.is_empty() {
+        return Err(InferError::Ambiguous(ambiguous));
+    }
+    Ok(solution.into_iter().map(|(k, v)| (k, ops[v])).collect())
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Op::*;
+
+    fn sample(before: [usize; 4], code: [usize; 4], after: [usize; 4]) -> Sample {
+        Sample {
+            before: before.to_vec(),
+            code,
+            after: after.to_vec(),
+        }
+    }
+
+    #[test]
+    fn candidates() {
+        // Example from day 16
+        let s = sample([3, 2, 1, 1], [9, 2, 1, 2], [3, 2, 2, 1]);
+        assert_eq!(s.candidates(&Op::ALL), vec![addi, mulr, seti]);
+
+        // Out-of-range registers don't match anything
+        let s = sample([0, 0, 0, 0], [0, 7, 0, 1], [0, 7, 0, 0]);
+        assert!(!s.matches(setr));
+        assert!(s.matches(seti));
+
+        // Nor do operations which would overflow
+        let big = usize::MAX;
+        let s = sample([big, 2, 0, 0], [0, 0, 1, 0], [big, 2, 0, 0]);
+        assert_eq!(s.candidates(&Op::ALL), vec![muli, borr, bori, setr]);
+    }
+
+    #[test]
+    fn solve() {
+        // 2 + 2 is explained by addr or mulr, and 2 + 3 only by addr
+        let both = [2, 2, 0, 0];
+        let samples = vec![
+            sample(both, [0, 0, 1, 2], [2, 2, 4, 0]),
+            sample([2, 3, 0, 0], [1, 0, 1, 2], [2, 3, 5, 0]),
+        ];
+        let m = infer(&Op::ALL, &samples).unwrap();
+        assert_eq!(m[&0], mulr);
+        assert_eq!(m[&1], addr);
+    }
+
+    #[test]
+    fn ambiguous() {
+        let samples = vec![
+            sample([2, 2, 0, 0], [0, 0, 1, 2], [2, 2, 4, 0]),
+            sample([2, 2, 0, 0], [1, 0, 1, 2], [2, 2, 4, 0]),
+            sample([5, 0, 0, 0], [2, 0, 0, 1], [5, 5, 0, 0]),
+        ];
+        let mut expected = BTreeMap::new();
+        expected.insert(0, vec![addr, mulr]);
+        expected.insert(1, vec![addr, mulr]);
+        expected.insert(2, vec![addi, banr, borr, bori, setr]);
+        match infer(&Op::ALL, &samples) {
+            Err(InferError::Ambiguous(c)) => assert_eq!(c, expected),
+            r => panic!("Unexpected result {:?}", r),
+        }
+
+        // Pinning one opcode resolves the other, leaving opcode 2 open
+        let mut samples = samples;
+        samples.push(sample([2, 3, 0, 0], [1, 0, 1, 2], [2, 3, 5, 0]));
+        let mut expected = BTreeMap::new();
+        expected.insert(2, vec![addi, banr, borr, bori, setr]);
+        assert_eq!(
+            infer(&Op::ALL, &samples),
+            Err(InferError::Ambiguous(expected))
+        );
+    }
+
+    #[test]
+    fn contradiction() {
+        // No operation can produce 5 from nothing
+        let samples = vec![sample([0, 0, 0, 0], [3, 0, 0, 0], [5, 0, 0, 0])];
+        assert_eq!(
+            infer(&Op::ALL, &samples),
+            Err(InferError::Contradiction {
+                opcodes: vec![3],
+                samples: vec![0],
+            })
+        );
+
+        // Opcode 0 can't be both {addr, mulr} and {addi, bori}
+        let samples = vec![
+            sample([2, 2, 0, 0], [0, 0, 1, 2], [2, 2, 4, 0]),
+            sample([9, 9, 9, 9], [1, 0, 0, 0], [9, 9, 9, 9]),
+            sample([2, 2, 0, 0], [0, 0, 1, 2], [2, 2, 3, 0]),
+        ];
+        assert_eq!(
+            infer(&Op::ALL, &samples),
+            Err(InferError::Contradiction {
+                opcodes: vec![0],
+                samples: vec![0, 2],
+            })
+        );
+
+        // Opcode 4 must be addr, so 1 and 2 can't both be mulr
+        let samples = vec![
+            sample([2, 2, 0, 0], [0, 0, 1, 2], [2, 2, 4, 0]),
+            sample([2, 3, 0, 0], [4, 0, 1, 2], [2, 3, 5, 0]),
+            sample([2, 2, 0, 0], [1, 0, 1, 2], [2, 2, 4, 0]),
+            sample([2, 2, 0, 0], [2, 0, 1, 2], [2, 2, 4, 0]),
+        ];
+        let e = infer(&Op::ALL, &samples).unwrap_err();
+        assert_eq!(
+            e,
+            InferError::Contradiction {
+                opcodes: vec![1, 2, 4],
+                samples: vec![1, 2, 3],
+            }
+        );
+        assert_eq!(
+            e.to_string(),
+            "Contradictory samples [1, 2, 3] for opcodes [1, 2, 4]"
+        );
+    }
+}
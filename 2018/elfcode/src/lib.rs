@@ -0,0 +1,374 @@
+//! Shared tools for the wrist-device "ElfCode" from 2018 (days 16, 19, 21)
+use std::fmt;
+use std::str::FromStr;
+
+pub mod decompile;
+pub mod infer;
+mod vm;
+pub use crate::vm::{Stop, Vm};
+
+/// The device has six registers (though day 16 only uses four)
+pub const NUM_REGISTERS: usize = 6;
+pub type Registers = [usize; NUM_REGISTERS];
+
+#[allow(non_camel_case_types)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Ord, PartialOrd)]
+pub enum Op {
+    addr,
+    addi,
+    mulr,
+    muli,
+    banr,
+    bani,
+    borr,
+    bori,
+    setr,
+    seti,
+    gtir,
+    gtri,
+    gtrr,
+    eqir,
+    eqri,
+    eqrr,
+}
+use crate::Op::*;
+
+/// The underlying operation performed by an opcode
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum Kind {
+    Add,
+    Mul,
+    And,
+    Or,
+    Set,
+    Gt,
+    Eq,
+}
+
+/// Where an opcode reads one of its arguments from
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum Source {
+    Register,
+    Immediate,
+    /// The argument is ignored (e.g. `b` for `setr` and `seti`)
+    Unused,
+}
+
+impl Op {
+    pub const ALL: [Op; 16] = [
+        addr, addi, mulr, muli, banr, bani, borr, bori, setr, seti, gtir, gtri, gtrr, eqir, eqri,
+        eqrr,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            addr => "addr",
+            addi => "addi",
+            mulr => "mulr",
+            muli => "muli",
+            banr => "banr",
+            bani => "bani",
+            borr => "borr",
+            bori => "bori",
+            setr => "setr",
+            seti => "seti",
+            gtir => "gtir",
+            gtri => "gtri",
+            gtrr => "gtrr",
+            eqir => "eqir",
+            eqri => "eqri",
+            eqrr => "eqrr",
+        }
+    }
+
+    pub fn kind(self) -> Kind {
+        match self {
+            addr | addi => Kind::Add,
+            mulr | muli => Kind::Mul,
+            banr | bani => Kind::And,
+            borr | bori => Kind::Or,
+            setr | seti => Kind::Set,
+            gtir | gtri | gtrr => Kind::Gt,
+            eqir | eqri | eqrr => Kind::Eq,
+        }
+    }
+
+    /// Returns the sources of the `a` and `b` arguments
+    pub fn sources(self) -> (Source, Source) {
+        use Source::*;
+        match self {
+            addr | mulr | banr | borr | gtrr | eqrr => (Register, Register),
+            addi | muli | bani | bori | gtri | eqri => (Register, Immediate),
+            gtir | eqir => (Immediate, Register),
+            setr => (Register, Unused),
+            seti => (Immediate, Unused),
+        }
+    }
+
+    /// Evaluates the opcode with the given registers and arguments,
+    /// returning the value which would be written to register `c`.
+    ///
+    /// This panics if a register argument is out of range.
+    pub fn eval(self, regs: &[usize], a: usize, b: usize) -> usize {
+        let (sa, sb) = self.sources();
+        let get = |s, v: usize| match s {
+            Source::Register => regs[v],
+            Source::Immediate | Source::Unused => v,
+        };
+        let (x, y) = (get(sa, a), get(sb, b));
+        match self.kind() {
+            Kind::Add => x + y,
+            Kind::Mul => x * y,
+            Kind::And => x & y,
+            Kind::Or => x | y,
+            Kind::Set => x,
+            Kind::Gt => (x > y) as usize,
+            Kind::Eq => (x == y) as usize,
+        }
+    }
+
+    /// Like `eval`, but returns `None` instead of panicking when a register
+    /// argument is out of range or the result overflows
+    pub fn try_eval(self, regs: &[usize], a: usize, b: usize) -> Option<usize> {
+        let (sa, sb) = self.sources();
+        let get = |s, v: usize| match s {
+            Source::Register => regs.get(v).copied(),
+            Source::Immediate | Source::Unused => Some(v),
+        };
+        let (x, y) = (get(sa, a)?, get(sb, b)?);
+        match self.kind() {
+            Kind::Add => x.checked_add(y),
+            Kind::Mul => x.checked_mul(y),
+            _ => Some(self.eval(regs, a, b)),
+        }
+    }
+}
+
+impl FromStr for Op {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, ()> {
+        Op::ALL.iter().find(|op| op.name() == s).cloned().ok_or(())
+    }
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// A single instruction, which stores `op(a, b)` into register `c`
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct Instruction {
+    pub op: Op,
+    pub a: usize,
+    pub b: usize,
+    pub c: usize,
+}
+
+impl Instruction {
+    pub fn eval(&self, regs: &mut [usize]) {
+        regs[self.c] = self.op.eval(regs, self.a, self.b);
+    }
+
+    /// Checks that every register argument is in range
+    pub fn is_valid(&self) -> bool {
+        let (sa, sb) = self.op.sources();
+        let ok = |s, v: usize| s != Source::Register || v < NUM_REGISTERS;
+        ok(sa, self.a) && ok(sb, self.b) && self.c < NUM_REGISTERS
+    }
+}
+
+impl FromStr for Instruction {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, ()> {
+        let words = s.split_whitespace().collect::<Vec<_>>();
+        match words.as_slice() {
+            [op, a, b, c] => {
+                let arg = |s: &str| s.parse::<usize>().map_err(|_| ());
+                let out = Instruction {
+                    op: op.parse()?,
+                    a: arg(a)?,
+                    b: arg(b)?,
+                    c: arg(c)?,
+                };
+                if out.is_valid() {
+                    Ok(out)
+                } else {
+                    Err(())
+                }
+            }
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} {} {}", self.op, self.a, self.b, self.c)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseError {
+    pub line: usize,
+    pub text: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Invalid instruction '{}' on line {}",
+            self.text, self.line
+        )
+    }
+}
+
+/// A program, with an optional `#ip` binding.
+///
+/// Instructions may be followed by a `#break` comment, which marks them as
+/// breakpoints; any other comments are ignored.  Execution stops just
+/// before a breakpoint instruction runs (see [`Vm::run`]).
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Program {
+    pub ip: Option<usize>,
+    pub instructions: Vec<Instruction>,
+    pub breakpoints: Vec<usize>,
+}
+
+impl Program {
+    pub fn len(&self) -> usize {
+        self.instructions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.instructions.is_empty()
+    }
+}
+
+impl FromStr for Program {
+    type Err = ParseError;
+    fn from_str(s: &str) -> Result<Self, ParseError> {
+        let mut out = Program::default();
+        for (i, line) in s.lines().enumerate() {
+            let err = || ParseError {
+                line: i + 1,
+                text: line.to_owned(),
+            };
+            let (code, comment) = match line.find('#') {
+                Some(0) => {
+                    let mut words = line.split_whitespace();
+                    if words.next() == Some("#ip") {
+                        let ip = words.next().and_then(|w| w.parse().ok());
+                        out.ip = Some(ip.filter(|r| *r < NUM_REGISTERS).ok_or_else(err)?);
+                    }
+                    continue;
+                }
+                Some(j) => (&line[..j], &line[j..]),
+                None => (line, ""),
+            };
+            if code.trim().is_empty() {
+                continue;
+            }
+            if comment.starts_with("#break") {
+                out.breakpoints.push(out.instructions.len());
+            }
+            out.instructions.push(code.parse().map_err(|_| err())?);
+        }
+        Ok(out)
+    }
+}
+
+impl fmt::Display for Program {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(ip) = self.ip {
+            writeln!(f, "#ip {}", ip)?;
+        }
+        for (i, instruction) in self.instructions.iter().enumerate() {
+            if self.breakpoints.contains(&i) {
+                writeln!(f, "{} #break", instruction)?;
+            } else {
+                writeln!(f, "{}", instruction)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let p: Program = "#ip 0\nseti 5 0 1\naddi 0 1 0 #break\n\nsetr 1 0 0 # comment"
+            .parse()
+            .unwrap();
+        assert_eq!(p.ip, Some(0));
+        assert_eq!(p.len(), 3);
+        assert_eq!(p.breakpoints, vec![1]);
+        assert_eq!(
+            p.instructions[1],
+            Instruction {
+                op: addi,
+                a: 0,
+                b: 1,
+                c: 0
+            }
+        );
+        assert_eq!(
+            p.to_string(),
+            "#ip 0\nseti 5 0 1\naddi 0 1 0 #break\nsetr 1 0 0\n"
+        );
+
+        let e = "#ip 0\nseti 5 0\n".parse::<Program>().unwrap_err();
+        assert_eq!(e.line, 2);
+        assert!("#ip x".parse::<Program>().is_err());
+        assert!("addq 1 2 3".parse::<Program>().is_err());
+
+        // Register arguments must be in range, but immediates can be anything
+        let e = "#ip 6
+seti 5 0 1"
+            .parse::<Program>()
+            .unwrap_err();
+        assert_eq!((e.line, e.text.as_str()), (1, "#ip 6"));
+        let e = "seti 5 0 1
+addr 6 0 1"
+            .parse::<Program>()
+            .unwrap_err();
+        assert_eq!((e.line, e.text.as_str()), (2, "addr 6 0 1"));
+        assert!("addi 0 1 6".parse::<Program>().is_err());
+        assert!("gtri 1 99 5".parse::<Program>().is_ok());
+        assert!("seti 99 99 5".parse::<Program>().is_ok());
+    }
+
+    #[test]
+    fn ops() {
+        // The example from day 16, which matches mulr, addi, and seti
+        let regs = [3, 2, 1, 1];
+        let matches = Op::ALL
+            .iter()
+            .filter(|op| op.eval(&regs, 2, 1) == 2)
+            .cloned()
+            .collect::<Vec<_>>();
+        assert_eq!(matches, vec![addi, mulr, seti]);
+
+        assert_eq!(gtir.eval(&regs, 5, 0), 1);
+        assert_eq!(eqrr.eval(&regs, 2, 3), 1);
+        assert_eq!(setr.try_eval(&regs, 7, 0), None);
+        assert_eq!(seti.try_eval(&regs, 7, 100), Some(7));
+        assert_eq!(addi.try_eval(&[usize::MAX], 0, 1), None);
+        assert_eq!(mulr.try_eval(&[usize::MAX, 2], 0, 1), None);
+        assert_eq!(muli.try_eval(&[usize::MAX], 0, 1), Some(usize::MAX));
+        for op in Op::ALL.iter() {
+            assert_eq!(op.name().parse::<Op>(), Ok(*op));
+        }
+    }
+}
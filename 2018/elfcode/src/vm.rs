@@ -0,0 +1,132 @@
+use crate::{Program, Registers};
+
+/// Reason why `Vm::run` returned
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Stop {
+    /// The instruction pointer left the program
+    Halted,
+    /// The VM is about to execute the instruction at this breakpoint
+    Breakpoint(usize),
+}
+
+/// An interpreter which binds the instruction pointer to a register (if the
+/// program has an `#ip` directive), and counts how many times each
+/// instruction has been executed.
+#[derive(Clone, Debug)]
+pub struct Vm {
+    program: Program,
+    pub regs: Registers,
+    pub ip: usize,
+    breakpoints: Vec<bool>,
+    counts: Vec<u64>,
+}
+
+impl Vm {
+    pub fn new(program: Program) -> Self {
+        let mut breakpoints = vec![false; program.len()];
+        for &b in &program.breakpoints {
+            breakpoints[b] = true;
+        }
+        let counts = vec![0; program.len()];
+        Vm {
+            program,
+            regs: [0; 6],
+            ip: 0,
+            breakpoints,
+            counts,
+        }
+    }
+
+    pub fn program(&self) -> &Program {
+        &self.program
+    }
+
+    pub fn running(&self) -> bool {
+        self.ip < self.program.len()
+    }
+
+    /// Stops execution when the VM reaches the given instruction
+    pub fn add_breakpoint(&mut self, i: usize) {
+        self.breakpoints[i] = true;
+    }
+
+    pub fn remove_breakpoint(&mut self, i: usize) {
+        self.breakpoints[i] = false;
+    }
+
+    /// Returns the number of times each instruction has been executed
+    pub fn counts(&self) -> &[u64] {
+        &self.counts
+    }
+
+    pub fn reset_counts(&mut self) {
+        self.counts.iter_mut().for_each(|c| *c = 0);
+    }
+
+    /// Executes a single instruction, returning `false` if the VM had
+    /// already halted
+    pub fn step(&mut self) -> bool {
+        if !self.running() {
+            return false;
+        }
+        if let Some(r) = self.program.ip {
+            self.regs[r] = self.ip;
+        }
+        self.program.instructions[self.ip].eval(&mut self.regs);
+        self.counts[self.ip] += 1;
+        self.ip = match self.program.ip {
+            Some(r) => self.regs[r] + 1,
+            None => self.ip + 1,
+        };
+        true
+    }
+
+    /// Runs until the program halts or reaches a breakpoint.  The current
+    /// instruction is always executed, so calling `run` again after a
+    /// breakpoint resumes execution.
+    pub fn run(&mut self) -> Stop {
+        while self.step() {
+            if self.running() && self.breakpoints[self.ip] {
+                return Stop::Breakpoint(self.ip);
+            }
+        }
+        Stop::Halted
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn example() {
+        // Example program from day 19
+        let p: Program = "#ip 0\nseti 5 0 1\nseti 6 0 2\naddi 0 1 0\naddr 1 2 3\n\
+                          setr 1 0 0\nseti 8 0 4\nseti 9 0 5"
+            .parse()
+            .unwrap();
+        let mut vm = Vm::new(p);
+        assert_eq!(vm.run(), Stop::Halted);
+        assert_eq!(vm.regs, [6, 5, 6, 0, 0, 9]);
+        assert_eq!(vm.ip, 7);
+        assert_eq!(vm.counts(), &[1, 1, 1, 0, 1, 0, 1]);
+    }
+
+    #[test]
+    fn breakpoints() {
+        let p: Program = "seti 1 0 0\naddi 0 1 0 #break\nmuli 0 10 0"
+            .parse()
+            .unwrap();
+        let mut vm = Vm::new(p);
+        assert_eq!(vm.run(), Stop::Breakpoint(1));
+        assert_eq!(vm.regs[0], 1);
+        vm.add_breakpoint(2);
+        assert_eq!(vm.run(), Stop::Breakpoint(2));
+        assert_eq!(vm.regs[0], 2);
+        assert_eq!(vm.run(), Stop::Halted);
+        assert_eq!(vm.regs[0], 20);
+        assert!(!vm.step());
+    }
+}
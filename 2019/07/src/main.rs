@@ -2,8 +2,27 @@ use itertools::Itertools;
 use std::io::Read;
 use std::str::FromStr;
 
+use vm::network::{Event, Link, Network, Stop};
 use vm::Vm;
 
+/// Builds a chain of amplifiers with the given phases, where the last
+/// amplifier's output goes to `last`.
+fn amplifiers(input: &str, ps: &[i64], last: Link) -> Network {
+    let vm = Vm::from_str(input).unwrap();
+    let mut net = Network::new();
+    for (i, p) in ps.iter().enumerate() {
+        let link = if i == ps.len() - 1 {
+            last
+        } else {
+            Link::Pipe(i + 1)
+        };
+        let n = net.add_node(vm.clone(), link);
+        net.input(n, *p);
+    }
+    net.input(0, 0);
+    net
+}
+
 fn main() {
     let mut input = String::new();
     std::io::stdin().read_to_string(&mut input).unwrap();
@@ -13,24 +32,17 @@ fn main() {
     let best = (0..5)
         .permutations(5)
         .map(|ps| {
-            // Build a fresh set of VMs and queues
-            let mut vms = vec![Vm::from_str(&input).unwrap(); 5];
-            for (i, vm) in vms.iter_mut().enumerate() {
-                vm.input(ps[i] as i64);
-            }
-            vms[0].input(0);
-
-            loop {
-                for i in 0..vms.len() {
-                    if let Some(out) = vms[i].step() {
-                        if let Some(vm) = vms.get_mut(i + 1) {
-                            vm.input(out);
-                        } else {
-                            return out;
-                        }
-                    }
+            let mut net = amplifiers(&input, &ps, Link::Output);
+            let mut output = None;
+            net.run(|e| match e {
+                Event::Output { node: 4, value } => {
+                    output = Some(*value);
+                    true
                 }
-            }
+                _ => false,
+            })
+            .unwrap();
+            output.unwrap()
         })
         .max()
         .unwrap();
@@ -40,24 +52,17 @@ fn main() {
     let best = (5..10)
         .permutations(5)
         .map(|ps| {
-            // Build a fresh set of VMs and queues
-            let mut vms = vec![Vm::from_str(&input).unwrap(); 5];
-            for (i, vm) in vms.iter_mut().enumerate() {
-                vm.input(ps[i] as i64);
-            }
-            vms[0].input(0);
-
+            let mut net = amplifiers(&input, &ps, Link::Pipe(0));
             let mut output = 0;
-            while vms.iter().any(Vm::running) {
-                for i in 0..vms.len() {
-                    if let Some(out) = vms[i].step() {
-                        if i == vms.len() - 1 {
-                            output = out;
-                        }
-                        vms[(i + 1) % 5].input(out);
+            let stop = net
+                .run(|e| {
+                    if let Event::Output { node: 4, value } = e {
+                        output = *value;
                     }
-                }
-            }
+                    false
+                })
+                .unwrap();
+            assert_eq!(stop, Stop::Halted);
             output
         })
         .max()
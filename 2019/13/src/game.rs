@@ -0,0 +1,247 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{Error, ErrorKind};
+
+use vm::Vm;
+
+use crate::policy::Policy;
+
+pub const EMPTY: i64 = 0;
+pub const WALL: i64 = 1;
+pub const BLOCK: i64 = 2;
+pub const PADDLE: i64 = 3;
+pub const BALL: i64 = 4;
+
+/// The cabinet writes its score to this (otherwise invalid) position
+const SCORE: (i64, i64) = (-1, 0);
+
+/// Current contents of the cabinet's screen
+#[derive(Clone, Debug, Default)]
+pub struct Screen {
+    pub tiles: HashMap<(i64, i64), i64>,
+    pub score: i64,
+    pub ball: (i64, i64),
+    pub paddle: (i64, i64),
+}
+
+impl Screen {
+    pub fn update(&mut self, (x, y, c): (i64, i64, i64)) {
+        if (x, y) == SCORE {
+            self.score = c;
+            return;
+        }
+        match c {
+            PADDLE => self.paddle = (x, y),
+            BALL => self.ball = (x, y),
+            _ => (),
+        }
+        self.tiles.insert((x, y), c);
+    }
+
+    pub fn blocks(&self) -> usize {
+        self.tiles.values().filter(|c| **c == BLOCK).count()
+    }
+}
+
+impl fmt::Display for Screen {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let xmax = self.tiles.keys().map(|p| p.0).max().unwrap_or(0);
+        let ymax = self.tiles.keys().map(|p| p.1).max().unwrap_or(0);
+
+        writeln!(f, "Score: {}", self.score)?;
+        for y in 0..=ymax {
+            for x in 0..=xmax {
+                let c = match *self.tiles.get(&(x, y)).unwrap_or(&EMPTY) {
+                    WALL => '█',
+                    BLOCK => '▒',
+                    PADDLE => '▔',
+                    BALL => '●',
+                    _ => ' ',
+                };
+                write!(f, "{}", c)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+/// Everything the cabinet drew before asking for input, and the joystick
+/// position that it was then given (`None` once the game has ended).
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Frame {
+    pub updates: Vec<(i64, i64, i64)>,
+    pub joystick: Option<i64>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Recording {
+    pub frames: Vec<Frame>,
+}
+
+impl Recording {
+    /// Returns the number of joystick inputs
+    pub fn steps(&self) -> usize {
+        self.frames.iter().filter(|f| f.joystick.is_some()).count()
+    }
+
+    /// Returns the number of joystick inputs which moved the paddle
+    pub fn moves(&self) -> usize {
+        self.frames
+            .iter()
+            .filter(|f| f.joystick.unwrap_or(0) != 0)
+            .count()
+    }
+
+    /// Returns the screen at the end of the recording
+    pub fn screen(&self) -> Screen {
+        let mut screen = Screen::default();
+        for f in &self.frames {
+            f.updates.iter().for_each(|u| screen.update(*u));
+        }
+        screen
+    }
+
+    /// Saves the recording as text, with one line per frame: the joystick
+    /// position (or `-` at the end), then each update as `x,y,tile`.
+    pub fn save(&self, path: &str) -> std::io::Result<()> {
+        let mut out = String::new();
+        for f in &self.frames {
+            match f.joystick {
+                Some(j) => out += &j.to_string(),
+                None => out.push('-'),
+            }
+            for (x, y, c) in &f.updates {
+                out += &format!(" {},{},{}", x, y, c);
+            }
+            out.push('\n');
+        }
+        std::fs::write(path, out)
+    }
+
+    pub fn load(path: &str) -> std::io::Result<Self> {
+        let text = std::fs::read_to_string(path)?;
+        let bad =
+            |line: &str| Error::new(ErrorKind::InvalidData, format!("Invalid frame '{}'", line));
+
+        let mut frames = vec![];
+        for line in text.lines() {
+            let mut words = line.split_whitespace();
+            let joystick = match words.next() {
+                Some("-") => None,
+                Some(j) => Some(j.parse().map_err(|_| bad(line))?),
+                None => continue,
+            };
+            let updates = words
+                .map(|w| {
+                    let v = w
+                        .split(',')
+                        .map(|i| i.parse::<i64>())
+                        .collect::<Result<Vec<_>, _>>()
+                        .map_err(|_| bad(line))?;
+                    match v.as_slice() {
+                        [x, y, c] => Ok((*x, *y, *c)),
+                        _ => Err(bad(line)),
+                    }
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            frames.push(Frame { updates, joystick });
+        }
+        Ok(Recording { frames })
+    }
+}
+
+/// Runs the cabinet until it halts or the policy gives up, recording each
+/// frame along the way.
+pub fn play(mut vm: Vm, policy: &mut dyn Policy) -> Recording {
+    let mut screen = Screen::default();
+    let mut rec = Recording::default();
+    loop {
+        let mut frame = Frame::default();
+        let mut out = Vec::with_capacity(3);
+        while vm.running() && !vm.needs_input() {
+            if let Some(v) = vm.step() {
+                out.push(v);
+                if out.len() == 3 {
+                    let u = (out[0], out[1], out[2]);
+                    screen.update(u);
+                    frame.updates.push(u);
+                    out.clear();
+                }
+            }
+        }
+        if vm.running() {
+            frame.joystick = policy.joystick(&screen);
+        }
+        let done = frame.joystick.is_none();
+        if let Some(j) = frame.joystick {
+            vm.input(j);
+        }
+        rec.frames.push(frame);
+        if done {
+            return rec;
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::policy::Follow;
+
+    /// Draws a paddle and ball, reads the joystick, then writes the
+    /// joystick position as the score and halts
+    fn cabinet() -> Vm {
+        Vm::new(&[
+            104, 1, 104, 2, 104, PADDLE, // paddle at (1, 2)
+            104, 3, 104, 1, 104, BALL, // ball at (3, 1)
+            3, 100, // read joystick
+            104, -1, 104, 0, 4, 100, // score = joystick
+            99,
+        ])
+    }
+
+    #[test]
+    fn play_and_round_trip() {
+        let rec = play(cabinet(), &mut Follow);
+        assert_eq!(
+            rec.frames,
+            vec![
+                Frame {
+                    updates: vec![(1, 2, PADDLE), (3, 1, BALL)],
+                    joystick: Some(1),
+                },
+                Frame {
+                    updates: vec![(-1, 0, 1)],
+                    joystick: None,
+                },
+            ]
+        );
+        assert_eq!((rec.steps(), rec.moves()), (1, 1));
+        let screen = rec.screen();
+        assert_eq!(
+            (screen.score, screen.paddle, screen.ball),
+            (1, (1, 2), (3, 1))
+        );
+
+        let path = std::env::temp_dir().join(format!("day13-{}.rec", std::process::id()));
+        let path = path.to_str().unwrap();
+        rec.save(path).unwrap();
+        let loaded = Recording::load(path);
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(loaded.unwrap(), rec);
+    }
+
+    #[test]
+    fn load_errors() {
+        let path = std::env::temp_dir().join(format!("day13-bad-{}.rec", std::process::id()));
+        let path = path.to_str().unwrap();
+        std::fs::write(path, "1 0,0,1\nx 1,2,3\n").unwrap();
+        let err = Recording::load(path).unwrap_err();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert_eq!(err.to_string(), "Invalid frame 'x 1,2,3'");
+    }
+}
@@ -1,94 +1,107 @@
-use std::cmp::Ordering;
-use std::collections::HashMap;
 use std::io::Read;
 use std::str::FromStr;
+use std::time::Duration;
 
 use vm::Vm;
 
-const DRAW_GAME: bool = false;
+mod game;
+mod policy;
+mod term;
 
-fn get_pixel_with(vm: &mut Vm, input: i64) -> Option<(i64, i64, i64)> {
-    if let Some(x) = vm.run_until_with(input) {
-        Some((
-            x,
-            vm.run_until_with(input).unwrap(),
-            vm.run_until_with(input).unwrap(),
-        ))
-    } else {
-        None
-    }
-}
+use crate::game::{play, Recording};
+use crate::policy::{Follow, Policy, Predict};
 
-fn draw(tiles: &HashMap<(i64, i64), i64>) {
-    let xmin = tiles.keys().map(|p| p.0).min().unwrap();
-    let xmax = tiles.keys().map(|p| p.0).max().unwrap();
-    let ymin = tiles.keys().map(|p| p.1).min().unwrap();
-    let ymax = tiles.keys().map(|p| p.1).max().unwrap();
+const USAGE: &str =
+    "usage: day13 [record FILE | replay FILE [FPS] | play [FILE] | compare] < input";
 
-    for y in ymin..=ymax {
-        for x in xmin..=xmax {
-            println!(
-                "{}",
-                match tiles.get(&(x, y)).unwrap_or(&0) {
-                    0 => ' ',
-                    1 => '█',
-                    2 => '▒',
-                    3 => '▔',
-                    4 => '●',
-                    _ => unreachable!(),
-                }
-            );
-        }
-        println!();
-    }
-}
+/// Time between frames when a human is playing
+const TICK: Duration = Duration::from_millis(150);
 
-fn main() {
+fn read_vm() -> Vm {
     let mut input = String::new();
     std::io::stdin().read_to_string(&mut input).unwrap();
-    let mut vm = Vm::from_str(&input).unwrap();
-
-    let mut tiles = HashMap::new();
-    while vm.running() {
-        let x = vm.run_until();
-        let y = vm.run_until();
-        let c = vm.run_until();
-        if vm.running() {
-            tiles.insert((x.unwrap(), y.unwrap()), c.unwrap());
-        } else {
-            break;
-        }
-    }
-    println!("Part 1: {}", tiles.values().filter(|v| **v == 2).count());
+    Vm::from_str(&input).unwrap()
+}
 
-    let mut vm = Vm::from_str(&input).unwrap();
+/// Returns a VM with quarters inserted, ready to play
+fn free_play(vm: &Vm) -> Vm {
+    let mut vm = vm.clone();
     vm.poke(0, 2);
+    vm
+}
 
-    // Build the initial game state
-    let mut ball_x = 0;
-    let mut paddle_x = 0;
-    let mut input = 0;
-    while tiles.values().any(|v| *v == 2) {
-        while let Some((x, y, c)) = get_pixel_with(&mut vm, input) {
-            let redraw = tiles.get(&(x, y)) != Some(&c);
-            tiles.insert((x, y), c);
+fn save(rec: &Recording, path: &str) {
+    match rec.save(path) {
+        Ok(()) => println!("Saved {} frames to {}", rec.frames.len(), path),
+        Err(e) => eprintln!("Could not save to {}: {}", path, e),
+    }
+}
 
-            match c {
-                3 => paddle_x = x,
-                4 => ball_x = x,
-                _ => (),
-            };
+fn main() {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    let args = args.iter().map(|s| s.as_str()).collect::<Vec<_>>();
+    match args.as_slice() {
+        [] => {
+            let vm = read_vm();
+            let screen = play(vm.clone(), &mut Follow).screen();
+            println!("Part 1: {}", screen.blocks());
 
-            input = match ball_x.cmp(&paddle_x) {
-                Ordering::Less => -1,
-                Ordering::Greater => 1,
-                Ordering::Equal => 0,
+            let screen = play(free_play(&vm), &mut Follow).screen();
+            println!("Part 2: {}", screen.score);
+        }
+        ["record", path] => {
+            let rec = play(free_play(&read_vm()), &mut Follow);
+            save(&rec, path);
+        }
+        ["replay", path, fps @ ..] => {
+            let fps = match fps {
+                [] => 30.0,
+                [f] => f.parse().expect("Invalid FPS"),
+                _ => panic!("{}", USAGE),
             };
-
-            if redraw && DRAW_GAME {
-                draw(&tiles);
+            // The delay between frames is 1 / FPS, which must be a valid
+            // (finite, non-negative) duration
+            if !(fps > 0.0 && Duration::try_from_secs_f64(1.0 / fps).is_ok()) {
+                eprintln!("FPS must be a positive number, not {}", fps);
+                std::process::exit(1);
+            }
+            let rec = Recording::load(path).unwrap_or_else(|e| panic!("{}: {}", path, e));
+            term::replay(&rec, fps).unwrap();
+        }
+        ["play", path @ ..] => {
+            let vm = free_play(&read_vm());
+            let rec = {
+                let mut keyboard = term::Keyboard::new(TICK).unwrap();
+                play(vm, &mut keyboard)
+            };
+            println!("Score: {}", rec.screen().score);
+            if let [path] = path {
+                save(&rec, path);
+            }
+        }
+        ["compare"] => {
+            let vm = read_vm();
+            let policies: Vec<(&str, Box<dyn Policy>)> = vec![
+                ("follow", Box::new(Follow)),
+                ("predict", Box::new(Predict::default())),
+            ];
+            println!(
+                "{:<8} {:>6} {:>6} {:>8}  result",
+                "policy", "steps", "moves", "score"
+            );
+            for (name, mut p) in policies {
+                let rec = play(free_play(&vm), p.as_mut());
+                let screen = rec.screen();
+                println!(
+                    "{:<8} {:>6} {:>6} {:>8}  {}",
+                    name,
+                    rec.steps(),
+                    rec.moves(),
+                    screen.score,
+                    if screen.blocks() == 0 { "won" } else { "lost" }
+                );
             }
         }
+        _ => eprintln!("{}", USAGE),
     }
-    println!("Part 2: {}", tiles.get(&(-1, 0)).unwrap());
 }
@@ -0,0 +1,97 @@
+use crate::game::{Screen, WALL};
+
+/// Something that can work the joystick
+pub trait Policy {
+    /// Returns the joystick position (-1, 0, or 1) for the current screen,
+    /// or `None` to stop playing
+    fn joystick(&mut self, screen: &Screen) -> Option<i64>;
+}
+
+fn towards(from: i64, to: i64) -> i64 {
+    (to - from).signum()
+}
+
+/// Keeps the paddle directly under the ball
+pub struct Follow;
+
+impl Policy for Follow {
+    fn joystick(&mut self, screen: &Screen) -> Option<i64> {
+        Some(towards(screen.paddle.0, screen.ball.0))
+    }
+}
+
+/// Works out where a falling ball will reach the paddle's row, bouncing off
+/// the side walls, and waits there.  Blocks aren't taken into account, so
+/// the prediction is refined every step.
+#[derive(Default)]
+pub struct Predict {
+    prev: Option<(i64, i64)>,
+}
+
+impl Policy for Predict {
+    fn joystick(&mut self, screen: &Screen) -> Option<i64> {
+        let (x, y) = screen.ball;
+        let (dx, dy) = match self.prev.replace(screen.ball) {
+            Some((px, py)) => (x - px, y - py),
+            None => (0, 0),
+        };
+        if dy <= 0 || dx == 0 {
+            return Some(towards(screen.paddle.0, x));
+        }
+
+        // Walls run along both sides of the screen
+        let xmin = 1;
+        let xmax = screen
+            .tiles
+            .iter()
+            .filter(|(_, c)| **c == WALL)
+            .map(|(p, _)| p.0)
+            .max()
+            .unwrap_or(1)
+            - 1;
+        let (mut x, mut dx) = (x, dx);
+        for _ in y..screen.paddle.1 - 1 {
+            if !(xmin..=xmax).contains(&(x + dx)) {
+                dx = -dx;
+            }
+            x += dx;
+        }
+        Some(towards(screen.paddle.0, x))
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::{BALL, PADDLE};
+
+    /// A screen with walls at `x = 0` and `x = 6`
+    fn screen(paddle: (i64, i64), ball: (i64, i64)) -> Screen {
+        let mut s = Screen::default();
+        for y in 0..=10 {
+            s.update((0, y, WALL));
+            s.update((6, y, WALL));
+        }
+        s.update((paddle.0, paddle.1, PADDLE));
+        s.update((ball.0, ball.1, BALL));
+        s
+    }
+
+    #[test]
+    fn follow() {
+        assert_eq!(Follow.joystick(&screen((3, 10), (5, 6))), Some(1));
+        assert_eq!(Follow.joystick(&screen((3, 10), (3, 6))), Some(0));
+        assert_eq!(Follow.joystick(&screen((3, 10), (1, 6))), Some(-1));
+    }
+
+    #[test]
+    fn predict() {
+        // Moving down and right, the ball bounces off the right wall and
+        // reaches the paddle's row at x = 2
+        let mut p = Predict::default();
+        assert_eq!(p.joystick(&screen((3, 10), (4, 5))), Some(1));
+        assert_eq!(p.joystick(&screen((3, 10), (5, 6))), Some(-1));
+    }
+}
@@ -0,0 +1,103 @@
+use std::io::{stdout, Write};
+use std::time::Duration;
+
+use crossterm::cursor::{Hide, MoveTo, Show};
+use crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use crossterm::terminal::{self, EnterAlternateScreen, LeaveAlternateScreen};
+use crossterm::{execute, queue};
+
+use crate::game::{Recording, Screen};
+use crate::policy::Policy;
+
+/// Takes over the terminal, restoring it when dropped
+pub struct Terminal {
+    raw: bool,
+}
+
+impl Terminal {
+    pub fn new(raw: bool) -> std::io::Result<Self> {
+        if raw {
+            terminal::enable_raw_mode()?;
+        }
+        execute!(stdout(), EnterAlternateScreen, Hide)?;
+        Ok(Terminal { raw })
+    }
+
+    pub fn draw(&mut self, screen: &Screen, status: &str) -> std::io::Result<()> {
+        let mut out = stdout();
+        queue!(out, MoveTo(0, 0))?;
+        // Raw mode doesn't translate newlines, so return explicitly
+        for line in screen.to_string().lines().chain(std::iter::once(status)) {
+            write!(out, "{}\r\n", line)?;
+        }
+        out.flush()
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        let _ = execute!(stdout(), Show, LeaveAlternateScreen);
+        if self.raw {
+            let _ = terminal::disable_raw_mode();
+        }
+    }
+}
+
+/// Plays back a recording at the given number of frames per second
+pub fn replay(rec: &Recording, fps: f64) -> std::io::Result<()> {
+    let mut term = Terminal::new(false)?;
+    let delay = Duration::from_secs_f64(1.0 / fps);
+    let mut screen = Screen::default();
+    for (i, f) in rec.frames.iter().enumerate() {
+        f.updates.iter().for_each(|u| screen.update(*u));
+        let status = format!("Frame {}/{}", i + 1, rec.frames.len());
+        term.draw(&screen, &status)?;
+        std::thread::sleep(delay);
+    }
+    Ok(())
+}
+
+/// Lets a human work the joystick with the arrow keys.
+///
+/// The game advances on a fixed tick; holding no key leaves the joystick
+/// centered, and `q` or escape stops playing.
+pub struct Keyboard {
+    term: Terminal,
+    tick: Duration,
+}
+
+impl Keyboard {
+    pub fn new(tick: Duration) -> std::io::Result<Self> {
+        Ok(Keyboard {
+            term: Terminal::new(true)?,
+            tick,
+        })
+    }
+}
+
+impl Policy for Keyboard {
+    fn joystick(&mut self, screen: &Screen) -> Option<i64> {
+        let status = "←/→ to move, q to quit";
+        self.term.draw(screen, status).ok()?;
+
+        let mut out = 0;
+        let start = std::time::Instant::now();
+        while let Some(t) = self.tick.checked_sub(start.elapsed()) {
+            if !event::poll(t).ok()? {
+                break;
+            }
+            if let Event::Key(k) = event::read().ok()? {
+                if k.kind == KeyEventKind::Release {
+                    continue;
+                }
+                match k.code {
+                    KeyCode::Left => out = -1,
+                    KeyCode::Right => out = 1,
+                    KeyCode::Char('q') | KeyCode::Esc => return None,
+                    _ => (),
+                }
+            }
+        }
+        Some(out)
+    }
+}
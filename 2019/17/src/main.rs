@@ -57,25 +57,13 @@ fn main() {
 
     let mut vm = Vm::from_str(&input).unwrap();
 
+    let view = vm.read_until_prompt();
+    print!("{}", view);
+
     let mut tiles = HashMap::new();
-    let mut x = 0;
-    let mut y = 0;
-    let mut i = 0;
-    while i < 10000 {
-        // Solve the halting problem
-        if let Some(o) = vm.step() {
-            i = 0;
-            let c = o as u8 as char;
-            if c == '\n' {
-                x = 0;
-                y += 1;
-            } else {
-                tiles.insert((x, y), c);
-                x += 1;
-            }
-            print!("{}", c);
-        } else {
-            i += 1;
+    for (y, line) in view.lines().enumerate() {
+        for (x, c) in line.chars().enumerate() {
+            tiles.insert((x as i32, y as i32), c);
         }
     }
 
@@ -142,25 +130,11 @@ fn main() {
 
     let cmds = optimize(commands, 3).unwrap();
     for cmd in cmds {
-        for (i, word) in cmd.iter().enumerate() {
-            for c in word.chars() {
-                vm.input(c as i64);
-            }
-            if i == cmd.len() - 1 {
-                vm.input('\n' as i64);
-            } else {
-                vm.input(',' as i64);
-            }
-        }
+        vm.send_line(&cmd.join(","));
     }
-    vm.input('n' as i64);
-    vm.input('\n' as i64);
+    vm.send_line("n");
 
-    while let Some(o) = vm.run_until() {
-        if o > 255 {
-            println!("Part 2: {}", o);
-        } else {
-            print!("{}", o as u8 as char);
-        }
-    }
+    let out = vm.read_ascii();
+    print!("{}", out.text);
+    println!("Part 2: {}", out.value().unwrap());
 }
@@ -0,0 +1,362 @@
+//! A native springscript interpreter, plus a hull simulator that walks a
+//! droid over a known pattern of holes without running the Intcode.
+use std::fmt;
+use std::str::FromStr;
+
+/// The springdroid only accepts this many instructions
+pub const MAX_INSTRUCTIONS: usize = 15;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Mode {
+    Walk,
+    Run,
+}
+
+impl Mode {
+    /// Returns the number of sensor registers available in this mode
+    pub fn range(self) -> usize {
+        match self {
+            Mode::Walk => 4,
+            Mode::Run => 9,
+        }
+    }
+}
+
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Mode::Walk => write!(f, "WALK"),
+            Mode::Run => write!(f, "RUN"),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Op {
+    And,
+    Or,
+    Not,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Reg {
+    /// Sensor register, where 0 is `A` (one tile ahead)
+    Sensor(usize),
+    T,
+    J,
+}
+
+impl fmt::Display for Reg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Reg::Sensor(i) => write!(f, "{}", (b'A' + *i as u8) as char),
+            Reg::T => write!(f, "T"),
+            Reg::J => write!(f, "J"),
+        }
+    }
+}
+
+/// A single instruction, which stores `op(x, y)` into `y`
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Instruction {
+    pub op: Op,
+    pub x: Reg,
+    pub y: Reg,
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let op = match self.op {
+            Op::And => "AND",
+            Op::Or => "OR",
+            Op::Not => "NOT",
+        };
+        write!(f, "{} {} {}", op, self.x, self.y)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ScriptError {
+    Parse { line: usize, text: String },
+    TooLong(usize),
+    Sensor { line: usize, mode: Mode },
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ScriptError::Parse { line, text } => {
+                write!(f, "Invalid instruction '{}' on line {}", text, line)
+            }
+            ScriptError::TooLong(n) => write!(
+                f,
+                "Script has {} instructions (max {})",
+                n, MAX_INSTRUCTIONS
+            ),
+            ScriptError::Sensor { line, mode } => {
+                write!(
+                    f,
+                    "Sensor on line {} is out of range in {} mode",
+                    line, mode
+                )
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Script(pub Vec<Instruction>);
+
+impl Script {
+    /// Runs the script with the given sensor readings (bit 0 is `A`),
+    /// returning whether the droid should jump
+    pub fn jumps(&self, sensors: u16) -> bool {
+        let (mut t, mut j) = (false, false);
+        for i in &self.0 {
+            let x = match i.x {
+                Reg::Sensor(s) => sensors & (1 << s) != 0,
+                Reg::T => t,
+                Reg::J => j,
+            };
+            let y = match i.y {
+                Reg::T => &mut t,
+                Reg::J => &mut j,
+                Reg::Sensor(_) => unreachable!(),
+            };
+            *y = match i.op {
+                Op::And => x && *y,
+                Op::Or => x || *y,
+                Op::Not => !x,
+            };
+        }
+        j
+    }
+
+    /// Checks that the droid would accept this script in the given mode
+    pub fn check(&self, mode: Mode) -> Result<(), ScriptError> {
+        if self.0.len() > MAX_INSTRUCTIONS {
+            return Err(ScriptError::TooLong(self.0.len()));
+        }
+        for (line, i) in self.0.iter().enumerate() {
+            if let Reg::Sensor(s) = i.x {
+                if s >= mode.range() {
+                    return Err(ScriptError::Sensor {
+                        line: line + 1,
+                        mode,
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Script {
+    type Err = ScriptError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut out = vec![];
+        for (line, text) in s.lines().enumerate() {
+            if text.trim().is_empty() {
+                continue;
+            }
+            let err = || ScriptError::Parse {
+                line: line + 1,
+                text: text.to_owned(),
+            };
+            let reg = |r: &str| match r {
+                "T" => Some(Reg::T),
+                "J" => Some(Reg::J),
+                r if r.len() == 1 && ("A"..="I").contains(&r) => {
+                    Some(Reg::Sensor((r.as_bytes()[0] - b'A') as usize))
+                }
+                _ => None,
+            };
+            let words = text.split_whitespace().collect::<Vec<_>>();
+            let (op, x, y) = match words.as_slice() {
+                [op, x, y] => (*op, reg(x).ok_or_else(err)?, reg(y).ok_or_else(err)?),
+                _ => return Err(err()),
+            };
+            let op = match op {
+                "AND" => Op::And,
+                "OR" => Op::Or,
+                "NOT" => Op::Not,
+                _ => return Err(err()),
+            };
+            if let Reg::Sensor(_) = y {
+                return Err(err());
+            }
+            out.push(Instruction { op, x, y });
+        }
+        Ok(Script(out))
+    }
+}
+
+impl fmt::Display for Script {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for i in &self.0 {
+            writeln!(f, "{}", i)?;
+        }
+        Ok(())
+    }
+}
+
+/// A row of hull, as reported by the droid when it falls.  Tiles past the
+/// end of the row are assumed to be solid.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Hull(pub Vec<bool>);
+
+impl Hull {
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn ground(&self, x: usize) -> bool {
+        self.0.get(x).copied().unwrap_or(true)
+    }
+
+    /// Returns the sensor readings for a droid standing at `x`
+    pub fn scan(&self, x: usize, range: usize) -> u16 {
+        (0..range)
+            .filter(|i| self.ground(x + 1 + i))
+            .fold(0, |acc, i| acc | (1 << i))
+    }
+
+    /// Walks a droid along the hull, jumping whenever `jump` returns true
+    /// for the current sensor readings.  Returns the position of the hole
+    /// that it falls into, if any.
+    pub fn walk<F>(&self, range: usize, mut jump: F) -> Result<(), usize>
+    where
+        F: FnMut(u16) -> bool,
+    {
+        let mut x = 0;
+        while x < self.len() {
+            if !self.ground(x) {
+                return Err(x);
+            }
+            x += if jump(self.scan(x, range)) { 4 } else { 1 };
+        }
+        Ok(())
+    }
+
+    pub fn survives(&self, script: &Script, mode: Mode) -> bool {
+        self.walk(mode.range(), |s| script.jumps(s)).is_ok()
+    }
+}
+
+impl FromStr for Hull {
+    type Err = char;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.chars()
+            .map(|c| match c {
+                '#' => Ok(true),
+                '.' | '@' => Ok(false),
+                c => Err(c),
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map(Hull)
+    }
+}
+
+impl fmt::Display for Hull {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for g in &self.0 {
+            write!(f, "{}", if *g { '#' } else { '.' })?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Jumps if there's a hole in A-C and ground at D
+    const WALK: &str = "NOT A J\nNOT B T\nOR T J\nNOT C T\nOR T J\nAND D J\n";
+
+    #[test]
+    fn parse() {
+        let s: Script = WALK.parse().unwrap();
+        assert_eq!(s.0.len(), 6);
+        assert_eq!(
+            s.0[1],
+            Instruction {
+                op: Op::Not,
+                x: Reg::Sensor(1),
+                y: Reg::T
+            }
+        );
+        assert_eq!(s.to_string(), WALK);
+
+        for (text, line) in &[
+            ("AND A", 1),
+            ("NOT A J\n\nXOR A J", 3),
+            ("AND A B", 1),
+            ("NOT J Z", 1),
+            ("OR J J K", 1),
+            ("NOT AB J", 1),
+        ] {
+            let bad = text.lines().last().unwrap().to_owned();
+            assert_eq!(
+                text.parse::<Script>(),
+                Err(ScriptError::Parse {
+                    line: *line,
+                    text: bad
+                })
+            );
+        }
+    }
+
+    #[test]
+    fn check() {
+        let s: Script = "NOT E J".parse().unwrap();
+        assert_eq!(s.check(Mode::Run), Ok(()));
+        let e = s.check(Mode::Walk).unwrap_err();
+        assert_eq!(
+            e,
+            ScriptError::Sensor {
+                line: 1,
+                mode: Mode::Walk
+            }
+        );
+        assert_eq!(
+            e.to_string(),
+            "Sensor on line 1 is out of range in WALK mode"
+        );
+
+        let s: Script = "NOT A J\n".repeat(MAX_INSTRUCTIONS + 1).parse().unwrap();
+        assert_eq!(s.check(Mode::Walk), Err(ScriptError::TooLong(16)));
+    }
+
+    #[test]
+    fn jumps() {
+        let s: Script = WALK.parse().unwrap();
+        assert!(!s.jumps(0b1111));
+        assert!(s.jumps(0b1110));
+        assert!(s.jumps(0b1011));
+        assert!(!s.jumps(0b0110));
+
+        // T and J both start out false
+        assert!(!Script::default().jumps(0));
+        let s: Script = "NOT T J".parse().unwrap();
+        assert!(s.jumps(0));
+        let s: Script = "OR J J\nAND T J".parse().unwrap();
+        assert!(!s.jumps(0b1111));
+    }
+
+    #[test]
+    fn walk() {
+        let hull: Hull = "#####.#..########".parse().unwrap();
+        assert_eq!(hull.to_string(), "#####.#..########");
+        assert_eq!(hull.scan(4, 4), 0b0010);
+
+        // Never jumping falls into the first hole, and always jumping lands
+        // in the second one
+        assert_eq!(hull.walk(4, |_| false), Err(5));
+        assert_eq!(hull.walk(4, |_| true), Err(8));
+
+        let s: Script = WALK.parse().unwrap();
+        assert!(hull.survives(&s, Mode::Walk));
+        assert!(!hull.survives(&Script::default(), Mode::Walk));
+        assert_eq!("##x".parse::<Hull>(), Err('x'));
+    }
+}
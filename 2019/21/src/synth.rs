@@ -0,0 +1,468 @@
+//! Pure-Rust springscript synthesis.
+//!
+//! This works in two steps.  First, we pick whether to jump at each scan
+//! that the droid sees while crossing the known hulls.  Then, we build the
+//! jump condition as an AND of clauses, each of which is an OR of (possibly
+//! negated) sensors.  Every clause must allow all of the
+//! jumps, and between them they must rule out every place where we walk;
+//! that's a small set cover problem, which we solve exactly.
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+
+use crate::springscript::{Hull, Instruction, Mode, Op, Reg, Script, MAX_INSTRUCTIONS};
+
+/// Something that proposes springscript, learning from the hulls where
+/// previous scripts fell
+pub trait Synthesizer {
+    fn add_hull(&mut self, hull: &Hull);
+    fn plan(&mut self) -> Script;
+}
+
+/// A set of scans, with one bit per scan.  There are at most 2^9 distinct
+/// scans, so this always has room.
+type Table = [u64; 8];
+
+fn set(t: &mut Table, i: usize) {
+    t[i / 64] |= 1 << (i % 64);
+}
+
+fn is_empty(t: &Table) -> bool {
+    t.iter().all(|w| *w == 0)
+}
+
+/// Whether to jump for each scan
+type Choices = BTreeMap<u16, bool>;
+
+/// An OR of sensor readings, each of which may be negated
+#[derive(Copy, Clone, Debug)]
+struct Clause {
+    pos: u16,
+    neg: u16,
+}
+
+impl Clause {
+    /// Appends instructions which AND this clause into J, using T as
+    /// scratch space.  The first clause is stored directly into J instead,
+    /// since it starts out false.
+    fn emit(&self, first: bool, out: &mut Vec<Instruction>) {
+        let sensors = |mask: u16| {
+            (0..16)
+                .filter(move |i| mask & (1 << i) != 0)
+                .map(Reg::Sensor)
+                .collect::<Vec<_>>()
+        };
+        let (pos, neg) = (sensors(self.pos), sensors(self.neg));
+        let r = if first { Reg::J } else { Reg::T };
+        let mut emit = |op, x, y| out.push(Instruction { op, x, y });
+
+        // Loads a sensor into r, then ORs or ANDs in the rest of them
+        let mut chain = |regs: &[Reg], op| {
+            if first {
+                emit(Op::Or, regs[0], r);
+            } else {
+                emit(Op::Not, regs[0], r);
+                emit(Op::Not, r, r);
+            }
+            for x in &regs[1..] {
+                emit(op, *x, r);
+            }
+        };
+        match (pos.as_slice(), neg.as_slice()) {
+            ([x], []) => {
+                let op = if first { Op::Or } else { Op::And };
+                return emit(op, *x, Reg::J);
+            }
+            ([], [x]) => emit(Op::Not, *x, r),
+            ([x], neg) => {
+                emit(Op::Not, *x, r);
+                for y in neg {
+                    emit(Op::And, *y, r);
+                }
+                emit(Op::Not, r, r);
+            }
+            (pos, []) => chain(pos, Op::Or),
+            ([], neg) => {
+                chain(neg, Op::And);
+                emit(Op::Not, r, r);
+            }
+            (pos, neg) => {
+                // Invert, so that we can AND in the negated readings
+                chain(pos, Op::Or);
+                emit(Op::Not, r, r);
+                for y in neg {
+                    emit(Op::And, *y, r);
+                }
+                emit(Op::Not, r, r);
+            }
+        }
+        if !first {
+            emit(Op::And, Reg::T, Reg::J);
+        }
+    }
+
+    /// Returns the number of instructions that `emit` would produce
+    fn cost(&self, first: bool) -> usize {
+        let mut out = vec![];
+        self.emit(first, &mut out);
+        out.len()
+    }
+}
+
+pub struct Enumerate {
+    mode: Mode,
+    hulls: Vec<Hull>,
+
+    /// Every possible clause in this mode, with its cost, indexed by a
+    /// base-3 code where each digit is a sensor (absent, set, or negated)
+    clauses: Vec<(Clause, usize)>,
+
+    /// Results from `search`, which is called with the same choices many
+    /// times over as hulls are added
+    cache: RefCell<HashMap<Choices, Option<Script>>>,
+}
+
+impl Enumerate {
+    pub fn new(mode: Mode) -> Self {
+        let range = mode.range();
+        let clauses = (0..3usize.pow(range as u32))
+            .map(|code| {
+                let mut c = Clause { pos: 0, neg: 0 };
+                let mut digits = code;
+                for i in 0..range {
+                    match digits % 3 {
+                        1 => c.pos |= 1 << i,
+                        2 => c.neg |= 1 << i,
+                        _ => (),
+                    }
+                    digits /= 3;
+                }
+                // Code 0 is the empty clause, which we never use
+                let cost = if code == 0 { 0 } else { c.cost(false) };
+                (c, cost)
+            })
+            .collect();
+        Enumerate {
+            mode,
+            hulls: vec![],
+            clauses,
+            cache: RefCell::default(),
+        }
+    }
+
+    /// Picks whether to jump at each scan that the droid sees, backtracking
+    /// through the hulls until it gets across all of them.  Each complete
+    /// set of choices is passed to `f`, stopping when it returns a value.
+    fn decide<F, T>(&self, h: usize, x: usize, choices: &mut Choices, f: &mut F) -> Option<T>
+    where
+        F: FnMut(&Choices) -> Option<T>,
+    {
+        let hull = match self.hulls.get(h) {
+            Some(hull) => hull,
+            None => return f(choices),
+        };
+        if x >= hull.len() {
+            // Adding more choices can only make the script longer, so give
+            // up early if this hull has already made it too long
+            if h + 1 < self.hulls.len() && self.search(choices).is_none() {
+                return None;
+            }
+            return self.decide(h + 1, 0, choices, f);
+        } else if !hull.ground(x) {
+            return None;
+        }
+        let scan = hull.scan(x, self.mode.range());
+        let step = |jump| if jump { 4 } else { 1 };
+        if let Some(jump) = choices.get(&scan) {
+            return self.decide(h, x + step(*jump), choices, f);
+        }
+        // Prefer jumping, which tends to give simpler conditions
+        for jump in [true, false] {
+            choices.insert(scan, jump);
+            if let Some(out) = self.decide(h, x + step(jump), choices, f) {
+                return Some(out);
+            }
+        }
+        choices.remove(&scan);
+        None
+    }
+
+    /// Finds a script that makes the given jump choices, if there's one
+    /// that fits in the droid's memory
+    fn search(&self, choices: &Choices) -> Option<Script> {
+        if let Some(s) = self.cache.borrow().get(choices) {
+            return s.clone();
+        }
+        let out = self.synthesize(choices);
+        self.cache.borrow_mut().insert(choices.clone(), out.clone());
+        out
+    }
+
+    /// Builds the script as an AND of clauses which are true wherever we
+    /// jump and which, between them, rule out every scan where we don't.
+    fn synthesize(&self, choices: &Choices) -> Option<Script> {
+        // Tables of which scans have each sensor set, where we jump, and
+        // where we walk
+        let mut sensors = vec![Table::default(); self.mode.range()];
+        let mut jumps = Table::default();
+        let mut walks = Table::default();
+        for (i, (scan, jump)) in choices.iter().enumerate() {
+            for (r, t) in sensors.iter_mut().enumerate() {
+                if scan & (1 << r) != 0 {
+                    set(t, i);
+                }
+            }
+            set(if *jump { &mut jumps } else { &mut walks }, i);
+        }
+
+        // Find every clause that allows all of the jumps, keeping the
+        // cheapest clause for each set of walks that it rules out
+        let words = choices.len().div_ceil(64);
+        let mut best: HashMap<Table, (usize, Clause)> = HashMap::new();
+        let mut values = vec![0; self.clauses.len() * words];
+        for (code, (c, cost)) in self.clauses.iter().enumerate().skip(1) {
+            // Each clause is its parent (minus the highest sensor) plus one
+            // more sensor, so we can build its truth table incrementally
+            let r = 15 - (c.pos | c.neg).leading_zeros() as usize;
+            let pos = c.pos & (1 << r) != 0;
+            let parent = code - 3usize.pow(r as u32) * if pos { 1 } else { 2 };
+            let (done, rest) = values.split_at_mut(code * words);
+            let v = &mut rest[..words];
+            for k in 0..words {
+                let t = sensors[r][k];
+                v[k] = done[parent * words + k] | if pos { t } else { !t };
+            }
+            let mut rules_out = Table::default();
+            let mut ok = true;
+            for k in 0..words {
+                ok &= jumps[k] & !v[k] == 0;
+                rules_out[k] = walks[k] & !v[k];
+            }
+            if !ok || is_empty(&rules_out) {
+                continue;
+            }
+            let e = best.entry(rules_out).or_insert((*cost, *c));
+            if *cost < e.0 {
+                *e = (*cost, *c);
+            }
+        }
+        // Writing a clause straight into J (rather than via T) can save
+        // instructions, which `cover` needs to know to stay within budget
+        let mut clauses = best
+            .into_iter()
+            .map(|(t, (cost, c))| (t, (cost, cost - c.cost(true), c)))
+            .collect::<Vec<_>>();
+        clauses.sort_by_key(|(t, (cost, ..))| (*cost, std::cmp::Reverse(*t)));
+
+        let mut chosen = vec![];
+        let mut found = None;
+        cover(
+            &clauses,
+            walks,
+            0,
+            0,
+            MAX_INSTRUCTIONS + 1,
+            &mut chosen,
+            &mut found,
+        );
+        let mut found = found?;
+
+        // Put the clause that benefits most from going straight into J
+        // first
+        found.sort_by_key(|c| c.cost(true) as isize - c.cost(false) as isize);
+        let mut out = vec![];
+        for (i, c) in found.iter().enumerate() {
+            c.emit(i == 0, &mut out);
+        }
+        if found.is_empty() && !is_empty(&jumps) {
+            // J needs to be always true
+            out.push(Instruction {
+                op: Op::Not,
+                x: Reg::T,
+                y: Reg::J,
+            });
+        }
+        if out.len() > MAX_INSTRUCTIONS {
+            return None;
+        }
+        Some(Script(out))
+    }
+}
+
+/// Finds the cheapest set of clauses that rules out every scan in `todo`,
+/// with a total cost below `limit`.
+///
+/// Each clause has a cost and the saving from writing it straight into J.
+/// Only one clause can go into J, so a set costs the sum of its clauses'
+/// costs minus their largest saving; `cost` and `saving` are the sum and
+/// largest saving of the clauses chosen so far.
+fn cover(
+    clauses: &[(Table, (usize, usize, Clause))],
+    todo: Table,
+    cost: usize,
+    saving: usize,
+    limit: usize,
+    chosen: &mut Vec<Clause>,
+    found: &mut Option<Vec<Clause>>,
+) -> usize {
+    let i = match todo.iter().position(|w| *w != 0) {
+        Some(w) => w * 64 + todo[w].trailing_zeros() as usize,
+        None => {
+            *found = Some(chosen.clone());
+            return cost - saving;
+        }
+    };
+    // Clauses are sorted by cost, so we can stop once even the largest
+    // saving wouldn't bring the next one within the limit
+    let most = clauses
+        .iter()
+        .map(|(_, (_, s, _))| *s)
+        .fold(saving, usize::max);
+    let mut limit = limit;
+    for (t, (c, s, clause)) in clauses {
+        let saving = saving.max(*s);
+        if (cost + c).saturating_sub(most) >= limit {
+            break;
+        } else if cost + c - saving >= limit || t[i / 64] & (1 << (i % 64)) == 0 {
+            continue;
+        }
+        let mut next = todo;
+        for (n, w) in next.iter_mut().zip(t) {
+            *n &= !w;
+        }
+        chosen.push(*clause);
+        let out = cover(clauses, next, cost + c, saving, limit, chosen, found);
+        limit = limit.min(out);
+        chosen.pop();
+    }
+    limit
+}
+
+impl Synthesizer for Enumerate {
+    fn add_hull(&mut self, hull: &Hull) {
+        self.hulls.push(hull.clone());
+    }
+
+    fn plan(&mut self) -> Script {
+        self.decide(0, 0, &mut Choices::new(), &mut |c| self.search(c))
+            .expect("Failed to find plan")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs the same loop as `solve`, but on known hulls instead of the
+    /// Intcode program: any hull where the plan fails is passed back to the
+    /// synthesizer, until the plan crosses all of them.
+    fn converge(mode: Mode, hulls: &[&str]) -> Script {
+        let hulls = hulls
+            .iter()
+            .map(|h| h.parse::<Hull>().unwrap())
+            .collect::<Vec<_>>();
+        let mut synth = Enumerate::new(mode);
+        for _ in 0..=hulls.len() {
+            let script = synth.plan();
+            assert_eq!(script.check(mode), Ok(()));
+            match hulls.iter().find(|h| !h.survives(&script, mode)) {
+                Some(h) => synth.add_hull(h),
+                None => return script,
+            }
+        }
+        panic!("Synthesizer added every hull without converging");
+    }
+
+    #[test]
+    fn walk() {
+        let hulls = [
+            "#####.###########",
+            "#####..#.########",
+            "#####...#########",
+            "#####.#..########",
+        ];
+        let script = converge(Mode::Walk, &hulls);
+        for h in &hulls {
+            assert!(h.parse::<Hull>().unwrap().survives(&script, Mode::Walk));
+        }
+    }
+
+    #[test]
+    fn run() {
+        let hulls = [
+            "#####.###########",
+            "#####...#########",
+            "#####.#.##..#.###",
+            "#####.##.##.#####",
+            "#####.#.##.#.####",
+            "#####..###.#..###",
+            "#####.#.#...#####",
+        ];
+        // Every hull can be crossed by the usual hand-written solution
+        let known: Script = "NOT A J\nNOT B T\nOR T J\nNOT C T\nOR T J\n\
+                             AND D J\nNOT E T\nNOT T T\nOR H T\nAND T J"
+            .parse()
+            .unwrap();
+        for h in &hulls {
+            assert!(
+                h.parse::<Hull>().unwrap().survives(&known, Mode::Run),
+                "{}",
+                h
+            );
+        }
+
+        let script = converge(Mode::Run, &hulls);
+        for h in &hulls {
+            assert!(h.parse::<Hull>().unwrap().survives(&script, Mode::Run));
+        }
+    }
+
+    #[test]
+    fn cover() {
+        // Two cheap clauses cost 16 together, but a more expensive clause
+        // fits within 15 once it goes straight into J
+        let table = |bits: &[usize]| {
+            let mut t = Table::default();
+            bits.iter().for_each(|i| set(&mut t, *i));
+            t
+        };
+        let clause = |pos| Clause { pos, neg: 0 };
+        let clauses = [
+            (table(&[0]), (8, 0, clause(1))),
+            (table(&[1]), (8, 0, clause(2))),
+            (table(&[0, 1]), (16, 1, clause(4))),
+        ];
+        let mut found = None;
+        let cost = super::cover(
+            &clauses,
+            table(&[0, 1]),
+            0,
+            0,
+            MAX_INSTRUCTIONS + 1,
+            &mut vec![],
+            &mut found,
+        );
+        assert_eq!(cost, 15);
+        let found = found.unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].pos, 4);
+    }
+
+    #[test]
+    fn emit() {
+        // (A | !B) goes straight into J, then (C | D) is ANDed in using T
+        let mut out = vec![];
+        Clause { pos: 1, neg: 2 }.emit(true, &mut out);
+        Clause {
+            pos: 0b1100,
+            neg: 0,
+        }
+        .emit(false, &mut out);
+        let s = Script(out);
+        for scan in 0..16u16 {
+            let a = scan & 1 != 0;
+            let b = scan & 2 != 0;
+            let cd = scan & 0b1100 != 0;
+            assert_eq!(s.jumps(scan), (a || !b) && cd, "{:04b}", scan);
+        }
+    }
+}
@@ -1,22 +1,188 @@
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::str::FromStr;
+use vm::ascii::{interact, Action, AsciiOutput};
 use vm::Vm;
 
-fn fuzz(vm: &mut Vm, dir: &str) {
-    let mut output = String::new();
-    for c in "inv\n".chars() {
-        vm.input(c as u8 as i64);
+/// Maximum number of instructions to run for a single command, after which
+/// we assume that the game is stuck in a loop
+const STEP_LIMIT: usize = 5_000_000;
+
+/// The room where the weight check happens
+const FLOOR: &str = "Pressure-Sensitive Floor";
+
+/// Runs until the next prompt, returning `None` if the game halts or doesn't
+/// reach a prompt within the step limit
+fn run(vm: &mut Vm) -> Option<String> {
+    let mut out = AsciiOutput::default();
+    for _ in 0..STEP_LIMIT {
+        if !vm.running() {
+            return None;
+        } else if vm.needs_input() {
+            return Some(out.text);
+        } else if let Some(v) = vm.step() {
+            out.push(v);
+        }
     }
-    while !vm.needs_input() {
-        if let Some(i) = vm.step() {
-            output += &format!("{}", i as u8 as char);
+    None
+}
+
+fn command(vm: &mut Vm, cmd: &str) -> Option<String> {
+    vm.send_line(cmd);
+    run(vm)
+}
+
+#[derive(Clone, Debug)]
+struct Room {
+    name: String,
+    doors: Vec<String>,
+    items: Vec<String>,
+}
+
+/// Parses the last room description in a block of output
+fn parse_room(text: &str) -> Option<Room> {
+    let start = text.rfind("== ")?;
+    let mut lines = text[start..].lines();
+    let name = lines.next()?.trim_matches(|c| c == '=' || c == ' ');
+
+    let mut room = Room {
+        name: name.to_owned(),
+        doors: vec![],
+        items: vec![],
+    };
+    let mut list = None;
+    for line in lines {
+        if line.starts_with("Doors here lead") {
+            list = Some(&mut room.doors);
+        } else if line.starts_with("Items here") {
+            list = Some(&mut room.items);
+        } else if let Some(item) = line.strip_prefix("- ") {
+            if let Some(list) = list.as_mut() {
+                list.push(item.to_owned());
+            }
+        } else {
+            list = None;
         }
     }
-    let inv: Vec<String> = output
+    Some(room)
+}
+
+fn opposite(dir: &str) -> &'static str {
+    match dir {
+        "north" => "south",
+        "south" => "north",
+        "east" => "west",
+        "west" => "east",
+        d => panic!("Invalid direction {}", d),
+    }
+}
+
+/// Checks whether an item can be safely picked up, by trying it on a clone
+/// of the VM: a trap item ends the game, hangs it, or stops us moving.
+fn is_safe(vm: &Vm, room: &Room, item: &str) -> bool {
+    let mut vm = vm.clone();
+    if command(&mut vm, &format!("take {}", item)).is_none() {
+        return false;
+    }
+    let dir = &room.doors[0];
+    match command(&mut vm, dir) {
+        Some(out) => out.contains("== "),
+        None => false,
+    }
+}
+
+/// A map of the ship, built by exploring on cloned VMs
+#[derive(Default)]
+struct Map {
+    /// Doors out of each room, and where they lead
+    doors: HashMap<String, HashMap<String, String>>,
+    /// Items that are safe to take, in each room
+    items: HashMap<String, Vec<String>>,
+    /// The room and direction that lead to the pressure plate
+    checkpoint: Option<(String, String)>,
+}
+
+impl Map {
+    fn explore(&mut self, vm: &Vm, room: &Room) {
+        let safe = room
+            .items
+            .iter()
+            .filter(|i| is_safe(vm, room, i))
+            .cloned()
+            .collect();
+        self.items.insert(room.name.clone(), safe);
+        self.doors.insert(room.name.clone(), HashMap::new());
+
+        for dir in &room.doors {
+            let mut next = vm.clone();
+            let Some(out) = command(&mut next, dir) else {
+                continue;
+            };
+            let Some(to) = parse_room(&out) else {
+                continue;
+            };
+            // Walking onto the plate with the wrong weight bounces us back
+            if out.contains(FLOOR) && to.name != FLOOR {
+                self.checkpoint = Some((room.name.clone(), dir.clone()));
+                continue;
+            }
+            self.doors
+                .get_mut(&room.name)
+                .unwrap()
+                .insert(dir.clone(), to.name.clone());
+            if !self.doors.contains_key(&to.name) {
+                self.explore(&next, &to);
+            }
+            // Record the way back, in case it wasn't seen from that side
+            self.doors
+                .get_mut(&to.name)
+                .unwrap()
+                .entry(opposite(dir).to_owned())
+                .or_insert_with(|| room.name.clone());
+        }
+    }
+
+    /// Finds the shortest list of moves from one room to another
+    fn path(&self, from: &str, to: &str) -> Vec<String> {
+        let mut prev: HashMap<&str, (&str, &str)> = HashMap::new();
+        let mut seen = HashSet::new();
+        let mut todo = VecDeque::new();
+        todo.push_back(from);
+        seen.insert(from);
+        while let Some(r) = todo.pop_front() {
+            if r == to {
+                break;
+            }
+            for (dir, next) in &self.doors[r] {
+                if seen.insert(next.as_str()) {
+                    prev.insert(next, (r, dir));
+                    todo.push_back(next);
+                }
+            }
+        }
+        let mut out = vec![];
+        let mut r = to;
+        while r != from {
+            let (p, dir) = prev[r];
+            out.push(dir.to_owned());
+            r = p;
+        }
+        out.reverse();
+        out
+    }
+}
+
+/// Tries every subset of the inventory when moving in the given direction,
+/// returning the output once the weight check passes (or the last attempt)
+fn fuzz(vm: &mut Vm, dir: &str, verbose: bool) -> String {
+    vm.send_line("inv");
+    let inv: Vec<String> = vm
+        .read_until_prompt()
         .lines()
         .filter(|i| i.starts_with("- "))
         .map(|i| i.replace("- ", ""))
         .collect();
 
+    let mut output = String::new();
     for i in 0..(1 << inv.len()) {
         let mut dropped = Vec::new();
         for (j, item) in inv.iter().enumerate() {
@@ -24,54 +190,179 @@ fn fuzz(vm: &mut Vm, dir: &str) {
                 dropped.push(item);
             }
         }
-        println!("Testing {:?}", dropped);
-        for d in dropped.iter() {
-            for c in format!("drop {}\n", d).chars() {
-                vm.input(c as u8 as i64);
-            }
+        if verbose {
+            println!("Testing {:?}", dropped);
         }
-        for c in dir.chars() {
-            vm.input(c as u8 as i64);
-        }
-        output.clear();
-        while !vm.needs_input() && vm.running() {
-            if let Some(i) = vm.step() {
-                output += &format!("{}", i as u8 as char);
-            }
+        for d in dropped.iter() {
+            vm.send_line(&format!("drop {}", d));
         }
+        vm.send_line(dir);
+        output = vm.read_until_prompt();
         if !output.contains("Droids on this ship are") {
             break;
         }
         for d in dropped.iter() {
-            for c in format!("take {}\n", d).chars() {
-                vm.input(c as u8 as i64);
+            vm.send_line(&format!("take {}", d));
+        }
+    }
+    output
+}
+
+/// Explores the ship, collects every safe item, and gets past the weight
+/// check, returning the password
+fn solve(mut vm: Vm) -> String {
+    let start = run(&mut vm).expect("Game did not start");
+    let start = parse_room(&start).expect("No starting room");
+
+    let mut map = Map::default();
+    map.explore(&vm, &start);
+    let (checkpoint, dir) = map.checkpoint.clone().expect("No checkpoint found");
+
+    let mut here = start.name.clone();
+    let mut rooms = map.items.keys().cloned().collect::<Vec<_>>();
+    rooms.sort();
+    for room in rooms {
+        for item in &map.items[&room] {
+            for step in map.path(&here, &room) {
+                command(&mut vm, &step).expect("Move failed");
             }
+            here = room.clone();
+            command(&mut vm, &format!("take {}", item)).expect("Take failed");
         }
     }
-    println!("{}", output);
+    for step in map.path(&here, &checkpoint) {
+        command(&mut vm, &step).expect("Move failed");
+    }
+
+    let out = fuzz(&mut vm, &dir, false);
+    out.split_whitespace()
+        .find(|w| w.len() > 1 && w.chars().all(|c| c.is_ascii_digit()))
+        .unwrap_or_else(|| panic!("No password in output:\n{}", out))
+        .to_owned()
 }
 
 fn main() {
     // We bake the machine into the program at compile time so that
     // the game can use stdin and be interactive
     let input = include_str!("../input");
-    let mut vm = Vm::from_str(&input).unwrap();
-
-    loop {
-        if vm.needs_input() {
-            let mut cmd = String::new();
-            std::io::stdin().read_line(&mut cmd).unwrap();
-            if cmd.starts_with("fuzz") {
-                let dir = cmd.split(' ').nth(1).unwrap();
-                fuzz(&mut vm, dir);
-                break;
-            } else {
-                for c in cmd.chars() {
-                    vm.input(c as u8 as i64);
+    let mut vm = Vm::from_str(input).unwrap();
+
+    if std::env::args().nth(1).as_deref() == Some("--solve") {
+        println!("{}", solve(vm));
+        return;
+    }
+
+    interact(&mut vm, |vm, cmd| {
+        if let Some(dir) = cmd.strip_prefix("fuzz ") {
+            println!("{}", fuzz(vm, dir.trim(), true));
+            Action::Quit
+        } else if let Some(name) = cmd.strip_prefix("save ") {
+            let path = format!("{}.sav", name.trim());
+            match vm.save(&path) {
+                Ok(()) => println!("Saved to {}\n\nCommand?", path),
+                Err(e) => println!("Could not save to {}: {}\n\nCommand?", path, e),
+            }
+            Action::Skip
+        } else if let Some(name) = cmd.strip_prefix("load ") {
+            let path = format!("{}.sav", name.trim());
+            match Vm::load(&path) {
+                Ok(v) => {
+                    *vm = v;
+                    println!("Loaded {}\n\nCommand?", path);
                 }
+                Err(e) => println!("Could not load {}: {}\n\nCommand?", path, e),
             }
-        } else if let Some(i) = vm.step() {
-            print!("{}", i as u8 as char);
+            Action::Skip
+        } else {
+            Action::Send
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HALL: &str = "
+
+
+== Hull Breach ==
+You got in through a hole in the floor here. To keep your ship from also freezing, the hole has been sealed.
+
+Doors here lead:
+- north
+- east
+- south
+
+Items here:
+- mutex
+- giant electromagnet
+
+Command?
+";
+
+    const BOUNCE: &str = "
+
+
+== Pressure-Sensitive Floor ==
+Analyzing...
+
+Doors here lead:
+- west
+
+A loud, robotic voice says \"Alert! Droids on this ship are heavier than the detected value!\" and you are ejected back to the checkpoint.
+
+
+
+== Security Checkpoint ==
+In the next room, a pressure-sensitive floor will verify your identity.
+
+Doors here lead:
+- north
+- east
+
+Command?
+";
+
+    #[test]
+    fn room() {
+        let room = parse_room(HALL).unwrap();
+        assert_eq!(room.name, "Hull Breach");
+        assert_eq!(room.doors, ["north", "east", "south"]);
+        assert_eq!(room.items, ["mutex", "giant electromagnet"]);
+        assert!(parse_room("Command?\n").is_none());
+    }
+
+    #[test]
+    fn bounce() {
+        // We land back at the checkpoint, which is the last room described
+        let room = parse_room(BOUNCE).unwrap();
+        assert_eq!(room.name, "Security Checkpoint");
+        assert_eq!(room.doors, ["north", "east"]);
+        assert!(room.items.is_empty());
+        assert!(BOUNCE.contains(FLOOR));
+    }
+
+    #[test]
+    fn path() {
+        let mut map = Map::default();
+        for (from, dir, to) in [
+            ("A", "north", "B"),
+            ("B", "east", "C"),
+            ("C", "south", "D"),
+            ("A", "west", "E"),
+        ] {
+            map.doors
+                .entry(from.to_owned())
+                .or_default()
+                .insert(dir.to_owned(), to.to_owned());
+            map.doors
+                .entry(to.to_owned())
+                .or_default()
+                .insert(opposite(dir).to_owned(), from.to_owned());
         }
+        assert_eq!(map.path("A", "D"), ["north", "east", "south"]);
+        assert_eq!(map.path("D", "E"), ["north", "west", "south", "west"]);
+        assert!(map.path("C", "C").is_empty());
     }
 }
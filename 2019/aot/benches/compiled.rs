@@ -0,0 +1,94 @@
+use aot::{ackerman, divmod, euler1, factor, isqrt, sum_of_primes};
+use criterion::{criterion_group, criterion_main, Criterion};
+use std::str::FromStr;
+use vm::aot::{Compiled, Program};
+use vm::{asm, Vm};
+
+/// Benchmarks a program in both the interpreter and compiled code, checking
+/// that both produce the expected output
+fn compare(c: &mut Criterion, name: &str, vm: Vm, program: &'static Program, out: &[i64]) {
+    let interpreted = || vm.clone().run();
+    let compiled = || Compiled::new(vm.clone(), program).run();
+    assert_eq!(interpreted(), out);
+    assert_eq!(compiled(), out);
+
+    let mut group = c.benchmark_group(name);
+    group.bench_function("interpreted", |b| b.iter(interpreted));
+    group.bench_function("compiled", |b| b.iter(compiled));
+    group.finish();
+}
+
+fn bench(tape: &str, input: &[i64]) -> Vm {
+    let mut vm = Vm::from_str(tape).unwrap();
+    for i in input {
+        vm.input(*i);
+    }
+    vm
+}
+
+pub fn sum_of_primes(c: &mut Criterion) {
+    let vm = bench(include_str!("../../vm/benches/sum-of-primes"), &[100000]);
+    compare(
+        c,
+        "sum-of-primes",
+        vm,
+        &sum_of_primes::PROGRAM,
+        &[454396537],
+    );
+}
+
+pub fn ackerman(c: &mut Criterion) {
+    let vm = bench(include_str!("../../vm/benches/ackerman"), &[3, 6]);
+    compare(c, "ackerman", vm, &ackerman::PROGRAM, &[509]);
+}
+
+pub fn isqrt(c: &mut Criterion) {
+    let vm = bench(include_str!("../../vm/benches/isqrt"), &[130]);
+    compare(c, "isqrt", vm, &isqrt::PROGRAM, &[11]);
+}
+
+pub fn divmod(c: &mut Criterion) {
+    let vm = bench(include_str!("../../vm/benches/divmod"), &[1024, 3]);
+    compare(c, "divmod", vm, &divmod::PROGRAM, &[341, 1]);
+}
+
+pub fn factor_small_prime(c: &mut Criterion) {
+    let i = 2147483647;
+    let vm = bench(include_str!("../../vm/benches/factor"), &[i]);
+    compare(c, &format!("factor {}", i), vm, &factor::PROGRAM, &[i]);
+}
+
+pub fn factor_large_composite(c: &mut Criterion) {
+    let i = 19201644899;
+    let vm = bench(include_str!("../../vm/benches/factor"), &[i]);
+    compare(
+        c,
+        &format!("factor {}", i),
+        vm,
+        &factor::PROGRAM,
+        &[138569, 138571],
+    );
+}
+
+pub fn euler1(c: &mut Criterion) {
+    let mut vm = Vm::new(&asm::assemble(include_str!("../../vm/benches/euler1.s")).unwrap());
+    vm.input(100000);
+    compare(c, "euler1", vm, &euler1::PROGRAM, &[2333316668]);
+}
+
+criterion_group! {
+    name = fast;
+    config = Criterion::default();
+    targets = isqrt,
+              divmod,
+              euler1,
+}
+criterion_group! {
+    name = slow;
+    config = Criterion::default().sample_size(10);
+    targets = sum_of_primes,
+              ackerman,
+              factor_small_prime,
+              factor_large_composite,
+}
+criterion_main!(fast, slow);
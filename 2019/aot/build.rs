@@ -0,0 +1,82 @@
+use std::io::Write;
+use std::path::Path;
+use std::str::FromStr;
+
+use vm::{asm, Vm};
+
+/// Tapes from `vm/benches`, as (module name, file)
+const BENCHES: &[(&str, &str)] = &[
+    ("sum_of_primes", "sum-of-primes"),
+    ("ackerman", "ackerman"),
+    ("isqrt", "isqrt"),
+    ("divmod", "divmod"),
+    ("factor", "factor"),
+];
+
+/// Small programs that exercise the interpreter fallback
+const TESTS: &[(&str, &str)] = &[
+    // Outputs 7, then overwrites its own first instruction to output 1
+    ("self_modifying", "104,7,1101,0,4,0,1105,1,0"),
+    // Calls a function through a return address on the stack
+    (
+        "call",
+        "
+            arb #100
+            in n
+            add #ret, #0 -> [rb+0]
+            jt #1, #double
+    ret:    out n
+            hlt
+    double: mul n, #2 -> n
+            jt #1, [rb+0]
+    n:      .data 0
+    ",
+    ),
+    // Jumps into the middle of a block, which must be interpreted
+    (
+        "computed",
+        "
+            in t
+            jt #1, t
+            add #1, #0 -> t
+            out #2
+            hlt
+    t:      .data 0
+    ",
+    ),
+];
+
+fn main() -> Result<(), std::io::Error> {
+    let manifest_dir = std::env::var_os("CARGO_MANIFEST_DIR").unwrap();
+    let bench_dir = Path::new(&manifest_dir).join("../vm/benches");
+    println!("cargo:rerun-if-changed=build.rs");
+
+    let out_dir = std::env::var_os("OUT_DIR").unwrap();
+    let mut f = std::fs::File::create(Path::new(&out_dir).join("gen.rs"))?;
+
+    let mut tapes = vec![];
+    for (name, file) in BENCHES {
+        let path = bench_dir.join(file);
+        println!("cargo:rerun-if-changed={}", path.display());
+        let text = std::fs::read_to_string(path)?;
+        let vm = Vm::from_str(&text).unwrap_or_else(|e| panic!("{}: {}", file, e));
+        tapes.push((name.to_string(), vm.tape().to_vec()));
+    }
+    let path = bench_dir.join("euler1.s");
+    println!("cargo:rerun-if-changed={}", path.display());
+    let src = std::fs::read_to_string(path)?;
+    tapes.push(("euler1".to_owned(), asm::assemble(&src).unwrap()));
+
+    for (name, src) in TESTS {
+        let tape = match Vm::from_str(src) {
+            Ok(vm) => vm.tape().to_vec(),
+            Err(_) => asm::assemble(src).unwrap_or_else(|e| panic!("{}: {}", name, e)),
+        };
+        tapes.push((name.to_string(), tape));
+    }
+
+    for (name, tape) in &tapes {
+        writeln!(f, "{}", vm::aot::compile(tape, name))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,108 @@
+//! Intcode programs compiled ahead of time by `vm::aot`.
+//!
+//! Each module exports a `PROGRAM`, built from the matching tape in
+//! `vm/benches` (plus a few small test programs).
+include!(concat!(env!("OUT_DIR"), "/gen.rs"));
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+    use vm::aot::{Compiled, Program};
+    use vm::{asm, Vm};
+
+    /// Runs a program through both the interpreter and the compiled code,
+    /// checking that the output and final state match
+    fn check(vm: Vm, program: &'static Program) -> Vec<i64> {
+        let mut interp = vm.clone();
+        let mut native = Compiled::new(vm, program);
+        assert!(native.is_native());
+        let out = native.run();
+        assert_eq!(out, interp.run());
+        assert_eq!(native.vm().tape(), interp.tape());
+        assert_eq!(native.vm().ip(), interp.ip());
+        assert_eq!(native.vm().base(), interp.base());
+        out
+    }
+
+    fn bench(tape: &str, input: &[i64]) -> Vm {
+        let mut vm = Vm::from_str(tape).unwrap();
+        for i in input {
+            vm.input(*i);
+        }
+        vm
+    }
+
+    #[test]
+    fn benches() {
+        let tape = include_str!("../../vm/benches/sum-of-primes");
+        assert_eq!(
+            check(bench(tape, &[100]), &sum_of_primes::PROGRAM),
+            vec![1060]
+        );
+        let tape = include_str!("../../vm/benches/ackerman");
+        assert_eq!(check(bench(tape, &[2, 3]), &ackerman::PROGRAM), vec![9]);
+        let tape = include_str!("../../vm/benches/isqrt");
+        assert_eq!(check(bench(tape, &[130]), &isqrt::PROGRAM), vec![11]);
+        let tape = include_str!("../../vm/benches/divmod");
+        assert_eq!(
+            check(bench(tape, &[1024, 3]), &divmod::PROGRAM),
+            vec![341, 1]
+        );
+        let tape = include_str!("../../vm/benches/factor");
+        assert_eq!(check(bench(tape, &[91]), &factor::PROGRAM), vec![7, 13]);
+
+        let mut vm = Vm::new(&asm::assemble(include_str!("../../vm/benches/euler1.s")).unwrap());
+        vm.input(1000);
+        assert_eq!(check(vm, &euler1::PROGRAM), vec![233168]);
+    }
+
+    #[test]
+    fn self_modifying() {
+        let vm = Vm::new(self_modifying::PROGRAM.tape);
+        let mut native = Compiled::new(vm, &self_modifying::PROGRAM);
+        assert_eq!(native.run_until(), Some(7));
+        assert!(native.is_native());
+        assert_eq!(native.run_until(), Some(1));
+        assert!(!native.is_native());
+    }
+
+    #[test]
+    fn patched() {
+        let mut vm = Vm::new(self_modifying::PROGRAM.tape);
+        vm.poke(1, 8);
+        let mut native = Compiled::new(vm, &self_modifying::PROGRAM);
+        assert!(!native.is_native());
+        assert_eq!(native.run_until(), Some(8));
+    }
+
+    #[test]
+    fn call() {
+        let mut vm = Vm::new(call::PROGRAM.tape);
+        vm.input(21);
+        assert_eq!(check(vm, &call::PROGRAM), vec![42]);
+    }
+
+    #[test]
+    fn computed() {
+        let mut vm = Vm::new(computed::PROGRAM.tape);
+        vm.input(9);
+        assert_eq!(check(vm, &computed::PROGRAM), vec![2]);
+
+        let mut vm = Vm::new(computed::PROGRAM.tape);
+        vm.input(-1);
+        let mut native = Compiled::new(vm, &computed::PROGRAM);
+        assert!(native.try_run().is_err());
+    }
+
+    #[test]
+    fn starved() {
+        let vm = Vm::new(call::PROGRAM.tape);
+        let mut native = Compiled::new(vm, &call::PROGRAM);
+        assert!(native.try_run_until().is_err());
+        native.input(4);
+        assert_eq!(native.run_until(), Some(8));
+    }
+}
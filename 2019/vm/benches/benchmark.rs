@@ -1,6 +1,6 @@
 use criterion::{criterion_group, criterion_main, Criterion};
 use std::str::FromStr;
-use vm::Vm;
+use vm::{asm, Vm};
 
 pub fn sum_of_primes(c: &mut Criterion) {
     let mut vm = Vm::from_str(include_str!("sum-of-primes")).unwrap();
@@ -64,11 +64,37 @@ pub fn factor_large_composite(c: &mut Criterion) {
     c.bench_function(&format!("factor {}", i), |b| b.iter(f));
 }
 
+pub fn euler1(c: &mut Criterion) {
+    let mut vm = Vm::new(&asm::assemble(include_str!("euler1.s")).unwrap());
+    vm.input(100000);
+    let f = || vm.clone().run_until().unwrap();
+    assert!(f() == 2333316668);
+    c.bench_function("euler1", |b| b.iter(f));
+}
+
+/// Runs the same tapes with and without the decode cache
+pub fn decode_cache(c: &mut Criterion) {
+    for (name, tape, input) in &[
+        ("sum-of-primes", include_str!("sum-of-primes"), 20000),
+        ("factor", include_str!("factor"), 2147483647),
+    ] {
+        for &cached in &[true, false] {
+            let mut vm = Vm::from_str(tape).unwrap();
+            vm.set_decode_cache(cached);
+            vm.input(*input);
+            let f = || vm.clone().run_until().unwrap();
+            let label = if cached { "cached" } else { "uncached" };
+            c.bench_function(&format!("{} ({})", name, label), |b| b.iter(f));
+        }
+    }
+}
+
 criterion_group! {
     name = fast;
     config = Criterion::default();
     targets = isqrt,
               divmod,
+              euler1,
 }
 criterion_group! {
     name = slow;
@@ -77,5 +103,6 @@ criterion_group! {
               ackerman,
               factor_small_prime,
               factor_large_composite,
+              decode_cache,
 }
 criterion_main!(fast, slow);
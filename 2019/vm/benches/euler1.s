@@ -0,0 +1,39 @@
+; Sums every multiple of 3 or 5 below the input value
+
+.macro clr dst
+        add #0, #0 -> dst
+.endm
+
+.macro inc dst
+        add dst, #1 -> dst
+.endm
+
+        in limit
+loop:   inc i
+        inc c3
+        inc c5
+        eq i, limit -> tmp
+        jt tmp, #done
+
+        eq c3, #3 -> t3
+        jf t3, #skip3
+        clr c3
+skip3:  eq c5, #5 -> t5
+        jf t5, #skip5
+        clr c5
+skip5:  add t3, t5 -> tmp
+        jf tmp, #loop
+        add sum, i -> sum
+        jt #1, #loop
+
+done:   out sum
+        hlt
+
+limit:  .data 0
+i:      .data 0
+c3:     .data 0
+c5:     .data 0
+t3:     .data 0
+t5:     .data 0
+tmp:    .data 0
+sum:    .data 0
@@ -0,0 +1,560 @@
+//! Ahead-of-time compilation of Intcode tapes into Rust.
+//!
+//! [`compile`] is meant to be called from a build script: it finds the
+//! reachable code in a tape (using [`walk`]), splits it into basic blocks,
+//! and emits a module containing a native function for those blocks.  The
+//! generated module exports a [`Program`], which is run with [`Compiled`].
+//!
+//! Compiled code works directly on a [`Vm`], and hands control back to the
+//! interpreter whenever it meets something it can't handle: computed jumps
+//! to addresses that aren't the start of a block, invalid addresses, or
+//! blocking on input.  If the program writes to its own code, the compiled
+//! version is abandoned and the rest of the run is interpreted.
+//!
+//! Arithmetic wraps on overflow, as it does in the interpreter.
+//!
+//! To opt in, add `vm` as a build dependency and write the output of
+//! [`compile`] to a file in `OUT_DIR`, then `include!` it and run
+//! `Compiled::new(vm, &name::PROGRAM)`; see the `aot` crate for an example.
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+use crate::disasm::{explore, walk, Instruction, Item, Operand};
+use crate::{
+    Decoded, Vm, VmError, MODE_POSITION, MODE_RELATIVE, OP_ADD, OP_BREAK, OP_EQ, OP_INPUT, OP_JIF,
+    OP_JIT, OP_LT, OP_MUL, OP_OUTPUT, OP_RBO,
+};
+
+/// Reasons why compiled code returned control
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Exit {
+    /// The program produced a value
+    Output(i64),
+    /// The program reached a halt instruction
+    Halted,
+    /// The instruction at `ip` must be run by the interpreter
+    Interpret,
+    /// The program wrote to its own code, so the compiled version can no
+    /// longer be trusted
+    Modified,
+}
+
+/// A compiled tape, as exported by the module that [`compile`] generates
+pub struct Program {
+    /// Runs from the VM's current state until it returns control
+    pub entry: fn(&mut Vm) -> Exit,
+    /// Bitset of tape cells that are part of compiled instructions
+    pub code: &'static [u64],
+    /// Bitset of addresses where compiled blocks start
+    pub blocks: &'static [u64],
+    /// The tape that was compiled
+    pub tape: &'static [i64],
+}
+
+impl Program {
+    /// Checks whether the VM's code matches the compiled tape
+    pub fn matches(&self, vm: &Vm) -> bool {
+        self.tape
+            .iter()
+            .enumerate()
+            .all(|(i, v)| !rt::bit(self.code, i) || vm.peek(i) == *v)
+    }
+}
+
+/// Functions used by generated code
+#[doc(hidden)]
+pub mod rt {
+    use crate::Vm;
+
+    #[inline]
+    pub fn load(vm: &Vm, addr: usize) -> i64 {
+        vm.mem.read(addr)
+    }
+
+    /// Writes to memory, returning `false` if the memory limit was hit
+    #[inline]
+    pub fn store(vm: &mut Vm, addr: usize, v: i64) -> bool {
+        vm.mem.write(addr, v)
+    }
+
+    /// Moves an input value into memory, returning `false` (and leaving the
+    /// input queue untouched) if there's no input or the store failed.
+    #[inline]
+    pub fn input(vm: &mut Vm, addr: usize) -> bool {
+        match vm.input.back() {
+            Some(&v) if vm.mem.write(addr, v) => {
+                vm.input.pop_back();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Checks whether a bit is set in a bitset
+    #[inline]
+    pub fn bit(bits: &[u64], i: usize) -> bool {
+        bits.get(i / 64).is_some_and(|w| (w >> (i % 64)) & 1 != 0)
+    }
+}
+
+/// A VM paired with a compiled version of its tape
+pub struct Compiled {
+    vm: Vm,
+    program: &'static Program,
+    native: bool,
+}
+
+impl Compiled {
+    /// Wraps a VM.  If its code doesn't match the compiled tape (e.g.
+    /// because it was patched after loading) or it's being profiled,
+    /// everything is interpreted.
+    pub fn new(vm: Vm, program: &'static Program) -> Self {
+        let native = program.matches(&vm) && vm.profile.is_none();
+        Compiled {
+            vm,
+            program,
+            native,
+        }
+    }
+
+    pub fn vm(&self) -> &Vm {
+        &self.vm
+    }
+
+    pub fn into_inner(self) -> Vm {
+        self.vm
+    }
+
+    /// Checks whether the compiled code is still in use
+    pub fn is_native(&self) -> bool {
+        self.native
+    }
+
+    pub fn input(&mut self, i: i64) {
+        self.vm.input(i);
+    }
+
+    /// Checks whether the instruction at `ip` would write to compiled code
+    fn writes_code(&self) -> bool {
+        let ip = self.vm.ip();
+        let Some(d) = Decoded::new(self.vm.peek(ip)) else {
+            return false;
+        };
+        let Some((n, true)) = Instruction::shape(d.opcode as i64) else {
+            return false;
+        };
+        let arg = self.vm.peek(ip + n);
+        let addr = match d.modes[n - 1] as i64 {
+            MODE_POSITION => arg,
+            MODE_RELATIVE => self.vm.base().wrapping_add(arg),
+            _ => return false,
+        };
+        addr >= 0 && rt::bit(self.program.code, addr as usize)
+    }
+
+    /// Runs until the program produces an output or halts, matching
+    /// [`Vm::try_run_until`]
+    pub fn try_run_until(&mut self) -> Result<Option<i64>, VmError> {
+        loop {
+            if !self.native {
+                return self.vm.try_run_until();
+            }
+            match (self.program.entry)(&mut self.vm) {
+                Exit::Output(i) => return Ok(Some(i)),
+                Exit::Halted => return Ok(None),
+                Exit::Modified => self.native = false,
+                // Interpret until we reach the start of a compiled block
+                Exit::Interpret => loop {
+                    if !self.vm.running() || self.vm.needs_input() {
+                        return self.vm.try_run_until();
+                    }
+                    if self.writes_code() {
+                        self.native = false;
+                    }
+                    if let Some(i) = self.vm.try_step()? {
+                        return Ok(Some(i));
+                    }
+                    if !self.native || rt::bit(self.program.blocks, self.vm.ip()) {
+                        break;
+                    }
+                },
+            }
+        }
+    }
+
+    pub fn try_run(&mut self) -> Result<Vec<i64>, VmError> {
+        let mut out = Vec::new();
+        while let Some(i) = self.try_run_until()? {
+            out.push(i);
+        }
+        Ok(out)
+    }
+
+    pub fn run_until(&mut self) -> Option<i64> {
+        self.try_run_until().unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn run(&mut self) -> Vec<i64> {
+        self.try_run().unwrap_or_else(|e| panic!("{}", e))
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Code generator for a single instruction
+struct Gen<'a> {
+    out: &'a mut String,
+    ip: usize,
+    next: usize,
+    code: &'a [u64],
+    patched: &'a BTreeSet<usize>,
+}
+
+impl Gen<'_> {
+    /// Returns a statement that hands this instruction to the interpreter
+    fn fallback(&self) -> String {
+        format!("{{ ip = {}; break Exit::Interpret; }}", self.ip)
+    }
+
+    fn modified(&self) -> String {
+        format!("{{ ip = {}; break Exit::Modified; }}", self.next)
+    }
+
+    /// Returns the raw value of a parameter, which is either a constant or
+    /// (if the program patches it at runtime) the name of a variable.
+    fn raw(&mut self, i: usize, v: i64) -> Result<i64, String> {
+        let cell = self.ip + i + 1;
+        if self.patched.contains(&cell) {
+            writeln!(self.out, "let o{} = rt::load(vm, {});", i, cell).unwrap();
+            Err(format!("o{}", i))
+        } else {
+            Ok(v)
+        }
+    }
+
+    /// Emits code to check that an address is valid, returning its name
+    fn addr(&mut self, name: &str, v: &str) -> String {
+        let f = self.fallback();
+        writeln!(self.out, "let {} = {}; if {} < 0 {}", name, v, name, f).unwrap();
+        format!("{} as usize", name)
+    }
+
+    /// Emits code to evaluate a parameter, returning an expression for it
+    fn param(&mut self, i: usize, op: Operand) -> Option<String> {
+        let a = format!("a{}", i);
+        let e = match op {
+            Operand::Immediate(v) => match self.raw(i, v) {
+                Ok(v) => return Some(format!("({}_i64)", v)),
+                Err(o) => return Some(o),
+            },
+            Operand::Position(p) => match self.raw(i, p) {
+                Ok(p) if p >= 0 => p.to_string(),
+                Ok(_) => return None,
+                Err(o) => self.addr(&a, &o),
+            },
+            Operand::Relative(r) => {
+                let v = match self.raw(i, r) {
+                    Ok(r) => format!("rb.wrapping_add({})", r),
+                    Err(o) => format!("rb.wrapping_add({})", o),
+                };
+                self.addr(&a, &v)
+            }
+        };
+        Some(format!("rt::load(vm, {})", e))
+    }
+
+    /// Emits code to compute the address of a destination parameter,
+    /// returning it and whether it's known to be compiled code.
+    fn dest(&mut self, i: usize, op: Operand) -> Option<(String, Option<bool>)> {
+        match op {
+            Operand::Position(p) => match self.raw(i, p) {
+                Ok(p) if p >= 0 => Some((p.to_string(), Some(rt::bit(self.code, p as usize)))),
+                Ok(_) => None,
+                Err(o) => Some((self.addr("d", &o), None)),
+            },
+            Operand::Relative(r) => {
+                let v = match self.raw(i, r) {
+                    Ok(r) => format!("rb.wrapping_add({})", r),
+                    Err(o) => format!("rb.wrapping_add({})", o),
+                };
+                Some((self.addr("d", &v), None))
+            }
+            Operand::Immediate(_) => None,
+        }
+    }
+
+    /// Emits code to write to a destination parameter, using `f` to build
+    /// the store expression from the address.  Returns `false` if the block
+    /// can't continue afterwards.
+    fn store<F>(&mut self, i: usize, op: Operand, f: F) -> Option<bool>
+    where
+        F: Fn(&str) -> String,
+    {
+        let (d, is_code) = self.dest(i, op)?;
+        let fallback = self.fallback();
+        let modified = self.modified();
+        writeln!(self.out, "if !{} {}", f(&d), fallback).unwrap();
+        match is_code {
+            Some(true) => {
+                writeln!(self.out, "{}", modified).unwrap();
+                Some(false)
+            }
+            Some(false) => Some(true),
+            None => {
+                writeln!(self.out, "if rt::bit(&CODE, {}) {}", d, modified).unwrap();
+                Some(true)
+            }
+        }
+    }
+
+    /// Emits an instruction, returning `false` if it ends the block
+    fn instruction(&mut self, op: &Instruction) -> bool {
+        writeln!(self.out, "// {:04}: {}", self.ip, op).unwrap();
+        self.out.push_str("{\n");
+        let r = self.body(op);
+        self.out.push_str("}\n");
+        match r {
+            Some(r) => r,
+            None => {
+                let f = self.fallback();
+                writeln!(self.out, "{}", f).unwrap();
+                false
+            }
+        }
+    }
+
+    fn body(&mut self, op: &Instruction) -> Option<bool> {
+        let p = &op.params;
+        match op.opcode {
+            OP_ADD | OP_MUL | OP_LT | OP_EQ => {
+                let a = self.param(0, p[0])?;
+                let b = self.param(1, p[1])?;
+                let v = match op.opcode {
+                    OP_ADD => format!("{}.wrapping_add({})", a, b),
+                    OP_MUL => format!("{}.wrapping_mul({})", a, b),
+                    OP_LT => format!("({} < {}) as i64", a, b),
+                    OP_EQ => format!("({} == {}) as i64", a, b),
+                    _ => unreachable!(),
+                };
+                writeln!(self.out, "let v = {};", v).unwrap();
+                self.store(2, p[2], |d| format!("rt::store(vm, {}, v)", d))
+            }
+            OP_INPUT => self.store(0, p[0], |d| format!("rt::input(vm, {})", d)),
+            OP_OUTPUT => {
+                let v = self.param(0, p[0])?;
+                writeln!(self.out, "let v = {};", v).unwrap();
+                writeln!(self.out, "ip = {}; break Exit::Output(v);", self.next).unwrap();
+                Some(false)
+            }
+            OP_JIT | OP_JIF => {
+                let c = self.param(0, p[0])?;
+                let cmp = if op.opcode == OP_JIT { "!=" } else { "==" };
+                writeln!(self.out, "if {} {} 0 {{", c, cmp).unwrap();
+                let t = match p[1] {
+                    Operand::Immediate(t) => self.raw(1, t),
+                    t => Err(self.param(1, t)?),
+                };
+                match t {
+                    Ok(t) if t >= 0 => writeln!(self.out, "ip = {}; continue;", t).unwrap(),
+                    Ok(_) => self.out.push_str(&self.fallback()),
+                    Err(t) => {
+                        let t = self.addr("t", &t);
+                        writeln!(self.out, "ip = {}; continue;", t).unwrap();
+                    }
+                }
+                writeln!(self.out, "}}\nip = {};", self.next).unwrap();
+                Some(false)
+            }
+            OP_RBO => {
+                let v = self.param(0, p[0])?;
+                writeln!(self.out, "rb = rb.wrapping_add({});", v).unwrap();
+                Some(true)
+            }
+            OP_BREAK => {
+                writeln!(self.out, "ip = {}; break Exit::Halted;", self.ip).unwrap();
+                Some(false)
+            }
+            _ => unreachable!(),
+        }
+    }
+}
+
+fn bitset<I: Iterator<Item = usize>>(len: usize, iter: I) -> Vec<u64> {
+    let mut bits = vec![0u64; len.div_ceil(64)];
+    for i in iter {
+        bits[i / 64] |= 1 << (i % 64);
+    }
+    bits
+}
+
+/// Returns cells that are the destination of a constant write and also a
+/// parameter of some instruction
+fn patched_params(code: &BTreeMap<usize, Instruction>) -> BTreeSet<usize> {
+    let params = code
+        .iter()
+        .flat_map(|(a, op)| a + 1..a + op.size())
+        .collect::<BTreeSet<_>>();
+    code.values()
+        .filter(|op| Instruction::shape(op.opcode).is_some_and(|(_, w)| w))
+        .filter_map(|op| match op.params.last() {
+            Some(Operand::Position(p)) if *p >= 0 => Some(*p as usize),
+            _ => None,
+        })
+        .filter(|p| params.contains(p))
+        .collect()
+}
+
+/// Finds code, starting from what [`walk`] finds.
+///
+/// [`walk`] assumes that a jump with an immediate condition always goes the
+/// same way, which isn't true if the program patches the condition, so
+/// this also follows the other branch of such jumps.
+fn find_code(tape: &[i64]) -> BTreeMap<usize, Instruction> {
+    let mut code = walk(tape)
+        .into_iter()
+        .filter_map(|(a, item)| match item {
+            Item::Code(op) => Some((a, op)),
+            Item::Data(_) => None,
+        })
+        .collect::<BTreeMap<_, _>>();
+    let mut tried = BTreeSet::new();
+    loop {
+        let patched = patched_params(&code);
+        let todo = code
+            .iter()
+            .filter(|(a, op)| {
+                matches!(op.opcode, OP_JIT | OP_JIF)
+                    && !op.falls_through()
+                    && patched.contains(&(*a + 1))
+            })
+            .map(|(a, op)| a + op.size())
+            .filter(|a| !code.contains_key(a) && tried.insert(*a))
+            .collect::<Vec<_>>();
+        if todo.is_empty() {
+            break code;
+        }
+        explore(tape, &mut code, todo);
+    }
+}
+
+/// Translates a tape into Rust source for a module named `name`, which
+/// exports a `PROGRAM: vm::aot::Program`.
+///
+/// The module uses the `vm` crate by name, so the crate that includes the
+/// generated code must depend on it.
+pub fn compile(tape: &[i64], name: &str) -> String {
+    let code = find_code(tape);
+
+    // Programs often patch the parameters of their own instructions (e.g.
+    // for indirect loads), so any parameter that's the destination of a
+    // constant write is loaded at runtime rather than compiled in.  Writes
+    // anywhere else in the compiled code invalidate it.
+    let patched = patched_params(&code);
+    let code = code.iter().map(|(a, op)| (*a, op)).collect::<Vec<_>>();
+
+    let bits = bitset(
+        tape.len(),
+        code.iter()
+            .flat_map(|(a, op)| *a..*a + op.size())
+            .filter(|i| !patched.contains(i)),
+    );
+
+    // Blocks start at the entry point, jump targets, and anything that
+    // could be the target of a computed jump (i.e. appears as an immediate).
+    // Execution also resumes after outputs and before inputs, so those are
+    // block boundaries too.
+    let mut leaders = BTreeSet::new();
+    leaders.insert(0);
+    let mut prev: Option<(usize, &Instruction)> = None;
+    for (a, op) in &code {
+        for p in &op.params {
+            if let Operand::Immediate(i) = p {
+                if *i >= 0 {
+                    leaders.insert(*i as usize);
+                }
+            }
+        }
+        let contiguous = prev.is_some_and(|(pa, p)| pa + p.size() == *a);
+        let ends_block =
+            prev.is_none_or(|(_, p)| matches!(p.opcode, OP_JIT | OP_JIF | OP_OUTPUT | OP_BREAK));
+        if !contiguous || ends_block || op.opcode == OP_INPUT {
+            leaders.insert(*a);
+        }
+        prev = Some((*a, op));
+    }
+    let starts = code.iter().map(|(a, _)| *a).filter(|a| leaders.contains(a));
+    let blocks = bitset(tape.len(), starts);
+
+    let mut out = String::new();
+    writeln!(
+        out,
+        "#[allow(clippy::all, unused, unreachable_code)]
+pub mod {} {{
+    use ::vm::aot::{{rt, Exit, Program}};
+    use ::vm::Vm;
+
+    pub static PROGRAM: Program = Program {{
+        entry: run,
+        code: &CODE,
+        blocks: &BLOCKS,
+        tape: &TAPE,
+    }};
+
+    static CODE: [u64; {}] = {:?};
+    static BLOCKS: [u64; {}] = {:?};
+    static TAPE: [i64; {}] = {:?};
+
+    fn run(vm: &mut Vm) -> Exit {{
+        let mut ip = vm.ip();
+        let mut rb = vm.base();
+        let exit = loop {{
+            match ip {{",
+        name,
+        bits.len(),
+        bits,
+        blocks.len(),
+        blocks,
+        tape.len(),
+        tape
+    )
+    .unwrap();
+
+    let mut i = 0;
+    while i < code.len() {
+        let start = code[i].0;
+        writeln!(out, "{} => {{", start).unwrap();
+        loop {
+            let (a, op) = code[i];
+            let mut g = Gen {
+                out: &mut out,
+                ip: a,
+                next: a + op.size(),
+                code: &bits,
+                patched: &patched,
+            };
+            let more = g.instruction(op);
+            i += 1;
+            let next = a + op.size();
+            if !more {
+                break;
+            } else if i == code.len() || code[i].0 != next || leaders.contains(&next) {
+                writeln!(out, "ip = {};", next).unwrap();
+                break;
+            }
+        }
+        out.push_str("}\n");
+    }
+
+    out.push_str(
+        "                _ => break Exit::Interpret,
+            }
+        };
+        vm.set_ip(ip);
+        vm.set_base(rb);
+        exit
+    }
+}
+",
+    );
+    out
+}
@@ -0,0 +1,175 @@
+//! Helpers for Intcode programs that talk in ASCII.
+use std::fmt;
+use std::io::{BufRead, Write};
+use std::iter::FromIterator;
+
+use crate::Vm;
+
+/// Output from an ASCII program, split into printable text and any other
+/// values (e.g. a final answer too large to be a character).
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct AsciiOutput {
+    pub text: String,
+    pub values: Vec<i64>,
+}
+
+impl AsciiOutput {
+    pub fn push(&mut self, v: i64) {
+        if v > 0 && v < 128 {
+            self.text.push(v as u8 as char);
+        } else {
+            self.values.push(v);
+        }
+    }
+
+    /// Returns the last non-ASCII value, which is usually the puzzle answer
+    pub fn value(&self) -> Option<i64> {
+        self.values.last().copied()
+    }
+}
+
+impl FromIterator<i64> for AsciiOutput {
+    fn from_iter<I: IntoIterator<Item = i64>>(iter: I) -> Self {
+        let mut out = AsciiOutput::default();
+        for v in iter {
+            out.push(v);
+        }
+        out
+    }
+}
+
+impl fmt::Display for AsciiOutput {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.text)?;
+        for v in &self.values {
+            writeln!(f, "{}", v)?;
+        }
+        Ok(())
+    }
+}
+
+impl Vm {
+    /// Queues a line of ASCII input, adding a trailing newline
+    pub fn send_line(&mut self, line: &str) {
+        for c in line.strip_suffix('\n').unwrap_or(line).chars() {
+            self.input(c as i64);
+        }
+        self.input('\n' as i64);
+    }
+
+    /// Runs until the program needs input or halts, collecting its output
+    pub fn read_ascii(&mut self) -> AsciiOutput {
+        let mut out = AsciiOutput::default();
+        while self.running() && !self.needs_input() {
+            if let Some(v) = self.step() {
+                out.push(v);
+            }
+        }
+        out
+    }
+
+    /// Runs until the program needs input or halts, returning its text
+    /// output (any non-ASCII values are dropped; see [`Vm::read_ascii`]).
+    pub fn read_until_prompt(&mut self) -> String {
+        self.read_ascii().text
+    }
+}
+
+/// What [`interact`] should do with a line typed by the user
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Action {
+    /// Send the line to the program
+    Send,
+    /// The line was handled by the hook, so don't send it
+    Skip,
+    /// Stop interacting
+    Quit,
+}
+
+/// Drives an ASCII program over stdin and stdout.
+///
+/// Each line typed by the user is passed to `hook` (which may inspect or
+/// modify the VM) before being sent to the program.  Returns when the
+/// program halts, stdin is closed, or the hook returns [`Action::Quit`].
+pub fn interact<F>(vm: &mut Vm, mut hook: F)
+where
+    F: FnMut(&mut Vm, &str) -> Action,
+{
+    let stdin = std::io::stdin();
+    loop {
+        print!("{}", vm.read_ascii());
+        std::io::stdout().flush().unwrap();
+        if !vm.running() {
+            break;
+        }
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line).unwrap() == 0 {
+            break;
+        }
+        match hook(vm, line.trim_end_matches('\n')) {
+            Action::Send => vm.send_line(&line),
+            Action::Skip => (),
+            Action::Quit => break,
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+
+    // Echoes each line in upper case, then outputs the total number of
+    // characters read when it sees an empty line.
+    const SHOUT: &str = "
+        loop:   in c
+                eq c, #10 -> t
+                jt t, #newline
+                add #0, #0 -> last
+                add n, #1 -> n
+                lt c, #97 -> t
+                jt t, #print
+                add c, #-32 -> c
+        print:  out c
+                jt #1, #loop
+        newline:
+                jt last, #done
+                out c
+                add #1, #0 -> last
+                jt #1, #loop
+        done:   out n
+                hlt
+        c:      .data 0
+        t:      .data 0
+        n:      .data 1000
+        last:   .data 0
+    ";
+
+    #[test]
+    fn lines() {
+        let mut vm = Vm::new(&assemble(SHOUT).unwrap());
+        assert_eq!(vm.read_until_prompt(), "");
+        assert!(vm.needs_input());
+
+        vm.send_line("hello");
+        assert_eq!(vm.read_until_prompt(), "HELLO\n");
+
+        vm.send_line("abc\n");
+        vm.send_line("");
+        let out = vm.read_ascii();
+        assert_eq!(out.text, "ABC\n");
+        assert_eq!(out.value(), Some(1008));
+        assert!(!vm.running());
+    }
+
+    #[test]
+    fn split() {
+        let out = "ok\n".bytes().map(i64::from).chain([0, 12345]);
+        let out = out.collect::<AsciiOutput>();
+        assert_eq!(out.text, "ok\n");
+        assert_eq!(out.values, vec![0, 12345]);
+        assert_eq!(out.to_string(), "ok\n0\n12345\n");
+    }
+}
@@ -0,0 +1,484 @@
+//! A small assembler for Intcode.
+//!
+//! Each line holds an optional label (`name:`), then either an instruction
+//! or a directive, with `;` starting a comment:
+//! ```text
+//! start:  in [rb+1]
+//!         add [rb+1], #-1 -> [rb+1]   ; the output can follow ',' or '->'
+//!         jt [rb+1], #start
+//!         hlt
+//! table:  .data 1, 2, start
+//! ```
+//!
+//! Operands are `#value` (immediate), `[rb+n]` (relative), or `value` /
+//! `@value` (position), where a value is an integer, a label, or a label
+//! plus or minus an integer.  A numeric label such as `0042:` checks the
+//! current address instead of defining a name, so a listing from
+//! [`crate::disasm::disassemble`] can be assembled again.
+//!
+//! Macros are declared with `.macro name arg, arg` and closed by `.endm`;
+//! within the body, `\@` expands to a number unique to each expansion, so
+//! that macros can define their own labels.
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::disasm::Instruction;
+use crate::{
+    MODE_IMMEDIATE, MODE_POSITION, MODE_RELATIVE, OP_ADD, OP_BREAK, OP_EQ, OP_INPUT, OP_JIF,
+    OP_JIT, OP_LT, OP_MUL, OP_OUTPUT, OP_RBO,
+};
+
+/// Maximum nesting depth for macro expansion
+const MAX_MACRO_DEPTH: usize = 64;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AsmError {
+    /// Line number in the source (starting at 1)
+    pub line: usize,
+    pub msg: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.msg)
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+fn err<T>(line: usize, msg: impl Into<String>) -> Result<T, AsmError> {
+    Err(AsmError {
+        line,
+        msg: msg.into(),
+    })
+}
+
+fn opcode(mnemonic: &str) -> Option<i64> {
+    match mnemonic.to_ascii_lowercase().as_str() {
+        "add" => Some(OP_ADD),
+        "mul" => Some(OP_MUL),
+        "in" => Some(OP_INPUT),
+        "out" => Some(OP_OUTPUT),
+        "jt" => Some(OP_JIT),
+        "jf" => Some(OP_JIF),
+        "lt" => Some(OP_LT),
+        "eq" => Some(OP_EQ),
+        "arb" => Some(OP_RBO),
+        "hlt" => Some(OP_BREAK),
+        _ => None,
+    }
+}
+
+fn is_ident(s: &str) -> bool {
+    s.chars()
+        .next()
+        .is_some_and(|c| c.is_alphabetic() || c == '_')
+        && s.chars().all(|c| c.is_alphanumeric() || c == '_')
+}
+
+/// An integer or label reference, with an optional offset
+#[derive(Clone, Debug)]
+enum Value {
+    Int(i64),
+    Label(String, i64),
+}
+
+impl Value {
+    fn parse(s: &str, line: usize) -> Result<Self, AsmError> {
+        let s = s.trim();
+        if let Ok(i) = s.parse() {
+            return Ok(Value::Int(i));
+        }
+        let (name, offset) = match s.find(['+', '-']) {
+            Some(i) => match s[i..].replace(' ', "").parse::<i64>() {
+                Ok(o) => (s[..i].trim(), o),
+                Err(_) => return err(line, format!("Invalid offset in {:?}", s)),
+            },
+            None => (s, 0),
+        };
+        if is_ident(name) {
+            Ok(Value::Label(name.to_owned(), offset))
+        } else {
+            err(line, format!("Invalid value {:?}", s))
+        }
+    }
+
+    fn resolve(&self, labels: &HashMap<String, i64>, line: usize) -> Result<i64, AsmError> {
+        match self {
+            Value::Int(i) => Ok(*i),
+            Value::Label(name, offset) => match labels.get(name) {
+                Some(v) => Ok(v + offset),
+                None => err(line, format!("Unknown label {:?}", name)),
+            },
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Operand {
+    mode: i64,
+    value: Value,
+}
+
+impl Operand {
+    fn parse(s: &str, line: usize) -> Result<Self, AsmError> {
+        let s = s.trim();
+        if let Some(v) = s.strip_prefix('#') {
+            Ok(Operand {
+                mode: MODE_IMMEDIATE,
+                value: Value::parse(v, line)?,
+            })
+        } else if let Some(r) = s.strip_prefix('[').and_then(|r| r.strip_suffix(']')) {
+            let r = r.trim();
+            let Some(offset) = r.strip_prefix("rb") else {
+                return err(line, format!("Expected [rb+n], got {:?}", s));
+            };
+            let offset = offset.trim();
+            let value = if offset.is_empty() {
+                Value::Int(0)
+            } else if let Some(v) = offset.strip_prefix('+') {
+                Value::parse(v, line)?
+            } else if offset.starts_with('-') {
+                Value::parse(offset, line)?
+            } else {
+                return err(line, format!("Expected [rb+n], got {:?}", s));
+            };
+            Ok(Operand {
+                mode: MODE_RELATIVE,
+                value,
+            })
+        } else {
+            Ok(Operand {
+                mode: MODE_POSITION,
+                value: Value::parse(s.strip_prefix('@').unwrap_or(s), line)?,
+            })
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Statement {
+    Op(i64, Vec<Operand>),
+    Data(Vec<Value>),
+}
+
+impl Statement {
+    fn size(&self) -> usize {
+        match self {
+            Statement::Op(_, params) => params.len() + 1,
+            Statement::Data(vs) => vs.len(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Macro {
+    args: Vec<String>,
+    body: Vec<(usize, String)>,
+}
+
+/// Replaces whole-word arguments in a macro body line
+fn substitute(text: &str, args: &HashMap<&str, &str>, unique: usize) -> String {
+    let text = text.replace("\\@", &unique.to_string());
+    let mut out = String::new();
+    let mut word = String::new();
+    for c in text.chars().chain(std::iter::once('\0')) {
+        if c.is_alphanumeric() || c == '_' {
+            word.push(c);
+        } else {
+            out += args.get(word.as_str()).copied().unwrap_or(&word);
+            word.clear();
+            if c != '\0' {
+                out.push(c);
+            }
+        }
+    }
+    out
+}
+
+struct Parser {
+    macros: HashMap<String, Macro>,
+    expansions: usize,
+
+    /// Labels and address checks, as (line, name, address)
+    labels: Vec<(usize, String, usize)>,
+    statements: Vec<(usize, Statement)>,
+    addr: usize,
+}
+
+impl Parser {
+    fn new() -> Self {
+        Parser {
+            macros: HashMap::new(),
+            expansions: 0,
+            labels: Vec::new(),
+            statements: Vec::new(),
+            addr: 0,
+        }
+    }
+
+    fn parse(&mut self, lines: &[(usize, String)], depth: usize) -> Result<(), AsmError> {
+        let mut iter = lines.iter();
+        while let Some((line, text)) = iter.next() {
+            let line = *line;
+            let mut text = text.split(';').next().unwrap().trim();
+
+            // Peel off labels
+            while let Some(i) = text.find(':') {
+                let name = text[..i].trim();
+                if !name.chars().all(|c| c.is_alphanumeric() || c == '_') || name.is_empty() {
+                    break;
+                }
+                self.labels.push((line, name.to_owned(), self.addr));
+                text = text[i + 1..].trim();
+            }
+            if text.is_empty() {
+                continue;
+            }
+
+            let (word, rest) = match text.find(char::is_whitespace) {
+                Some(i) => (&text[..i], text[i..].trim()),
+                None => (text, ""),
+            };
+            // Instructions with only an output (i.e. `in`) may be written
+            // as `in -> dst`, which is how they're disassembled
+            let rest = rest.strip_prefix("->").unwrap_or(rest).trim();
+            let args = if rest.is_empty() {
+                vec![]
+            } else {
+                rest.replace("->", ",")
+                    .split(',')
+                    .map(|s| s.trim().to_owned())
+                    .collect()
+            };
+
+            if word == ".macro" {
+                let Some((name, args)) = args.split_first() else {
+                    return err(line, "Missing macro name");
+                };
+                let mut name = name.to_owned();
+                let mut args = args.to_vec();
+                // The first argument is separated from the name by a space
+                if let Some(i) = name.find(char::is_whitespace) {
+                    args.insert(0, name[i..].trim().to_owned());
+                    name.truncate(i);
+                }
+                if !is_ident(&name) || !args.iter().all(|a| is_ident(a)) {
+                    return err(line, format!("Invalid macro declaration {:?}", text));
+                }
+                let mut body = vec![];
+                loop {
+                    match iter.next() {
+                        Some((_, t)) if t.split(';').next().unwrap().trim() == ".endm" => break,
+                        Some(b) => body.push(b.clone()),
+                        None => return err(line, format!("Unterminated macro {:?}", name)),
+                    }
+                }
+                self.macros.insert(name, Macro { args, body });
+            } else if word == ".data" {
+                let vs = args
+                    .iter()
+                    .map(|a| Value::parse(a, line))
+                    .collect::<Result<Vec<_>, _>>()?;
+                self.push(line, Statement::Data(vs));
+            } else if let Some(op) = opcode(word) {
+                let params = args
+                    .iter()
+                    .map(|a| Operand::parse(a, line))
+                    .collect::<Result<Vec<_>, _>>()?;
+                let (n, writes) = Instruction::shape(op).unwrap();
+                if params.len() != n {
+                    return err(
+                        line,
+                        format!("{} takes {} operands, got {}", word, n, params.len()),
+                    );
+                }
+                if writes && params[n - 1].mode == MODE_IMMEDIATE {
+                    return err(line, format!("{} can't write to an immediate", word));
+                }
+                self.push(line, Statement::Op(op, params));
+            } else if let Some(m) = self.macros.get(word).cloned() {
+                if depth >= MAX_MACRO_DEPTH {
+                    return err(line, format!("Macro {:?} nested too deeply", word));
+                } else if m.args.len() != args.len() {
+                    return err(
+                        line,
+                        format!(
+                            "Macro {:?} takes {} arguments, got {}",
+                            word,
+                            m.args.len(),
+                            args.len()
+                        ),
+                    );
+                }
+                self.expansions += 1;
+                let subs = m
+                    .args
+                    .iter()
+                    .map(String::as_str)
+                    .zip(args.iter().map(String::as_str))
+                    .collect();
+                let body = m
+                    .body
+                    .iter()
+                    .map(|(_, t)| (line, substitute(t, &subs, self.expansions)))
+                    .collect::<Vec<_>>();
+                self.parse(&body, depth + 1)?;
+            } else {
+                return err(line, format!("Unknown instruction {:?}", word));
+            }
+        }
+        Ok(())
+    }
+
+    fn push(&mut self, line: usize, s: Statement) {
+        self.addr += s.size();
+        self.statements.push((line, s));
+    }
+}
+
+/// Assembles the given source into a tape, suitable for [`crate::Vm::new`]
+pub fn assemble(src: &str) -> Result<Vec<i64>, AsmError> {
+    let lines = src
+        .lines()
+        .enumerate()
+        .map(|(i, t)| (i + 1, t.to_owned()))
+        .collect::<Vec<_>>();
+    let mut p = Parser::new();
+    p.parse(&lines, 0)?;
+
+    let mut labels = HashMap::new();
+    for (line, name, addr) in p.labels {
+        if let Ok(a) = name.parse::<usize>() {
+            if a != addr {
+                return err(line, format!("Expected address {}, but at {}", a, addr));
+            }
+        } else if labels.insert(name.clone(), addr as i64).is_some() {
+            return err(line, format!("Duplicate label {:?}", name));
+        }
+    }
+
+    let mut out = Vec::with_capacity(p.addr);
+    for (line, s) in p.statements {
+        match s {
+            Statement::Op(op, params) => {
+                let mut instruction = op;
+                let mut values = vec![];
+                for (i, p) in params.iter().enumerate() {
+                    instruction += p.mode * 10_i64.pow(i as u32 + 2);
+                    values.push(p.value.resolve(&labels, line)?);
+                }
+                out.push(instruction);
+                out.extend(values);
+            }
+            Statement::Data(vs) => {
+                for v in vs {
+                    out.push(v.resolve(&labels, line)?);
+                }
+            }
+        }
+    }
+    Ok(out)
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disasm::disassemble;
+    use crate::Vm;
+
+    // Prints the Fibonacci sequence, with the count given as input
+    const FIB: &str = "
+        .macro mov src, dst
+            add src, #0 -> dst
+        .endm
+
+                in count
+                mov #1, a
+                mov #1, b
+        loop:   jf count, #done
+                out a
+                add a, b -> next
+                mov b, a
+                mov next, b
+                add count, #-1 -> count
+                jt #1, #loop
+        done:   hlt
+        count:  .data 0
+        a:      .data 0
+        b:      .data 0
+        next:   .data 0
+    ";
+
+    #[test]
+    fn fib() {
+        let tape = assemble(FIB).unwrap();
+        let mut vm = Vm::new(&tape);
+        vm.input(10);
+        assert_eq!(vm.run(), vec![1, 1, 2, 3, 5, 8, 13, 21, 34, 55]);
+    }
+
+    #[test]
+    fn encoding() {
+        assert_eq!(
+            assemble("ADD [rb+3], #5 -> @17").unwrap(),
+            vec![1201, 3, 5, 17]
+        );
+        assert_eq!(assemble("out [rb-1]\nhlt").unwrap(), vec![204, -1, 99]);
+        assert_eq!(
+            assemble("x: jt #1, #x+2\n.data x, x - 1").unwrap(),
+            vec![1105, 1, 2, 0, -1]
+        );
+    }
+
+    #[test]
+    fn round_trip() {
+        let tape = assemble(FIB).unwrap();
+        let listing = disassemble(&tape);
+        assert_eq!(assemble(&listing).unwrap(), tape);
+
+        for prog in [
+            include_str!("../benches/ackerman"),
+            include_str!("../benches/divmod"),
+            include_str!("../benches/factor"),
+            include_str!("../benches/isqrt"),
+            include_str!("../benches/sum-of-primes"),
+        ] {
+            let tape = prog
+                .trim()
+                .split(',')
+                .map(|i| i.parse().unwrap())
+                .collect::<Vec<i64>>();
+            assert_eq!(assemble(&disassemble(&tape)).unwrap(), tape);
+        }
+    }
+
+    #[test]
+    fn local_labels() {
+        let src = "
+            .macro skip
+                jt #1, #over\\@
+                .data 123
+            over\\@:
+            .endm
+            skip
+            skip
+            hlt";
+        assert_eq!(
+            assemble(src).unwrap(),
+            vec![1105, 1, 4, 123, 1105, 1, 8, 123, 99]
+        );
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(assemble("hlt\nfoo").unwrap_err().line, 2);
+        assert_eq!(assemble("add #1, #2 -> #3").unwrap_err().line, 1);
+        assert_eq!(assemble("out 1, 2").unwrap_err().line, 1);
+        assert_eq!(assemble("jt #1, #nowhere").unwrap_err().line, 1);
+        assert_eq!(assemble("a: hlt\na: hlt").unwrap_err().line, 2);
+        assert_eq!(assemble("hlt\n0000: hlt").unwrap_err().line, 2);
+    }
+}
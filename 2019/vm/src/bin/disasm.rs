@@ -0,0 +1,78 @@
+//! Prints a listing of the Intcode tape on stdin.
+//!
+//! With `--cfg`, prints the tape's control-flow graph in Graphviz format.
+//!
+//! With `--trace`, runs the tape instead, printing each instruction as it
+//! is executed.  Any further arguments are used as input values.
+//!
+//! With `--profile`, runs the tape and prints its output, followed by a
+//! profile of the run.  Further arguments are used as input values, or sent
+//! as lines of ASCII text if they aren't numbers.
+use std::io::Read;
+use std::str::FromStr;
+
+use vm::ascii::AsciiOutput;
+use vm::cfg::Cfg;
+use vm::{disasm, Vm};
+
+fn main() {
+    let mut input = String::new();
+    std::io::stdin().read_to_string(&mut input).unwrap();
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("--trace") => (),
+        Some("--profile") => return profile(&input, &args[1..]),
+        Some("--cfg") => {
+            let vm = Vm::from_str(&input).unwrap();
+            print!("{}", Cfg::new(vm.tape()).to_dot());
+            return;
+        }
+        _ => {
+            let vm = Vm::from_str(&input).unwrap();
+            print!("{}", disasm::disassemble(vm.tape()));
+            return;
+        }
+    }
+
+    let mut vm = Vm::from_str(&input).unwrap();
+    for a in &args[1..] {
+        vm.input(i64::from_str(a).expect("Invalid input value"));
+    }
+    while vm.running() {
+        if vm.needs_input() {
+            eprintln!("Out of input");
+            std::process::exit(1);
+        }
+        println!("{}", disasm::trace(&vm));
+        match vm.try_step() {
+            Ok(Some(out)) => println!("      => {}", out),
+            Ok(None) => (),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+    println!("{}", disasm::trace(&vm));
+}
+
+fn profile(tape: &str, args: &[String]) {
+    let mut vm = Vm::from_str(tape).unwrap();
+    for a in args {
+        match i64::from_str(a) {
+            Ok(i) => vm.input(i),
+            Err(_) => vm.send_line(a),
+        }
+    }
+    vm.enable_profiling();
+    let result = vm.try_run();
+    if let Ok(out) = &result {
+        print!("{}", out.iter().cloned().collect::<AsciiOutput>());
+    }
+    eprint!("{}", vm.profile().unwrap().report(vm.tape()));
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
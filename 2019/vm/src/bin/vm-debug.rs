@@ -0,0 +1,249 @@
+//! Interactive debugger for Intcode programs.
+//!
+//! Usage: `vm-debug <tape>`, then type `help` at the prompt.
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{BufRead, Write};
+use std::str::FromStr;
+
+use vm::disasm::{self, Instruction};
+use vm::Vm;
+
+const HELP: &str = "\
+s, step [n]         execute n instructions (default 1)
+c, continue         run until a breakpoint, watchpoint, halt, or input wait
+b, break <ip>       set a breakpoint
+d, delete <ip>      remove a breakpoint
+w, watch <addr>     stop when the value at an address changes
+u, unwatch <addr>   remove a watchpoint
+l, list [addr] [n]  disassemble n instructions (default: at ip)
+x <addr> [n]        print n memory values
+set <addr> <value>  write to memory
+ip [value]          print or set the instruction pointer
+rb [value]          print or set the relative base
+in <value>...       queue input values
+ascii <text>        queue a line of ASCII input (with trailing newline)
+clear               discard queued input
+info                print machine state, breakpoints and watchpoints
+q, quit             exit";
+
+/// Largest count accepted by `list` and `x`, so that a typo can't print (or
+/// allocate) without bound
+const MAX_COUNT: usize = 1 << 16;
+
+struct Debugger {
+    vm: Vm,
+    breakpoints: BTreeSet<usize>,
+    watchpoints: BTreeMap<usize, i64>,
+}
+
+impl Debugger {
+    fn new(vm: Vm) -> Self {
+        Debugger {
+            vm,
+            breakpoints: BTreeSet::new(),
+            watchpoints: BTreeMap::new(),
+        }
+    }
+
+    fn mem(&self, addr: usize) -> i64 {
+        self.vm.peek(addr)
+    }
+
+    /// Executes one instruction, returning `false` if execution should stop
+    fn step(&mut self) -> bool {
+        if !self.vm.running() {
+            println!("Halted");
+            return false;
+        } else if self.vm.needs_input() {
+            println!("Waiting for input at {:04}", self.vm.ip());
+            return false;
+        }
+        match self.vm.try_step() {
+            Ok(Some(out)) if out > 0 && out < 128 => print!("{}", out as u8 as char),
+            Ok(Some(out)) => println!("[out {}]", out),
+            Ok(None) => (),
+            Err(e) => {
+                println!("Error: {}", e);
+                return false;
+            }
+        }
+        let mut ok = true;
+        let changed = self
+            .watchpoints
+            .iter()
+            .map(|(a, v)| (*a, *v, self.mem(*a)))
+            .filter(|(_, old, new)| old != new)
+            .collect::<Vec<_>>();
+        for (addr, old, new) in changed {
+            println!("Watchpoint {:04}: {} -> {}", addr, old, new);
+            self.watchpoints.insert(addr, new);
+            ok = false;
+        }
+        ok
+    }
+
+    fn list(&self, mut addr: usize, n: usize) {
+        let tape = self.vm.tape();
+        for _ in 0..n {
+            if addr >= tape.len() {
+                break;
+            }
+            let marker = match (addr == self.vm.ip(), self.breakpoints.contains(&addr)) {
+                (true, true) => "*>",
+                (true, false) => " >",
+                (false, true) => "* ",
+                (false, false) => "  ",
+            };
+            match Instruction::decode(tape, addr) {
+                Some(op) => {
+                    println!("{} {:04}: {}", marker, addr, op);
+                    addr += op.size();
+                }
+                None => {
+                    println!("{} {:04}: .data {}", marker, addr, tape[addr]);
+                    addr += 1;
+                }
+            }
+        }
+    }
+
+    fn info(&self) {
+        println!("ip: {:04}", self.vm.ip());
+        println!("rb: {}", self.vm.base());
+        println!("input: {:?}", self.vm.pending_input().collect::<Vec<_>>());
+        println!("breakpoints: {:?}", self.breakpoints);
+        println!("watchpoints: {:?}", self.watchpoints);
+        println!("next: {}", disasm::trace(&self.vm));
+    }
+
+    /// Runs a single command, returning `false` when it's time to quit
+    fn command(&mut self, line: &str) -> Result<bool, String> {
+        let words = line.split_whitespace().collect::<Vec<_>>();
+        let Some((cmd, args)) = words.split_first() else {
+            return Ok(true);
+        };
+        let arg = |i: usize| -> Result<Option<i64>, String> {
+            args.get(i)
+                .map(|a| i64::from_str(a).map_err(|e| format!("Invalid number {:?}: {}", a, e)))
+                .transpose()
+        };
+        let addr = |i: usize| -> Result<usize, String> {
+            match arg(i)? {
+                Some(a) if a >= 0 => Ok(a as usize),
+                Some(a) => Err(format!("Invalid address {}", a)),
+                None => Err("Missing address".to_owned()),
+            }
+        };
+        let count = |i: usize, default: usize| -> Result<usize, String> {
+            match arg(i)? {
+                Some(n) if n >= 0 && n as u64 <= MAX_COUNT as u64 => Ok(n as usize),
+                Some(n) => Err(format!("Invalid count {} (must be 0-{})", n, MAX_COUNT)),
+                None => Ok(default),
+            }
+        };
+        match *cmd {
+            "s" | "step" => {
+                for _ in 0..arg(0)?.unwrap_or(1) {
+                    if !self.step() {
+                        break;
+                    }
+                }
+                println!("{}", disasm::trace(&self.vm));
+            }
+            "c" | "continue" => {
+                // Always take one step, so we can continue past a breakpoint
+                while self.step() {
+                    if self.breakpoints.contains(&self.vm.ip()) {
+                        println!("Breakpoint at {:04}", self.vm.ip());
+                        break;
+                    }
+                }
+                println!("{}", disasm::trace(&self.vm));
+            }
+            "b" | "break" => {
+                self.breakpoints.insert(addr(0)?);
+            }
+            "d" | "delete" => {
+                self.breakpoints.remove(&addr(0)?);
+            }
+            "w" | "watch" => {
+                let a = addr(0)?;
+                self.watchpoints.insert(a, self.mem(a));
+            }
+            "u" | "unwatch" => {
+                self.watchpoints.remove(&addr(0)?);
+            }
+            "l" | "list" => {
+                let start = if args.is_empty() {
+                    self.vm.ip()
+                } else {
+                    addr(0)?
+                };
+                self.list(start, count(1, 10)?);
+            }
+            "x" => {
+                let a = addr(0)?;
+                let n = count(1, 1)?;
+                let end = a.checked_add(n).ok_or("Address range overflows")?;
+                for (i, chunk) in (a..end).collect::<Vec<_>>().chunks(8).enumerate() {
+                    let vs = chunk.iter().map(|a| self.mem(*a).to_string());
+                    println!("{:04}: {}", a + i * 8, vs.collect::<Vec<_>>().join(", "));
+                }
+            }
+            "set" => {
+                let a = addr(0)?;
+                let v = arg(1)?.ok_or("Missing value")?;
+                self.vm.try_poke(a, v).map_err(|e| e.to_string())?;
+            }
+            "ip" => match args.first() {
+                Some(_) => self.vm.set_ip(addr(0)?),
+                None => println!("{:04}", self.vm.ip()),
+            },
+            "rb" => match arg(0)? {
+                Some(v) => self.vm.set_base(v),
+                None => println!("{}", self.vm.base()),
+            },
+            "in" => {
+                for i in 0..args.len() {
+                    self.vm.input(arg(i)?.unwrap());
+                }
+            }
+            "ascii" => {
+                let text = line.trim()[cmd.len()..].trim_start();
+                for c in text.chars().chain(std::iter::once('\n')) {
+                    self.vm.input(c as i64);
+                }
+            }
+            "clear" => self.vm.clear_input(),
+            "info" => self.info(),
+            "h" | "help" => println!("{}", HELP),
+            "q" | "quit" => return Ok(false),
+            _ => return Err(format!("Unknown command {:?}; try 'help'", cmd)),
+        }
+        Ok(true)
+    }
+}
+
+fn main() {
+    let path = std::env::args().nth(1).expect("Usage: vm-debug <tape>");
+    let tape = std::fs::read_to_string(&path).expect("Could not read tape");
+    let vm = Vm::from_str(&tape).unwrap_or_else(|e| panic!("{}", e));
+
+    let mut dbg = Debugger::new(vm);
+    println!("{}", disasm::trace(&dbg.vm));
+
+    let stdin = std::io::stdin();
+    loop {
+        print!("(vm) ");
+        std::io::stdout().flush().unwrap();
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line).unwrap() == 0 {
+            break;
+        }
+        match dbg.command(&line) {
+            Ok(true) => (),
+            Ok(false) => break,
+            Err(e) => println!("{}", e),
+        }
+    }
+}
@@ -0,0 +1,497 @@
+//! Control-flow graphs of Intcode tapes.
+//!
+//! [`Cfg::new`] splits a tape into basic blocks, resolving jump targets that
+//! are immediate or stored in a position-mode cell that's only ever given
+//! constant values.  Calls (a jump after storing the return address on the
+//! stack) and returns (a jump through a relative-mode parameter) are
+//! recognized; any other jump that can't be resolved is marked as indirect.
+//!
+//! This is a best-effort analysis: it only sees writes made by code that it
+//! has found.  A relative-mode write could land on any cell, so if the code
+//! contains one, jumps through position-mode cells are left unresolved.
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+use std::ops::Range;
+
+use crate::disasm::{explore, walk, Instruction, Item, Operand};
+use crate::{OP_ADD, OP_BREAK, OP_EQ, OP_JIF, OP_JIT, OP_LT, OP_MUL};
+
+/// How control leaves a basic block
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Terminator {
+    /// Execution continues into the next block
+    Fallthrough,
+    /// A jump (possibly conditional) to known targets
+    Jump,
+    /// A jump to a function, after storing the return address on the stack
+    Call,
+    /// A jump through the stack, i.e. returning from a call
+    Return,
+    /// A jump whose target couldn't be resolved
+    Indirect,
+    Halt,
+    /// Execution runs into something that isn't code
+    Invalid,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum EdgeKind {
+    Fallthrough,
+    Jump,
+    Call,
+    /// From a call site to its return address
+    Return,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Block {
+    pub start: usize,
+    /// One past the last cell of the block
+    pub end: usize,
+    pub instructions: Vec<(usize, Instruction)>,
+    pub terminator: Terminator,
+    pub succs: Vec<(usize, EdgeKind)>,
+    /// Whether the program writes to this block's code
+    pub modified: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct Cfg {
+    pub blocks: BTreeMap<usize, Block>,
+    /// Regions of code that are written by the program
+    pub modified: Vec<Range<usize>>,
+}
+
+/// Returns the value written by an instruction, if it's a constant
+fn constant(op: &Instruction) -> Option<i64> {
+    let (a, b) = match op.params[..] {
+        [Operand::Immediate(a), Operand::Immediate(b), _] => (a, b),
+        _ => return None,
+    };
+    match op.opcode {
+        OP_ADD => a.checked_add(b),
+        OP_MUL => a.checked_mul(b),
+        OP_LT => Some((a < b) as i64),
+        OP_EQ => Some((a == b) as i64),
+        _ => None,
+    }
+}
+
+/// Returns the position-mode address written by an instruction, if any
+fn written(op: &Instruction) -> Option<usize> {
+    match (Instruction::shape(op.opcode), op.params.last()) {
+        (Some((_, true)), Some(Operand::Position(p))) if *p >= 0 => Some(*p as usize),
+        _ => None,
+    }
+}
+
+/// Checks whether an instruction writes through a relative-mode parameter
+fn writes_relative(op: &Instruction) -> bool {
+    matches!(
+        (Instruction::shape(op.opcode), op.params.last()),
+        (Some((_, true)), Some(Operand::Relative(_)))
+    )
+}
+
+/// Tracks what the program writes to each position-mode address
+struct Stores {
+    /// Addresses which are only ever given constant values
+    constant: BTreeMap<usize, BTreeSet<i64>>,
+    /// Addresses which are written with values we don't know
+    unknown: BTreeSet<usize>,
+    /// Whether any instruction writes through a relative-mode parameter,
+    /// which could change any cell
+    relative: bool,
+}
+
+impl Stores {
+    fn new(code: &BTreeMap<usize, Instruction>) -> Self {
+        let mut out = Stores {
+            constant: BTreeMap::new(),
+            unknown: BTreeSet::new(),
+            relative: false,
+        };
+        for op in code.values() {
+            out.relative |= writes_relative(op);
+            if let Some(a) = written(op) {
+                match constant(op) {
+                    Some(v) => {
+                        out.constant.entry(a).or_default().insert(v);
+                    }
+                    None => {
+                        out.unknown.insert(a);
+                    }
+                }
+            }
+        }
+        out
+    }
+
+    /// Returns every value that could be in a cell, or `None` if unknown
+    fn values(&self, tape: &[i64], addr: usize) -> Option<BTreeSet<i64>> {
+        if self.relative || self.unknown.contains(&addr) {
+            return None;
+        }
+        let mut out = self.constant.get(&addr).cloned().unwrap_or_default();
+        out.insert(tape.get(addr).copied().unwrap_or(0));
+        Some(out)
+    }
+
+    fn contains(&self, addr: usize) -> bool {
+        self.unknown.contains(&addr) || self.constant.contains_key(&addr)
+    }
+}
+
+/// Returns the possible targets of a jump, or `None` if they can't be
+/// resolved.  Negative targets are dropped, since jumping there is an error.
+fn targets(op: &Instruction, tape: &[i64], stores: &Stores) -> Option<Vec<usize>> {
+    let vs = match op.params[1] {
+        Operand::Immediate(t) => std::iter::once(t).collect(),
+        Operand::Position(p) if p >= 0 => stores.values(tape, p as usize)?,
+        _ => return None,
+    };
+    Some(
+        vs.into_iter()
+            .filter(|t| *t >= 0)
+            .map(|t| t as usize)
+            .collect(),
+    )
+}
+
+/// Checks whether a jump is always or never taken, based on an immediate
+/// condition
+fn taken(op: &Instruction) -> Option<bool> {
+    match (op.opcode, op.params[0]) {
+        (OP_JIT, Operand::Immediate(c)) => Some(c != 0),
+        (OP_JIF, Operand::Immediate(c)) => Some(c == 0),
+        _ => None,
+    }
+}
+
+impl Cfg {
+    pub fn new(tape: &[i64]) -> Self {
+        let mut code = walk(tape)
+            .into_iter()
+            .filter_map(|(a, item)| match item {
+                Item::Code(op) => Some((a, op)),
+                Item::Data(_) => None,
+            })
+            .collect::<BTreeMap<_, _>>();
+
+        // Follow position-mode jump targets until nothing new is found
+        let mut tried = BTreeSet::new();
+        let stores = loop {
+            let stores = Stores::new(&code);
+            let todo = code
+                .values()
+                .filter(|op| matches!(op.opcode, OP_JIT | OP_JIF))
+                .filter_map(|op| targets(op, tape, &stores))
+                .flatten()
+                .filter(|t| !code.contains_key(t) && tried.insert(*t))
+                .collect::<Vec<_>>();
+            if todo.is_empty() {
+                break stores;
+            }
+            explore(tape, &mut code, todo);
+        };
+
+        // Find block boundaries
+        let mut leaders = BTreeSet::new();
+        leaders.insert(0);
+        for (a, op) in &code {
+            if matches!(op.opcode, OP_JIT | OP_JIF | OP_BREAK) {
+                leaders.insert(a + op.size());
+            }
+            if matches!(op.opcode, OP_JIT | OP_JIF) {
+                leaders.extend(targets(op, tape, &stores).unwrap_or_default());
+            }
+        }
+
+        let modified_cells = code
+            .iter()
+            .flat_map(|(a, op)| *a..*a + op.size())
+            .filter(|i| stores.contains(*i))
+            .collect::<BTreeSet<_>>();
+
+        let mut blocks = BTreeMap::new();
+        let mut iter = code.iter().peekable();
+        while let Some((&start, op)) = iter.next() {
+            let mut instructions = vec![(start, op.clone())];
+            let mut end = start + op.size();
+            while let Some((&a, op)) = iter.peek() {
+                // Every jump and halt is followed by a leader
+                if a != end || leaders.contains(&a) {
+                    break;
+                }
+                instructions.push((a, (*op).clone()));
+                end = a + op.size();
+                iter.next();
+            }
+            let block = Self::terminate(start, end, instructions, tape, &code, &stores);
+            blocks.insert(start, block);
+        }
+        for b in blocks.values_mut() {
+            b.modified = (b.start..b.end).any(|i| modified_cells.contains(&i));
+        }
+
+        let mut modified: Vec<Range<usize>> = vec![];
+        for i in modified_cells {
+            match modified.last_mut() {
+                Some(r) if r.end == i => r.end += 1,
+                _ => modified.push(i..i + 1),
+            }
+        }
+        Cfg { blocks, modified }
+    }
+
+    /// Works out how control leaves a block
+    fn terminate(
+        start: usize,
+        end: usize,
+        instructions: Vec<(usize, Instruction)>,
+        tape: &[i64],
+        code: &BTreeMap<usize, Instruction>,
+        stores: &Stores,
+    ) -> Block {
+        let last = &instructions.last().unwrap().1;
+        let mut succs = vec![];
+        let fallthrough = |succs: &mut Vec<_>| {
+            if code.contains_key(&end) {
+                succs.push((end, EdgeKind::Fallthrough));
+                Terminator::Fallthrough
+            } else {
+                Terminator::Invalid
+            }
+        };
+        let terminator = match last.opcode {
+            OP_BREAK => Terminator::Halt,
+            OP_JIT | OP_JIF => {
+                let taken = taken(last);
+                let mut t = match (last.params[1], targets(last, tape, stores)) {
+                    _ if taken == Some(false) => Terminator::Jump,
+                    (Operand::Relative(_), _) => Terminator::Return,
+                    (_, None) => Terminator::Indirect,
+                    (_, Some(ts)) => {
+                        // A call stores its own return address on the stack
+                        // before jumping unconditionally
+                        let call = taken == Some(true)
+                            && instructions.iter().any(|(_, op)| {
+                                matches!(op.params.last(), Some(Operand::Relative(_)))
+                                    && constant(op) == Some(end as i64)
+                            });
+                        let kind = if call { EdgeKind::Call } else { EdgeKind::Jump };
+                        succs.extend(ts.into_iter().map(|t| (t, kind)));
+                        if call {
+                            succs.push((end, EdgeKind::Return));
+                            Terminator::Call
+                        } else {
+                            Terminator::Jump
+                        }
+                    }
+                };
+                if taken != Some(true) && fallthrough(&mut succs) == Terminator::Invalid {
+                    t = Terminator::Invalid;
+                }
+                t
+            }
+            _ => fallthrough(&mut succs),
+        };
+        Block {
+            start,
+            end,
+            instructions,
+            terminator,
+            succs,
+            modified: false,
+        }
+    }
+
+    /// Returns the block containing the given address
+    pub fn block(&self, addr: usize) -> Option<&Block> {
+        self.blocks
+            .range(..=addr)
+            .next_back()
+            .map(|(_, b)| b)
+            .filter(|b| addr < b.end)
+    }
+
+    /// Renders the graph in Graphviz's DOT format
+    pub fn to_dot(&self) -> String {
+        let mut out = String::new();
+        out.push_str("digraph cfg {\n");
+        out.push_str("    node [shape=box, fontname=\"monospace\"];\n");
+        for b in self.blocks.values() {
+            let mut label = String::new();
+            for (a, op) in &b.instructions {
+                write!(label, "{:04}: {}\\l", a, op).unwrap();
+            }
+            let mut attrs = vec![];
+            let mut styles = vec![];
+            match b.terminator {
+                Terminator::Indirect => {
+                    label += "(indirect jump)\\l";
+                    attrs.push("color=red");
+                }
+                Terminator::Invalid => {
+                    label += "(falls into data)\\l";
+                    attrs.push("color=red");
+                }
+                Terminator::Return => attrs.push("peripheries=2"),
+                Terminator::Halt => styles.push("bold"),
+                _ => (),
+            }
+            if b.modified {
+                label += "(self-modified)\\l";
+                styles.push("filled");
+                attrs.push("fillcolor=lightyellow");
+            }
+            let style = format!("style=\"{}\"", styles.join(","));
+            if !styles.is_empty() {
+                attrs.push(&style);
+            }
+            let attrs = attrs.iter().map(|a| format!(", {}", a)).collect::<String>();
+            writeln!(out, "    b{} [label=\"{}\"{}];", b.start, label, attrs).unwrap();
+        }
+        for b in self.blocks.values() {
+            for (to, kind) in &b.succs {
+                let style = match kind {
+                    EdgeKind::Fallthrough => "",
+                    EdgeKind::Jump => " [label=\"jump\"]",
+                    EdgeKind::Call => " [label=\"call\", color=blue]",
+                    EdgeKind::Return => " [label=\"return\", style=dashed]",
+                };
+                // Jumps into the middle of a block point at that block
+                let to = self.block(*to).map_or(*to, |t| t.start);
+                writeln!(out, "    b{} -> b{}{};", b.start, to, style).unwrap();
+            }
+        }
+        out.push_str("}\n");
+        out
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+
+    const CALLS: &str = "
+                arb #100
+                in n
+        loop:   add #ret, #0 -> [rb+0]
+                jt #1, #double
+        ret:    add k, #-1 -> k
+                jt k, #loop
+                jt #1, @vector
+        done:   out n
+                hlt
+        double: mul n, #2 -> n
+                jt #1, [rb+0]
+        n:      .data 0
+        k:      .data 3
+        vector: .data done
+    ";
+
+    #[test]
+    fn blocks() {
+        let cfg = Cfg::new(&assemble(CALLS).unwrap());
+        let starts = cfg.blocks.keys().copied().collect::<Vec<_>>();
+        assert_eq!(starts, vec![0, 4, 11, 18, 24]);
+
+        let b = &cfg.blocks;
+        assert_eq!(b[&0].terminator, Terminator::Fallthrough);
+        assert_eq!(b[&0].succs, vec![(4, EdgeKind::Fallthrough)]);
+        assert_eq!(b[&4].terminator, Terminator::Call);
+        assert_eq!(
+            b[&4].succs,
+            vec![(24, EdgeKind::Call), (11, EdgeKind::Return)]
+        );
+        assert_eq!(b[&11].terminator, Terminator::Jump);
+        assert_eq!(
+            b[&11].succs,
+            vec![(4, EdgeKind::Jump), (18, EdgeKind::Fallthrough)]
+        );
+        // The relative-mode write in the loop could change `vector`
+        assert_eq!(b[&18].terminator, Terminator::Indirect);
+        assert_eq!(b[&24].terminator, Terminator::Return);
+        assert!(cfg.modified.is_empty());
+        assert_eq!(cfg.block(26).unwrap().start, 24);
+        assert!(cfg.block(31).is_none());
+    }
+
+    #[test]
+    fn vector() {
+        let tape = assemble(
+            "
+                in n
+                jt n, @vector
+                hlt
+        done:   out n
+                hlt
+        n:      .data 0
+        vector: .data done
+        ",
+        )
+        .unwrap();
+        let cfg = Cfg::new(&tape);
+        let b = &cfg.blocks;
+        assert_eq!(b.keys().copied().collect::<Vec<_>>(), vec![0, 5, 6]);
+        // Resolved through the `vector` cell
+        assert_eq!(
+            b[&0].succs,
+            vec![(6, EdgeKind::Jump), (5, EdgeKind::Fallthrough)]
+        );
+        assert_eq!(b[&6].terminator, Terminator::Halt);
+
+        // A relative-mode write can change the cell without us noticing
+        let tape = assemble(
+            "
+                arb #vector
+                add #bad, #0 -> [rb+0]
+                jt #1, @vector
+        good:   hlt
+        bad:    out #1
+                hlt
+        vector: .data good
+        ",
+        )
+        .unwrap();
+        let cfg = Cfg::new(&tape);
+        assert_eq!(cfg.blocks[&0].terminator, Terminator::Indirect);
+        assert!(cfg.blocks[&0].succs.is_empty());
+    }
+
+    #[test]
+    fn unresolved() {
+        let tape = assemble(
+            "
+                in t
+                add #1, #0 -> @patch
+        patch:  out #5
+                jt #1, t
+        t:      .data 0
+        ",
+        )
+        .unwrap();
+        let cfg = Cfg::new(&tape);
+        assert_eq!(cfg.blocks.len(), 1);
+        let b = &cfg.blocks[&0];
+        assert_eq!(b.end, 11);
+        assert_eq!(b.terminator, Terminator::Indirect);
+        assert!(b.modified);
+        assert_eq!(cfg.modified, vec![6..7]);
+    }
+
+    #[test]
+    fn dot() {
+        let cfg = Cfg::new(&assemble(CALLS).unwrap());
+        let dot = cfg.to_dot();
+        assert!(dot.starts_with("digraph cfg {\n"));
+        assert!(dot.contains("b4 -> b24 [label=\"call\", color=blue];"));
+        assert!(dot.contains("b4 -> b11 [label=\"return\", style=dashed];"));
+        assert!(dot.contains("b0 -> b4;"));
+        assert!(dot.contains("0024: MUL @31, #2 -> @31\\l"));
+    }
+}
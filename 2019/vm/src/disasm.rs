@@ -0,0 +1,315 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+use crate::{
+    Vm, MODE_IMMEDIATE, MODE_POSITION, MODE_RELATIVE, OP_ADD, OP_BREAK, OP_EQ, OP_INPUT, OP_JIF,
+    OP_JIT, OP_LT, OP_MUL, OP_OUTPUT, OP_RBO,
+};
+
+/// Number of values printed on each line of a `.data` run
+const DATA_PER_LINE: usize = 8;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Operand {
+    Position(i64),
+    Immediate(i64),
+    Relative(i64),
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Operand::Position(p) => write!(f, "@{}", p),
+            Operand::Immediate(i) => write!(f, "#{}", i),
+            Operand::Relative(r) if r < 0 => write!(f, "[rb-{}]", -(r as i128)),
+            Operand::Relative(r) => write!(f, "[rb+{}]", r),
+        }
+    }
+}
+
+/// A single decoded instruction
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Instruction {
+    pub opcode: i64,
+    pub params: Vec<Operand>,
+}
+
+impl Instruction {
+    /// Returns the number of parameters and whether the last one is
+    /// written to, or `None` if this isn't a valid opcode.
+    pub(crate) fn shape(opcode: i64) -> Option<(usize, bool)> {
+        match opcode {
+            OP_ADD | OP_MUL | OP_LT | OP_EQ => Some((3, true)),
+            OP_INPUT => Some((1, true)),
+            OP_OUTPUT | OP_RBO => Some((1, false)),
+            OP_JIT | OP_JIF => Some((2, false)),
+            OP_BREAK => Some((0, false)),
+            _ => None,
+        }
+    }
+
+    /// Decodes the instruction at the given address.
+    ///
+    /// Returns `None` if the value there isn't a well-formed instruction,
+    /// i.e. it has an unknown opcode, invalid or unused mode digits, an
+    /// immediate-mode output, or runs off the end of the tape.
+    pub fn decode(mem: &[i64], ip: usize) -> Option<Self> {
+        let instruction = *mem.get(ip)?;
+        if instruction < 0 {
+            return None;
+        }
+        let opcode = instruction % 100;
+        let (n, writes) = Self::shape(opcode)?;
+        if instruction / 10_i64.pow(n as u32 + 2) != 0 {
+            return None;
+        }
+        let mut params = Vec::with_capacity(n);
+        for i in 1..=n {
+            let v = *mem.get(ip + i)?;
+            let op = match (instruction / 10_i64.pow(i as u32 + 1)) % 10 {
+                MODE_POSITION => Operand::Position(v),
+                MODE_IMMEDIATE if !(writes && i == n) => Operand::Immediate(v),
+                MODE_RELATIVE => Operand::Relative(v),
+                _ => return None,
+            };
+            params.push(op);
+        }
+        Some(Instruction { opcode, params })
+    }
+
+    /// Returns the number of tape cells used by this instruction
+    pub fn size(&self) -> usize {
+        self.params.len() + 1
+    }
+
+    pub fn mnemonic(&self) -> &'static str {
+        match self.opcode {
+            OP_ADD => "ADD",
+            OP_MUL => "MUL",
+            OP_INPUT => "IN",
+            OP_OUTPUT => "OUT",
+            OP_JIT => "JT",
+            OP_JIF => "JF",
+            OP_LT => "LT",
+            OP_EQ => "EQ",
+            OP_RBO => "ARB",
+            OP_BREAK => "HLT",
+            _ => unreachable!(),
+        }
+    }
+
+    /// Returns the jump target, if this is a jump with an immediate target
+    pub fn target(&self) -> Option<usize> {
+        match (self.opcode, self.params.get(1)) {
+            (OP_JIT, Some(Operand::Immediate(t))) | (OP_JIF, Some(Operand::Immediate(t)))
+                if *t >= 0 =>
+            {
+                Some(*t as usize)
+            }
+            _ => None,
+        }
+    }
+
+    /// Checks whether execution can continue to the next instruction
+    pub fn falls_through(&self) -> bool {
+        match (self.opcode, self.params.first()) {
+            (OP_BREAK, _) => false,
+            (OP_JIT, Some(Operand::Immediate(c))) => *c == 0,
+            (OP_JIF, Some(Operand::Immediate(c))) => *c != 0,
+            _ => true,
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.mnemonic())?;
+        let (n, writes) = Self::shape(self.opcode).unwrap();
+        let inputs = if writes { n - 1 } else { n };
+        for (i, p) in self.params[..inputs].iter().enumerate() {
+            write!(f, "{}{}", if i == 0 { " " } else { ", " }, p)?;
+        }
+        if writes {
+            write!(f, " -> {}", self.params[n - 1])?;
+        }
+        Ok(())
+    }
+}
+
+/// Decodes code reachable from the addresses in `todo`, following
+/// immediate jump targets and fallthrough, and adds it to `code`.
+///
+/// Instructions that would overlap existing code are skipped.
+pub(crate) fn explore(mem: &[i64], code: &mut BTreeMap<usize, Instruction>, mut todo: Vec<usize>) {
+    while let Some(ip) = todo.pop() {
+        if code.contains_key(&ip) {
+            continue;
+        }
+        let Some(op) = Instruction::decode(mem, ip) else {
+            continue;
+        };
+        if code.range(ip + 1..ip + op.size()).next().is_some()
+            || code
+                .range(..ip)
+                .next_back()
+                .is_some_and(|(a, prev)| a + prev.size() > ip)
+        {
+            continue;
+        }
+        if let Some(t) = op.target() {
+            todo.push(t);
+        }
+        if op.falls_through() {
+            todo.push(ip + op.size());
+        }
+        code.insert(ip, op);
+    }
+}
+
+/// A region of the tape, as found by [`walk`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Item {
+    Code(Instruction),
+    Data(Vec<i64>),
+}
+
+/// Splits a tape into code and data, keyed by starting address.
+///
+/// Code is found by following execution from `ip = 0`, along with any
+/// immediate jump targets.  Return addresses (immediate values pointing
+/// just past an unconditional jump) are also treated as entry points, so
+/// that code after a function call is found.  Everything else is data.
+pub fn walk(mem: &[i64]) -> BTreeMap<usize, Item> {
+    let mut code = BTreeMap::new();
+    let mut tried = BTreeSet::new();
+    let mut todo = vec![0];
+    loop {
+        explore(mem, &mut code, todo);
+        let immediates = code
+            .values()
+            .flat_map(|op| op.params.iter())
+            .filter_map(|p| match p {
+                Operand::Immediate(i) if *i >= 0 => Some(*i as usize),
+                _ => None,
+            })
+            .collect::<BTreeSet<_>>();
+        todo = code
+            .iter()
+            .filter(|(_, op)| !op.falls_through())
+            .map(|(a, op)| a + op.size())
+            .filter(|a| immediates.contains(a) && !code.contains_key(a) && tried.insert(*a))
+            .collect();
+        if todo.is_empty() {
+            break;
+        }
+    }
+
+    let mut out = BTreeMap::new();
+    let mut ip = 0;
+    while ip < mem.len() {
+        if let Some(op) = code.remove(&ip) {
+            ip += op.size();
+            out.insert(ip - op.size(), Item::Code(op));
+        } else {
+            let start = ip;
+            while ip < mem.len() && !code.contains_key(&ip) {
+                ip += 1;
+            }
+            out.insert(start, Item::Data(mem[start..ip].to_vec()));
+        }
+    }
+    out
+}
+
+/// Builds a listing of the given tape
+pub fn disassemble(mem: &[i64]) -> String {
+    let items = walk(mem);
+
+    let mut sources: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+    for (addr, item) in &items {
+        if let Item::Code(op) = item {
+            if let Some(t) = op.target() {
+                sources.entry(t).or_default().push(*addr);
+            }
+        }
+    }
+
+    let mut out = String::new();
+    for (addr, item) in &items {
+        match item {
+            Item::Code(op) => {
+                let line = format!("{:04}: {}", addr, op);
+                if let Some(s) = sources.get(addr) {
+                    let s = s.iter().map(|s| format!("{:04}", s)).collect::<Vec<_>>();
+                    out += &format!("{:<40}; from {}\n", line, s.join(", "));
+                } else {
+                    out += &format!("{}\n", line);
+                }
+            }
+            Item::Data(d) => {
+                for (i, chunk) in d.chunks(DATA_PER_LINE).enumerate() {
+                    let vs = chunk.iter().map(|v| v.to_string()).collect::<Vec<_>>();
+                    out += &format!("{:04}: .data {}\n", addr + i * DATA_PER_LINE, vs.join(", "));
+                }
+            }
+        }
+    }
+    out
+}
+
+/// Formats the instruction that the VM is about to execute, in the same
+/// format as [`disassemble`]
+pub fn trace(vm: &Vm) -> String {
+    match Instruction::decode(vm.tape(), vm.ip()) {
+        Some(op) => format!("{:04}: {}", vm.ip(), op),
+        None => format!("{:04}: .data {}", vm.ip(), vm.peek(vm.ip())),
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn operands() {
+        let op = Instruction::decode(&[11101, 3, 5, 17], 0);
+        assert_eq!(op, None);
+        let op = Instruction::decode(&[1001, 3, 5, 17], 0).unwrap();
+        assert_eq!(op.to_string(), "ADD @3, #5 -> @17");
+        let op = Instruction::decode(&[1201, 3, 5, 17], 0).unwrap();
+        assert_eq!(op.to_string(), "ADD [rb+3], #5 -> @17");
+        let op = Instruction::decode(&[21201, 3, 5, 17], 0).unwrap();
+        assert_eq!(op.to_string(), "ADD [rb+3], #5 -> [rb+17]");
+        let op = Instruction::decode(&[204, -1], 0).unwrap();
+        assert_eq!(op.to_string(), "OUT [rb-1]");
+        assert_eq!(Instruction::decode(&[1, 2, 3], 0), None);
+    }
+
+    #[test]
+    fn listing() {
+        // Outputs 1 if the input is non-zero
+        let prog = [3, 3, 1105, -1, 9, 1101, 0, 0, 12, 4, 12, 99, 1];
+        assert_eq!(
+            disassemble(&prog),
+            "0000: IN -> @3\n\
+             0002: JT #-1, #9\n\
+             0005: .data 1101, 0, 0, 12\n\
+             0009: OUT @12                           ; from 0002\n\
+             0011: HLT\n\
+             0012: .data 1\n"
+        );
+    }
+
+    #[test]
+    fn return_address() {
+        // Pushes a return address, calls a function at 9, then halts
+        let prog = [21101, 7, 0, 0, 1105, 1, 9, 99, 0, 104, 5, 2105, 1, 0];
+        let items = walk(&prog);
+        assert!(matches!(items[&7], Item::Code(ref op) if op.opcode == OP_BREAK));
+        assert!(matches!(items[&8], Item::Data(ref d) if d == &[0]));
+        assert!(matches!(items[&9], Item::Code(ref op) if op.opcode == OP_OUTPUT));
+        assert!(matches!(items[&11], Item::Code(ref op) if op.opcode == OP_JIT));
+    }
+}
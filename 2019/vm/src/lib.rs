@@ -4,7 +4,20 @@ extern crate quickcheck;
 #[macro_use(quickcheck)]
 extern crate quickcheck_macros;
 
+pub mod aot;
+pub mod ascii;
+pub mod asm;
+pub mod cfg;
+pub mod disasm;
+mod memory;
+pub mod network;
+pub mod profile;
+mod snapshot;
+
+pub use memory::DEFAULT_MEMORY_LIMIT;
+
 use std::collections::VecDeque;
+use std::num::ParseIntError;
 use std::str::FromStr;
 
 const OP_ADD: i64 = 1;
@@ -22,23 +35,197 @@ const MODE_POSITION: i64 = 0;
 const MODE_IMMEDIATE: i64 = 1;
 const MODE_RELATIVE: i64 = 2;
 
+/// Errors that can occur while executing a tape
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum VmError {
+    /// The low two digits of the instruction aren't a known opcode
+    InvalidOpcode { ip: usize, instruction: i64 },
+    /// A parameter's mode digit isn't position, immediate, or relative
+    InvalidMode {
+        ip: usize,
+        instruction: i64,
+        param: u32,
+        mode: i64,
+    },
+    /// A parameter or jump target resolved to a negative address
+    NegativeAddress {
+        ip: usize,
+        instruction: i64,
+        address: i64,
+    },
+    /// The program wanted input, but the input queue was empty
+    InputStarved { ip: usize, instruction: i64 },
+    /// An output parameter was given in immediate mode
+    ImmediateWrite {
+        ip: usize,
+        instruction: i64,
+        param: u32,
+    },
+    /// A write would have exceeded the memory limit
+    MemoryLimit {
+        ip: usize,
+        instruction: i64,
+        address: usize,
+    },
+}
+
+impl VmError {
+    /// Returns the instruction pointer at which the error occurred
+    pub fn ip(&self) -> usize {
+        match *self {
+            VmError::InvalidOpcode { ip, .. }
+            | VmError::InvalidMode { ip, .. }
+            | VmError::NegativeAddress { ip, .. }
+            | VmError::InputStarved { ip, .. }
+            | VmError::ImmediateWrite { ip, .. }
+            | VmError::MemoryLimit { ip, .. } => ip,
+        }
+    }
+
+    /// Returns the raw instruction that caused the error
+    pub fn instruction(&self) -> i64 {
+        match *self {
+            VmError::InvalidOpcode { instruction, .. }
+            | VmError::InvalidMode { instruction, .. }
+            | VmError::NegativeAddress { instruction, .. }
+            | VmError::InputStarved { instruction, .. }
+            | VmError::ImmediateWrite { instruction, .. }
+            | VmError::MemoryLimit { instruction, .. } => instruction,
+        }
+    }
+}
+
+impl std::fmt::Display for VmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            VmError::InvalidOpcode { ip, instruction } => {
+                write!(f, "Invalid opcode {} at {}", instruction, ip)
+            }
+            VmError::InvalidMode {
+                ip,
+                instruction,
+                param,
+                mode,
+            } => write!(
+                f,
+                "Invalid mode {} for parameter {} of {} at {}",
+                mode, param, instruction, ip
+            ),
+            VmError::NegativeAddress {
+                ip,
+                instruction,
+                address,
+            } => write!(
+                f,
+                "Negative address {} used by {} at {}",
+                address, instruction, ip
+            ),
+            VmError::InputStarved { ip, instruction } => {
+                write!(f, "Ran out of input for {} at {}", instruction, ip)
+            }
+            VmError::ImmediateWrite {
+                ip,
+                instruction,
+                param,
+            } => write!(
+                f,
+                "Immediate-mode write to parameter {} of {} at {}",
+                param, instruction, ip
+            ),
+            VmError::MemoryLimit {
+                ip,
+                instruction,
+                address,
+            } => write!(
+                f,
+                "Memory limit exceeded writing to {} with {} at {}",
+                address, instruction, ip
+            ),
+        }
+    }
+}
+
+impl std::error::Error for VmError {}
+
+/// Error returned when a tape can't be parsed
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseError {
+    /// Index of the failing token in the comma-separated tape
+    pub index: usize,
+    /// The failing token itself
+    pub token: String,
+    pub err: ParseIntError,
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "Could not parse token {} ({:?}): {}",
+            self.index, self.token, self.err
+        )
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// An instruction's opcode and parameter modes, cached by address so
+/// that hot loops don't repeatedly split the instruction into digits
+#[derive(Copy, Clone)]
+struct Decoded {
+    opcode: u8,
+    modes: [u8; 3],
+}
+
+impl Decoded {
+    fn new(instruction: i64) -> Option<Self> {
+        if instruction < 0 {
+            return None;
+        }
+        let modes = instruction / 100;
+        Some(Decoded {
+            opcode: (instruction % 100) as u8,
+            modes: [
+                (modes % 10) as u8,
+                (modes / 10 % 10) as u8,
+                (modes / 100 % 10) as u8,
+            ],
+        })
+    }
+}
+
+/// Result of executing a single instruction
+enum Status {
+    Continue,
+    Output(i64),
+    Blocked,
+    Halted,
+}
+
 #[derive(Clone)]
 pub struct Vm {
-    mem: Vec<i64>,
+    mem: memory::Memory,
     ip: usize,
     input: VecDeque<i64>,
     base: i64,
+    profile: Option<Box<profile::Profile>>,
 }
 
 impl FromStr for Vm {
-    type Err = ();
+    type Err = ParseError;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         Ok(Self::new(
             &s.trim()
                 .split(',')
-                .map(|i| i64::from_str(i))
-                .map(|r| r.expect("Could not parse int"))
-                .collect::<Vec<_>>(),
+                .enumerate()
+                .map(|(index, t)| {
+                    i64::from_str(t).map_err(|err| ParseError {
+                        index,
+                        token: t.to_owned(),
+                        err,
+                    })
+                })
+                .collect::<Result<Vec<_>, _>>()?,
         ))
     }
 }
@@ -46,125 +233,271 @@ impl FromStr for Vm {
 impl Vm {
     pub fn new(mem: &[i64]) -> Self {
         Self {
-            mem: mem.to_vec(),
+            mem: memory::Memory::new(mem),
             ip: 0,
             input: VecDeque::new(),
             base: 0,
+            profile: None,
         }
     }
 
     pub fn running(&self) -> bool {
-        self.mem[self.ip] != OP_BREAK
+        self.mem.read(self.ip) != OP_BREAK
     }
 
     pub fn needs_input(&self) -> bool {
-        self.mem[self.ip] % 100 == OP_INPUT && self.input.is_empty()
+        self.mem.read(self.ip) % 100 == OP_INPUT && self.input.is_empty()
+    }
+
+    fn read(&self, index: usize) -> i64 {
+        self.mem.read(index)
+    }
+
+    /// Reads a parameter's value from memory, for profiling
+    fn read_param(&mut self, index: usize) -> i64 {
+        if let Some(p) = &mut self.profile {
+            p.read(index);
+        }
+        self.mem.read(index)
+    }
+
+    /// Writes to memory, invalidating any cached decoding of the target
+    /// cell (since it may be self-modifying code).
+    fn write(&mut self, index: usize, v: i64) -> Result<(), VmError> {
+        if let Some(p) = &mut self.profile {
+            p.write(index);
+        }
+        if self.mem.write(index, v) {
+            Ok(())
+        } else {
+            Err(VmError::MemoryLimit {
+                ip: self.ip,
+                instruction: self.read(self.ip),
+                address: index,
+            })
+        }
     }
 
-    fn get(&mut self, index: usize) -> &mut i64 {
-        if index >= self.mem.len() {
-            self.mem.resize(index + 1, 0);
+    /// Decodes the instruction at `ip`, using the cache if possible
+    fn decode(&mut self) -> Result<Decoded, VmError> {
+        if let Some(d) = self.mem.decoded(self.ip) {
+            return Ok(d);
         }
-        &mut self.mem[index]
-    }
-
-    fn param(&mut self, index: u32) -> &mut i64 {
-        let m = (self.mem[self.ip] / 10_i64.pow(index + 1)) % 10;
-        let arg = self.ip + index as usize;
-        let pos = self.mem[arg];
-        match m {
-            MODE_POSITION => self.get(pos as usize),
-            MODE_IMMEDIATE => &mut self.mem[arg],
-            MODE_RELATIVE => self.get((pos + self.base) as usize),
-            _ => panic!(),
+        let instruction = self.read(self.ip);
+        let d = Decoded::new(instruction).ok_or(VmError::InvalidOpcode {
+            ip: self.ip,
+            instruction,
+        })?;
+        self.mem.cache(self.ip, d);
+        Ok(d)
+    }
+
+    fn addr(&self, address: i64) -> Result<usize, VmError> {
+        if address < 0 {
+            Err(VmError::NegativeAddress {
+                ip: self.ip,
+                instruction: self.read(self.ip),
+                address,
+            })
+        } else {
+            Ok(address as usize)
         }
     }
 
+    fn invalid_mode(&self, index: usize, mode: i64) -> VmError {
+        VmError::InvalidMode {
+            ip: self.ip,
+            instruction: self.read(self.ip),
+            param: index as u32,
+            mode,
+        }
+    }
+
+    /// Reads the value of a parameter
+    fn param(&mut self, d: Decoded, index: usize) -> Result<i64, VmError> {
+        let arg = self.read(self.ip + index);
+        match d.modes[index - 1] as i64 {
+            MODE_POSITION => Ok(self.read_param(self.addr(arg)?)),
+            MODE_IMMEDIATE => Ok(arg),
+            MODE_RELATIVE => Ok(self.read_param(self.addr(arg.wrapping_add(self.base))?)),
+            mode => Err(self.invalid_mode(index, mode)),
+        }
+    }
+
+    /// Writes to a parameter, which can't be in immediate mode
+    fn dest(&mut self, d: Decoded, index: usize, v: i64) -> Result<(), VmError> {
+        let arg = self.read(self.ip + index);
+        let addr = match d.modes[index - 1] as i64 {
+            MODE_POSITION => self.addr(arg)?,
+            MODE_RELATIVE => self.addr(arg.wrapping_add(self.base))?,
+            MODE_IMMEDIATE => {
+                return Err(VmError::ImmediateWrite {
+                    ip: self.ip,
+                    instruction: self.read(self.ip),
+                    param: index as u32,
+                })
+            }
+            mode => return Err(self.invalid_mode(index, mode)),
+        };
+        self.write(addr, v)
+    }
+
+    fn jump(&mut self, d: Decoded, index: usize) -> Result<(), VmError> {
+        let target = self.param(d, index)?;
+        let from = self.ip;
+        self.ip = self.addr(target)?;
+        if let Some(p) = &mut self.profile {
+            p.jump(from, self.ip);
+        }
+        Ok(())
+    }
+
     pub fn input(&mut self, i: i64) {
         self.input.push_front(i);
     }
 
-    pub fn step(&mut self) -> Option<i64> {
-        let opcode = self.mem[self.ip] % 100;
-        match opcode {
+    /// Executes a single instruction, recording it in the profile once it
+    /// has completed (so a blocked input or repeated halt isn't counted)
+    fn exec(&mut self) -> Result<Status, VmError> {
+        let ip = self.ip;
+        let d = self.decode()?;
+        let status = self.dispatch(d)?;
+        if let Some(p) = &mut self.profile {
+            match status {
+                Status::Blocked => (),
+                Status::Halted => p.halt(ip),
+                Status::Continue | Status::Output(_) => p.hit(ip, d.opcode as i64),
+            }
+        }
+        Ok(status)
+    }
+
+    fn dispatch(&mut self, d: Decoded) -> Result<Status, VmError> {
+        match d.opcode as i64 {
             OP_ADD => {
-                let lhs = *self.param(1);
-                let rhs = *self.param(2);
-                *self.param(3) = lhs + rhs;
+                let lhs = self.param(d, 1)?;
+                let rhs = self.param(d, 2)?;
+                self.dest(d, 3, lhs.wrapping_add(rhs))?;
                 self.ip += 4;
             }
             OP_MUL => {
-                let lhs = *self.param(1);
-                let rhs = *self.param(2);
-                *self.param(3) = lhs * rhs;
+                let lhs = self.param(d, 1)?;
+                let rhs = self.param(d, 2)?;
+                self.dest(d, 3, lhs.wrapping_mul(rhs))?;
                 self.ip += 4;
             }
-            OP_INPUT => {
-                if let Some(i) = self.input.pop_back() {
-                    *self.param(1) = i;
+            OP_INPUT => match self.input.back() {
+                Some(&i) => {
+                    self.dest(d, 1, i)?;
+                    self.input.pop_back();
                     self.ip += 2;
                 }
-            }
+                None => return Ok(Status::Blocked),
+            },
             OP_OUTPUT => {
-                let out = *self.param(1);
+                let out = self.param(d, 1)?;
                 self.ip += 2;
-                return Some(out);
+                return Ok(Status::Output(out));
             }
             OP_JIT => {
-                if *self.param(1) != 0 {
-                    self.ip = *self.param(2) as usize;
+                if self.param(d, 1)? != 0 {
+                    self.jump(d, 2)?;
                 } else {
                     self.ip += 3;
                 }
             }
             OP_JIF => {
-                if *self.param(1) == 0 {
-                    self.ip = *self.param(2) as usize;
+                if self.param(d, 1)? == 0 {
+                    self.jump(d, 2)?;
                 } else {
                     self.ip += 3;
                 }
             }
             OP_LT => {
-                let lhs = *self.param(1);
-                let rhs = *self.param(2);
-                *self.param(3) = (lhs < rhs) as i64;
+                let lhs = self.param(d, 1)?;
+                let rhs = self.param(d, 2)?;
+                self.dest(d, 3, (lhs < rhs) as i64)?;
                 self.ip += 4;
             }
             OP_EQ => {
-                let lhs = *self.param(1);
-                let rhs = *self.param(2);
-                *self.param(3) = (lhs == rhs) as i64;
+                let lhs = self.param(d, 1)?;
+                let rhs = self.param(d, 2)?;
+                self.dest(d, 3, (lhs == rhs) as i64)?;
                 self.ip += 4;
             }
             OP_RBO => {
-                self.base += *self.param(1);
+                self.base = self.base.wrapping_add(self.param(d, 1)?);
                 self.ip += 2;
             }
-            OP_BREAK => (),
-            _ => panic!("Invalid opcode {}", opcode),
+            OP_BREAK => return Ok(Status::Halted),
+            _ => {
+                return Err(VmError::InvalidOpcode {
+                    ip: self.ip,
+                    instruction: self.read(self.ip),
+                })
+            }
         };
-        None
+        Ok(Status::Continue)
+    }
+
+    /// Executes a single instruction, returning its output (if any).
+    ///
+    /// If the VM is waiting on input and the input queue is empty, this
+    /// does nothing and returns `Ok(None)`; use [`Vm::needs_input`] to
+    /// check for that case.
+    pub fn try_step(&mut self) -> Result<Option<i64>, VmError> {
+        match self.exec()? {
+            Status::Output(i) => Ok(Some(i)),
+            _ => Ok(None),
+        }
     }
 
-    pub fn run(&mut self) -> Vec<i64> {
+    pub fn step(&mut self) -> Option<i64> {
+        self.try_step().unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    fn starved(&self) -> VmError {
+        VmError::InputStarved {
+            ip: self.ip,
+            instruction: self.read(self.ip),
+        }
+    }
+
+    /// Runs until the program halts, returning all of its output.
+    ///
+    /// Unlike [`Vm::try_step`], running out of input is an error here.
+    pub fn try_run(&mut self) -> Result<Vec<i64>, VmError> {
         let mut out = Vec::new();
-        while self.running() {
-            assert!(!self.needs_input());
-            if let Some(i) = self.step() {
-                out.push(i);
+        loop {
+            match self.exec()? {
+                Status::Continue => (),
+                Status::Output(i) => out.push(i),
+                Status::Blocked => return Err(self.starved()),
+                Status::Halted => return Ok(out),
             }
         }
-        out
     }
 
-    pub fn run_until(&mut self) -> Option<i64> {
-        while self.running() {
-            assert!(!self.needs_input());
-            if let Some(i) = self.step() {
-                return Some(i);
+    /// Runs until the program produces an output or halts.
+    ///
+    /// Unlike [`Vm::try_step`], running out of input is an error here.
+    pub fn try_run_until(&mut self) -> Result<Option<i64>, VmError> {
+        loop {
+            match self.exec()? {
+                Status::Continue => (),
+                Status::Output(i) => return Ok(Some(i)),
+                Status::Blocked => return Err(self.starved()),
+                Status::Halted => return Ok(None),
             }
         }
-        None
+    }
+
+    pub fn run(&mut self) -> Vec<i64> {
+        self.try_run().unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn run_until(&mut self) -> Option<i64> {
+        self.try_run_until().unwrap_or_else(|e| panic!("{}", e))
     }
 
     pub fn run_until_with(&mut self, input: i64) -> Option<i64> {
@@ -183,12 +516,68 @@ impl Vm {
         self.step()
     }
 
+    /// Returns the VM's memory, including any cells that have been
+    /// written past the end of the original tape
+    ///
+    /// Values written far past the end are stored sparsely, and aren't
+    /// included here; use [`Vm::peek`] to read them.
+    pub fn tape(&self) -> &[i64] {
+        self.mem.dense()
+    }
+
+    /// Reads from memory, returning zero for cells that were never written
     pub fn peek(&self, i: usize) -> i64 {
-        self.mem[i]
+        self.mem.read(i)
+    }
+
+    /// Writes to memory, failing with [`VmError::MemoryLimit`] if that would
+    /// exceed the memory limit
+    pub fn try_poke(&mut self, i: usize, v: i64) -> Result<(), VmError> {
+        self.write(i, v)
     }
 
+    /// Writes to memory, panicking if that would exceed the memory limit
     pub fn poke(&mut self, i: usize, v: i64) {
-        self.mem[i] = v;
+        self.try_poke(i, v).unwrap_or_else(|e| panic!("{}", e));
+    }
+
+    /// Sets the maximum number of memory cells that the VM may allocate
+    /// (by default, [`DEFAULT_MEMORY_LIMIT`]).  Writes that would exceed
+    /// this limit fail with [`VmError::MemoryLimit`].
+    pub fn set_memory_limit(&mut self, cells: usize) {
+        self.mem.set_limit(cells);
+    }
+
+    /// Enables or disables caching of decoded instructions (which is on by
+    /// default).  Results are the same either way; this only exists so that
+    /// the cache can be benchmarked.
+    pub fn set_decode_cache(&mut self, enabled: bool) {
+        self.mem.set_caching(enabled);
+    }
+
+    pub fn ip(&self) -> usize {
+        self.ip
+    }
+
+    pub fn set_ip(&mut self, ip: usize) {
+        self.ip = ip;
+    }
+
+    pub fn base(&self) -> i64 {
+        self.base
+    }
+
+    pub fn set_base(&mut self, base: i64) {
+        self.base = base;
+    }
+
+    /// Returns queued input values, in the order they'll be consumed
+    pub fn pending_input(&self) -> impl Iterator<Item = i64> + '_ {
+        self.input.iter().rev().copied()
+    }
+
+    pub fn clear_input(&mut self) {
+        self.input.clear();
     }
 }
 
@@ -209,9 +598,9 @@ mod tests {
                 vec![30, 1, 1, 4, 2, 5, 6, 0, 99],
             ),
         ] {
-            let mut vm = Vm::new(&tape);
+            let mut vm = Vm::new(tape);
             vm.run();
-            assert_eq!(vm.mem[..tape.len()], output[..]);
+            assert_eq!(vm.tape()[..tape.len()], output[..]);
         }
     }
 
@@ -362,4 +751,130 @@ mod tests {
         v.input(10);
         assert_eq!(v.run(), vec![1, 1, 2, 3, 5, 8, 13, 21, 34, 55]);
     }
+
+    #[test]
+    fn errors() {
+        let mut v = Vm::new(&[42]);
+        assert_eq!(
+            v.try_step(),
+            Err(VmError::InvalidOpcode {
+                ip: 0,
+                instruction: 42
+            })
+        );
+
+        let mut v = Vm::new(&[1, 0, 0, 0, 301, 0, 0, 0, 99]);
+        assert_eq!(v.try_step(), Ok(None));
+        assert_eq!(
+            v.try_step(),
+            Err(VmError::InvalidMode {
+                ip: 4,
+                instruction: 301,
+                param: 1,
+                mode: 3,
+            })
+        );
+
+        let mut v = Vm::new(&[4, -3, 99]);
+        assert_eq!(
+            v.try_run(),
+            Err(VmError::NegativeAddress {
+                ip: 0,
+                instruction: 4,
+                address: -3,
+            })
+        );
+
+        let mut v = Vm::new(&[1105, 1, -1, 99]);
+        assert_eq!(v.try_step().unwrap_err().ip(), 0);
+
+        let mut v = Vm::new(&[3, 0, 99]);
+        assert_eq!(v.try_step(), Ok(None));
+        assert_eq!(
+            v.try_run(),
+            Err(VmError::InputStarved {
+                ip: 0,
+                instruction: 3
+            })
+        );
+
+        let mut v = Vm::new(&[11101, 1, 1, 0, 99]);
+        assert_eq!(
+            v.try_run_until(),
+            Err(VmError::ImmediateWrite {
+                ip: 0,
+                instruction: 11101,
+                param: 3,
+            })
+        );
+    }
+
+    #[test]
+    fn parse_error() {
+        let err = Vm::from_str("1,0,x0,0,99").err().unwrap();
+        assert_eq!(err.index, 2);
+        assert_eq!(err.token, "x0");
+        assert!(Vm::from_str("1,0,0,0,99\n").is_ok());
+    }
+
+    #[test]
+    fn state() {
+        let mut v = Vm::new(&[109, 5, 3, 0, 99]);
+        v.input(1);
+        v.input(2);
+        assert_eq!(v.pending_input().collect::<Vec<_>>(), vec![1, 2]);
+        v.step();
+        assert_eq!((v.ip(), v.base()), (2, 5));
+        v.step();
+        assert_eq!(v.pending_input().collect::<Vec<_>>(), vec![2]);
+        assert_eq!(v.peek(0), 1);
+        v.poke(10, 7);
+        assert_eq!(v.tape().len(), 11);
+    }
+
+    // Rewrites an already-executed instruction, so the decode cache must
+    // be invalidated: the first pass outputs #7, the second outputs @7.
+    #[test]
+    fn self_modifying() {
+        for &cached in &[true, false] {
+            let mut v = Vm::new(&[104, 7, 1101, 0, 4, 0, 1105, 1, 0]);
+            v.set_decode_cache(cached);
+            assert_eq!(v.run_until(), Some(7));
+            assert_eq!(v.run_until(), Some(1));
+        }
+    }
+
+    #[test]
+    fn wrapping() {
+        // Arithmetic wraps on overflow, in debug and release builds alike
+        let mut v = Vm::new(&[1102, i64::MAX, 2, 0, 1001, 0, 3, 0, 4, 0, 99]);
+        assert_eq!(v.run(), vec![1]);
+    }
+
+    #[test]
+    fn far_memory() {
+        // Stores 5 at address 10^12, then reads it back out
+        let mut v = Vm::new(&[1101, 2, 3, 1_000_000_000_000, 4, 1_000_000_000_000, 99]);
+        assert_eq!(v.run(), vec![5]);
+        assert_eq!(v.peek(1_000_000_000_000), 5);
+        assert_eq!(v.tape().len(), 7);
+
+        let mut v = Vm::new(&[1101, 2, 3, 1_000_000_000_000, 99]);
+        v.set_memory_limit(100);
+        assert_eq!(
+            v.try_run(),
+            Err(VmError::MemoryLimit {
+                ip: 0,
+                instruction: 1101,
+                address: 1_000_000_000_000
+            })
+        );
+
+        assert!(matches!(
+            v.try_poke(1_000_000_000_000, 5),
+            Err(VmError::MemoryLimit { .. })
+        ));
+        assert_eq!(v.try_poke(10, 5), Ok(()));
+        assert_eq!(v.peek(10), 5);
+    }
 }
@@ -0,0 +1,228 @@
+//! Intcode memory.
+//!
+//! The tape and any cells near it live in a dense `Vec`, which is what
+//! nearly every program touches.  Writes far past the end go into sparse
+//! pages instead, so that a program storing to address 10^12 doesn't try to
+//! allocate terabytes.  The total allocation is capped by a limit.
+use std::collections::BTreeMap;
+
+use crate::Decoded;
+
+/// Number of cells in a sparse page
+const PAGE_SIZE: usize = 1024;
+
+/// Writes less than this far past the end of dense memory grow it, rather
+/// than allocating a sparse page.  This must be at least `PAGE_SIZE`.
+const DENSE_SLACK: usize = 1 << 16;
+
+/// Default limit on allocated memory, in cells (128 MiB)
+pub const DEFAULT_MEMORY_LIMIT: usize = 1 << 24;
+
+#[derive(Clone)]
+pub(crate) struct Memory {
+    dense: Vec<i64>,
+
+    /// Decoded instructions, parallel to `dense`
+    decoded: Vec<Option<Decoded>>,
+
+    /// Whether `decoded` is used at all, so that it can be benchmarked
+    caching: bool,
+
+    /// Sparse pages, keyed by page index.  Pages may overlap the end of
+    /// `dense`, in which case the dense values take priority.
+    pages: BTreeMap<usize, Box<[i64]>>,
+
+    /// Maximum number of cells to allocate
+    limit: usize,
+}
+
+impl Memory {
+    pub fn new(tape: &[i64]) -> Self {
+        Memory {
+            dense: tape.to_vec(),
+            decoded: vec![None; tape.len()],
+            caching: true,
+            pages: BTreeMap::new(),
+            limit: DEFAULT_MEMORY_LIMIT,
+        }
+    }
+
+    pub fn dense(&self) -> &[i64] {
+        &self.dense
+    }
+
+    /// Returns the cached decoding of the instruction at `i`, if any
+    #[inline]
+    pub fn decoded(&self, i: usize) -> Option<Decoded> {
+        if !self.caching {
+            return None;
+        }
+        self.decoded.get(i).copied().flatten()
+    }
+
+    /// Caches the decoding of the instruction at `i`, which is only stored
+    /// for dense memory (and is cleared when that cell is written)
+    #[inline]
+    pub fn cache(&mut self, i: usize, d: Decoded) {
+        if !self.caching {
+            return;
+        }
+        if let Some(c) = self.decoded.get_mut(i) {
+            *c = Some(d);
+        }
+    }
+
+    /// Enables or disables the decode cache, clearing it either way
+    pub fn set_caching(&mut self, caching: bool) {
+        self.caching = caching;
+        self.decoded.iter_mut().for_each(|d| *d = None);
+    }
+
+    pub fn set_limit(&mut self, limit: usize) {
+        self.limit = limit;
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// Returns the number of allocated cells
+    pub fn size(&self) -> usize {
+        self.dense.len() + self.pages.len() * PAGE_SIZE
+    }
+
+    /// Reads a cell, returning zero for anything never written
+    #[inline]
+    pub fn read(&self, i: usize) -> i64 {
+        match self.dense.get(i) {
+            Some(v) => *v,
+            None => self
+                .pages
+                .get(&(i / PAGE_SIZE))
+                .map_or(0, |p| p[i % PAGE_SIZE]),
+        }
+    }
+
+    /// Writes a cell, returning `false` if that would exceed the limit
+    #[inline]
+    pub fn write(&mut self, i: usize, v: i64) -> bool {
+        if i < self.dense.len() {
+            self.dense[i] = v;
+            self.decoded[i] = None;
+            true
+        } else if i < self.dense.len() + DENSE_SLACK {
+            if !self.grow(i + 1) {
+                return false;
+            }
+            self.dense[i] = v;
+            true
+        } else {
+            let k = i / PAGE_SIZE;
+            if !self.pages.contains_key(&k) {
+                if self.size() + PAGE_SIZE > self.limit {
+                    return false;
+                }
+                self.pages.insert(k, vec![0; PAGE_SIZE].into_boxed_slice());
+            }
+            self.pages.get_mut(&k).unwrap()[i % PAGE_SIZE] = v;
+            true
+        }
+    }
+
+    /// Grows dense memory to `len` cells, copying in any values from sparse
+    /// pages that it now covers (and dropping pages that are fully covered)
+    fn grow(&mut self, len: usize) -> bool {
+        let old = self.dense.len();
+        let covered = self
+            .pages
+            .range(old / PAGE_SIZE..=(len - 1) / PAGE_SIZE)
+            .map(|(k, _)| *k)
+            .collect::<Vec<_>>();
+        let dropped = covered
+            .iter()
+            .filter(|k| (*k + 1) * PAGE_SIZE <= len)
+            .count();
+        if len + (self.pages.len() - dropped) * PAGE_SIZE > self.limit {
+            return false;
+        }
+        self.dense.resize(len, 0);
+        self.decoded.resize(len, None);
+        for k in covered {
+            let start = k * PAGE_SIZE;
+            let page = &self.pages[&k];
+            for a in old.max(start)..len.min(start + PAGE_SIZE) {
+                self.dense[a] = page[a - start];
+            }
+            if start + PAGE_SIZE <= len {
+                self.pages.remove(&k);
+            }
+        }
+        true
+    }
+
+    /// Iterates over non-zero cells in sparse pages, as `(address, value)`
+    pub fn sparse(&self) -> impl Iterator<Item = (usize, i64)> + '_ {
+        let end = self.dense.len();
+        self.pages.iter().flat_map(move |(k, p)| {
+            p.iter()
+                .enumerate()
+                .map(move |(j, v)| (k * PAGE_SIZE + j, *v))
+                .filter(move |(a, v)| *v != 0 && *a >= end)
+        })
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dense_growth() {
+        let mut m = Memory::new(&[1, 2, 3]);
+        assert!(m.write(100, 5));
+        assert_eq!(m.dense().len(), 101);
+        assert_eq!(m.read(100), 5);
+        assert_eq!(m.read(2), 3);
+        assert_eq!(m.read(1 << 40), 0);
+    }
+
+    #[test]
+    fn sparse() {
+        let mut m = Memory::new(&[1, 2, 3]);
+        assert!(m.write(1_000_000_000_000, 7));
+        assert_eq!(m.dense().len(), 3);
+        assert_eq!(m.size(), 3 + PAGE_SIZE);
+        assert_eq!(m.read(1_000_000_000_000), 7);
+        assert_eq!(m.sparse().collect::<Vec<_>>(), vec![(1_000_000_000_000, 7)]);
+    }
+
+    #[test]
+    fn absorb() {
+        let mut m = Memory::new(&[]);
+        let far = DENSE_SLACK + 10;
+        assert!(m.write(far, 1));
+        assert_eq!(m.dense().len(), 0);
+
+        // Walk upwards, growing dense memory until it covers the page
+        for i in (0..far + PAGE_SIZE).step_by(PAGE_SIZE / 2) {
+            assert!(m.write(i, 2));
+        }
+        assert!(m.dense().len() > far);
+        assert_eq!(m.sparse().count(), 0);
+        assert_eq!(m.read(far), 1);
+    }
+
+    #[test]
+    fn limit() {
+        let mut m = Memory::new(&[0; 10]);
+        m.set_limit(PAGE_SIZE * 2);
+        assert!(m.write(1 << 30, 1));
+        assert!(!m.write(1 << 31, 1));
+        assert!(!m.write(PAGE_SIZE + 500, 1));
+        assert!(m.write(PAGE_SIZE - 500, 1));
+        assert_eq!(m.read(1 << 30), 1);
+        assert_eq!(m.read(1 << 31), 0);
+    }
+}
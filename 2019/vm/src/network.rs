@@ -0,0 +1,459 @@
+//! Running many connected VMs together.
+//!
+//! Each node in a [`Network`] is a [`Vm`] plus a [`Link`] that says where
+//! its output goes.  [`Network::run`] schedules nodes round-robin, letting
+//! each one run until it blocks on input, produces output, or uses up its
+//! time slice, and reports every interesting [`Event`] to a callback.
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::{Vm, VmError};
+
+/// Maximum number of instructions a node executes before yielding
+const QUANTUM: usize = 1000;
+
+/// A node that polls for input this many times in a row (receiving its idle
+/// input each time) without any traffic is considered idle.
+const IDLE_POLLS: usize = 2;
+
+pub type NodeId = usize;
+
+/// Where a node's output is sent
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Link {
+    /// Every output value is sent as input to another node
+    Pipe(NodeId),
+    /// Output is grouped into packets (an address followed by a payload),
+    /// which are delivered to the node with that address
+    Router,
+    /// Output is only reported through [`Event::Output`]
+    Output,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Event {
+    /// A node produced a value (sent for every output, before routing)
+    Output { node: NodeId, value: i64 },
+    /// A packet was routed, possibly to the NAT
+    Packet {
+        from: NodeId,
+        address: i64,
+        data: Vec<i64>,
+    },
+    /// The network was idle, so the NAT resent its last packet
+    NatWake { target: NodeId, data: Vec<i64> },
+}
+
+/// Reasons why [`Network::run`] returned
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Stop {
+    /// The callback asked to stop
+    Requested,
+    /// Every node has halted
+    Halted,
+    /// Every node is idle, and there's no NAT (or nothing for it to send)
+    Idle,
+    /// No node can make progress; these nodes are blocked on input
+    Deadlock { blocked: Vec<NodeId> },
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum NetError {
+    /// A node's VM failed
+    Vm { node: NodeId, err: VmError },
+    /// A node sent a packet to an address with no node attached
+    UnknownAddress { node: NodeId, address: i64 },
+    /// The NAT tried to wake an address with no node attached
+    UnknownNatTarget(i64),
+}
+
+impl fmt::Display for NetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NetError::Vm { node, err } => write!(f, "Node {}: {}", node, err),
+            NetError::UnknownAddress { node, address } => {
+                write!(
+                    f,
+                    "Node {} sent a packet to unknown address {}",
+                    node, address
+                )
+            }
+            NetError::UnknownNatTarget(address) => {
+                write!(f, "NAT target {} has no node attached", address)
+            }
+        }
+    }
+}
+
+impl std::error::Error for NetError {}
+
+#[derive(Clone)]
+struct Node {
+    vm: Vm,
+    link: Link,
+
+    /// Value provided when the node reads from an empty input queue.  If
+    /// this is `None`, the node blocks until input arrives.
+    idle_input: Option<i64>,
+
+    /// Partial packet, for routed nodes
+    buf: Vec<i64>,
+
+    /// Number of idle polls since the last traffic
+    polls: usize,
+}
+
+impl Node {
+    fn idle(&self) -> bool {
+        !self.vm.running()
+            || self.polls >= IDLE_POLLS
+            || (self.idle_input.is_none() && self.vm.needs_input())
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Nat {
+    address: i64,
+    target: i64,
+    last: Option<Vec<i64>>,
+}
+
+#[derive(Clone)]
+pub struct Network {
+    nodes: Vec<Node>,
+    addresses: HashMap<i64, NodeId>,
+    packet_size: usize,
+    nat: Option<Nat>,
+}
+
+impl Default for Network {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Network {
+    /// Builds an empty network, with packets of an address and two values
+    pub fn new() -> Self {
+        Network {
+            nodes: Vec::new(),
+            addresses: HashMap::new(),
+            packet_size: 3,
+            nat: None,
+        }
+    }
+
+    /// Sets the packet size for routed nodes, including the address
+    pub fn set_packet_size(&mut self, n: usize) {
+        assert!(n > 0, "Packets must include an address");
+        self.packet_size = n;
+    }
+
+    pub fn add_node(&mut self, vm: Vm, link: Link) -> NodeId {
+        self.nodes.push(Node {
+            vm,
+            link,
+            idle_input: None,
+            buf: Vec::new(),
+            polls: 0,
+        });
+        self.nodes.len() - 1
+    }
+
+    /// Assigns a router address to the given node
+    pub fn set_address(&mut self, node: NodeId, address: i64) {
+        self.addresses.insert(address, node);
+    }
+
+    /// Sets a value to be read by the node when its input queue is empty,
+    /// rather than blocking.
+    pub fn set_idle_input(&mut self, node: NodeId, value: i64) {
+        self.nodes[node].idle_input = Some(value);
+    }
+
+    /// Installs a NAT, which stores the last packet sent to `address` and
+    /// delivers it to the node at `target` whenever the network is idle.
+    pub fn set_nat(&mut self, address: i64, target: i64) {
+        self.nat = Some(Nat {
+            address,
+            target,
+            last: None,
+        });
+    }
+
+    /// Queues an input value for the given node
+    pub fn input(&mut self, node: NodeId, value: i64) {
+        self.nodes[node].vm.input(value);
+        self.nodes[node].polls = 0;
+    }
+
+    pub fn vm(&self, node: NodeId) -> &Vm {
+        &self.nodes[node].vm
+    }
+
+    /// Returns the nodes which are blocked waiting on input
+    pub fn blocked(&self) -> Vec<NodeId> {
+        self.nodes
+            .iter()
+            .enumerate()
+            .filter(|(_, n)| n.idle_input.is_none() && n.vm.needs_input())
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    fn deliver(&mut self, from: NodeId, address: i64, data: &[i64]) -> Result<(), NetError> {
+        if let Some(nat) = self.nat.as_mut().filter(|n| n.address == address) {
+            nat.last = Some(data.to_vec());
+            return Ok(());
+        }
+        let Some(&to) = self.addresses.get(&address) else {
+            return Err(NetError::UnknownAddress {
+                node: from,
+                address,
+            });
+        };
+        for d in data {
+            self.input(to, *d);
+        }
+        Ok(())
+    }
+
+    /// Runs a single node for one time slice, returning the number of
+    /// instructions executed and whether the callback asked to stop.
+    fn run_node<F>(&mut self, i: NodeId, f: &mut F) -> Result<(usize, bool), NetError>
+    where
+        F: FnMut(&Event) -> bool,
+    {
+        let mut steps = 0;
+        while steps < QUANTUM && self.nodes[i].vm.running() {
+            let node = &mut self.nodes[i];
+            let mut polled = false;
+            if node.vm.needs_input() {
+                match node.idle_input {
+                    Some(v) => {
+                        node.vm.input(v);
+                        node.polls += 1;
+                        polled = true;
+                    }
+                    None => break,
+                }
+            }
+            let out = node
+                .vm
+                .try_step()
+                .map_err(|err| NetError::Vm { node: i, err })?;
+            steps += 1;
+
+            if let Some(value) = out {
+                node.polls = 0;
+                if f(&Event::Output { node: i, value }) {
+                    return Ok((steps, true));
+                }
+                match node.link {
+                    Link::Pipe(to) => self.input(to, value),
+                    Link::Output => (),
+                    Link::Router => {
+                        node.buf.push(value);
+                        if node.buf.len() == self.packet_size {
+                            let packet = std::mem::take(&mut node.buf);
+                            let address = packet[0];
+                            let data = packet[1..].to_vec();
+                            self.deliver(i, address, &data)?;
+                            let e = Event::Packet {
+                                from: i,
+                                address,
+                                data,
+                            };
+                            if f(&e) {
+                                return Ok((steps, true));
+                            }
+                        }
+                    }
+                }
+                break;
+            } else if polled {
+                break;
+            }
+        }
+        Ok((steps, false))
+    }
+
+    /// Runs the network until the callback returns `true` or no further
+    /// progress is possible.
+    pub fn run<F>(&mut self, mut f: F) -> Result<Stop, NetError>
+    where
+        F: FnMut(&Event) -> bool,
+    {
+        loop {
+            let mut steps = 0;
+            for i in 0..self.nodes.len() {
+                let (n, stop) = self.run_node(i, &mut f)?;
+                if stop {
+                    return Ok(Stop::Requested);
+                }
+                steps += n;
+            }
+
+            if self.nodes.iter().all(|n| !n.vm.running()) {
+                return Ok(Stop::Halted);
+            } else if steps == 0 {
+                return Ok(Stop::Deadlock {
+                    blocked: self.blocked(),
+                });
+            } else if self.nodes.iter().all(Node::idle) {
+                let Some((target, data)) = self
+                    .nat
+                    .as_ref()
+                    .and_then(|n| Some((n.target, n.last.clone()?)))
+                else {
+                    return Ok(Stop::Idle);
+                };
+                let Some(&to) = self.addresses.get(&target) else {
+                    return Err(NetError::UnknownNatTarget(target));
+                };
+                for d in &data {
+                    self.input(to, *d);
+                }
+                if f(&Event::NatWake { target: to, data }) {
+                    return Ok(Stop::Requested);
+                }
+            }
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Reads a value, adds one, and writes it out, forever
+    fn increment() -> Vm {
+        Vm::new(&[3, 11, 101, 1, 11, 11, 4, 11, 1105, 1, 0, 0])
+    }
+
+    #[test]
+    fn ring() {
+        let mut net = Network::new();
+        let a = net.add_node(increment(), Link::Pipe(1));
+        net.add_node(increment(), Link::Pipe(a));
+        net.input(a, 0);
+        let mut last = 0;
+        let stop = net
+            .run(|e| match e {
+                Event::Output { value, .. } => {
+                    last = *value;
+                    *value >= 10
+                }
+                _ => false,
+            })
+            .unwrap();
+        assert_eq!(stop, Stop::Requested);
+        assert_eq!(last, 10);
+    }
+
+    #[test]
+    fn deadlock() {
+        let mut net = Network::new();
+        let a = net.add_node(increment(), Link::Pipe(1));
+        let b = net.add_node(increment(), Link::Pipe(a));
+        let stop = net.run(|_| false).unwrap();
+        assert_eq!(
+            stop,
+            Stop::Deadlock {
+                blocked: vec![a, b]
+            }
+        );
+    }
+
+    #[test]
+    fn halted() {
+        let mut net = Network::new();
+        net.add_node(Vm::new(&[104, 1, 99]), Link::Output);
+        let mut out = vec![];
+        let stop = net
+            .run(|e| {
+                if let Event::Output { value, .. } = e {
+                    out.push(*value);
+                }
+                false
+            })
+            .unwrap();
+        assert_eq!(stop, Stop::Halted);
+        assert_eq!(out, vec![1]);
+    }
+
+    #[test]
+    fn nat() {
+        // Node 0 sends a packet to the NAT at 255, then echoes anything it
+        // receives back to the NAT with one added to the payload.  Input of
+        // -1 means "no packet", which it ignores.
+        let echo = [
+            104, 255, 104, 7, 104, 8, // send (255, 7, 8)
+            3, 100, // loop: read x
+            1008, 100, -1, 102, // if x == -1
+            1005, 102, 6, // then goto loop
+            3, 101, // read y
+            101, 1, 101, 101, // y += 1
+            104, 255, 4, 100, 4, 101, // send (255, x, y)
+            1105, 1, 6,
+        ];
+        let mut net = Network::new();
+        let n = net.add_node(Vm::new(&echo), Link::Router);
+        net.set_address(n, 0);
+        net.set_idle_input(n, -1);
+        net.set_nat(255, 0);
+
+        let mut wakes = vec![];
+        let stop = net
+            .run(|e| match e {
+                Event::NatWake { data, .. } => {
+                    wakes.push(data[1]);
+                    wakes.len() == 3
+                }
+                _ => false,
+            })
+            .unwrap();
+        assert_eq!(stop, Stop::Requested);
+        assert_eq!(wakes, vec![8, 9, 10]);
+    }
+
+    #[test]
+    fn idle() {
+        let mut net = Network::new();
+        // Reads input forever, without producing any output
+        let n = net.add_node(Vm::new(&[3, 100, 1105, 1, 0]), Link::Router);
+        net.set_idle_input(n, -1);
+        assert_eq!(net.run(|_| false).unwrap(), Stop::Idle);
+    }
+
+    #[test]
+    fn unknown_address() {
+        let mut net = Network::new();
+        net.add_node(Vm::new(&[104, 5, 104, 1, 104, 2, 99]), Link::Router);
+        assert_eq!(
+            net.run(|_| false),
+            Err(NetError::UnknownAddress {
+                node: 0,
+                address: 5
+            })
+        );
+    }
+
+    #[test]
+    fn unknown_nat_target() {
+        // Sends one packet to the NAT, then idles
+        let mut net = Network::new();
+        let n = net.add_node(
+            Vm::new(&[104, 255, 104, 1, 104, 2, 3, 100, 1105, 1, 6]),
+            Link::Router,
+        );
+        net.set_address(n, 0);
+        net.set_idle_input(n, -1);
+        net.set_nat(255, 7);
+        let err = net.run(|_| false).unwrap_err();
+        assert_eq!(err, NetError::UnknownNatTarget(7));
+        assert_eq!(err.to_string(), "NAT target 7 has no node attached");
+    }
+}
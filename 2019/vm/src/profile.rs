@@ -0,0 +1,300 @@
+//! Execution profiling.
+//!
+//! When profiling is enabled with [`Vm::enable_profiling`], the VM counts
+//! how often each instruction runs, which jumps are taken, and how often
+//! each address is read and written.  [`Profile::report`] turns those
+//! counts into a summary of the hottest blocks and loops.
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::Write;
+
+use crate::disasm::Instruction;
+use crate::{Vm, OP_BREAK, OP_JIF, OP_JIT};
+
+/// Number of rows in each section of the report
+const REPORT_ROWS: usize = 10;
+
+#[derive(Clone, Debug, Default)]
+pub struct Profile {
+    /// Execution count and opcode for each instruction address
+    hits: HashMap<usize, (u64, i64)>,
+    /// Number of times each jump was taken, keyed by `(from, to)`
+    jumps: HashMap<(usize, usize), u64>,
+    reads: HashMap<usize, u64>,
+    writes: HashMap<usize, u64>,
+    /// Address of the halt instruction the VM is stopped at, so that
+    /// running a halted VM again isn't counted
+    halted: Option<usize>,
+}
+
+/// A straight-line run of executed instructions, as found by the profiler
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Block {
+    pub start: usize,
+    /// Address of the last instruction in the block
+    pub end: usize,
+    /// Number of times the block was entered
+    pub entries: u64,
+    /// Total number of instructions executed within the block
+    pub instructions: u64,
+}
+
+impl Profile {
+    pub(crate) fn hit(&mut self, ip: usize, opcode: i64) {
+        self.hits.entry(ip).or_insert((0, opcode)).0 += 1;
+        self.halted = None;
+    }
+
+    pub(crate) fn halt(&mut self, ip: usize) {
+        if self.halted != Some(ip) {
+            self.hit(ip, OP_BREAK);
+            self.halted = Some(ip);
+        }
+    }
+
+    pub(crate) fn jump(&mut self, from: usize, to: usize) {
+        *self.jumps.entry((from, to)).or_default() += 1;
+    }
+
+    pub(crate) fn read(&mut self, addr: usize) {
+        *self.reads.entry(addr).or_default() += 1;
+    }
+
+    pub(crate) fn write(&mut self, addr: usize) {
+        *self.writes.entry(addr).or_default() += 1;
+    }
+
+    /// Returns the total number of instructions executed
+    pub fn instructions(&self) -> u64 {
+        self.hits.values().map(|(n, _)| n).sum()
+    }
+
+    /// Returns the number of times the instruction at `ip` was executed
+    pub fn hits(&self, ip: usize) -> u64 {
+        self.hits.get(&ip).map_or(0, |(n, _)| *n)
+    }
+
+    pub fn reads(&self, addr: usize) -> u64 {
+        self.reads.get(&addr).copied().unwrap_or(0)
+    }
+
+    pub fn writes(&self, addr: usize) -> u64 {
+        self.writes.get(&addr).copied().unwrap_or(0)
+    }
+
+    /// Returns execution counts for each opcode, most frequent first
+    pub fn opcodes(&self) -> Vec<(i64, u64)> {
+        let mut counts: BTreeMap<i64, u64> = BTreeMap::new();
+        for (n, op) in self.hits.values() {
+            *counts.entry(*op).or_default() += n;
+        }
+        let mut out = counts.into_iter().collect::<Vec<_>>();
+        out.sort_by_key(|(op, n)| (std::cmp::Reverse(*n), *op));
+        out
+    }
+
+    /// Splits executed code into blocks, which start at the first executed
+    /// instruction, jump targets, and after jumps.
+    pub fn blocks(&self) -> Vec<Block> {
+        let mut leaders = self
+            .jumps
+            .keys()
+            .map(|(_, to)| *to)
+            .collect::<BTreeSet<_>>();
+        let code = self.hits.iter().collect::<BTreeMap<_, _>>();
+
+        let mut out: Vec<Block> = vec![];
+        let mut next = None;
+        for (ip, (n, op)) in code {
+            if next != Some(*ip) || leaders.contains(ip) {
+                out.push(Block {
+                    start: *ip,
+                    end: *ip,
+                    entries: *n,
+                    instructions: 0,
+                });
+            }
+            let b = out.last_mut().unwrap();
+            b.end = *ip;
+            b.instructions += n;
+
+            let size = Instruction::shape(*op).map_or(1, |(n, _)| n + 1);
+            next = Some(ip + size);
+            if *op == OP_JIT || *op == OP_JIF {
+                leaders.insert(ip + size);
+            }
+        }
+        out
+    }
+
+    /// Returns taken jumps to the same or an earlier address, as
+    /// `(from, to, count)`, most frequent first
+    pub fn back_edges(&self) -> Vec<(usize, usize, u64)> {
+        let mut out = self
+            .jumps
+            .iter()
+            .filter(|((from, to), _)| to <= from)
+            .map(|((from, to), n)| (*from, *to, *n))
+            .collect::<Vec<_>>();
+        out.sort_by_key(|(from, to, n)| (std::cmp::Reverse(*n), *from, *to));
+        out
+    }
+
+    /// Builds a human-readable report, using the tape to disassemble the
+    /// start of each block
+    pub fn report(&self, tape: &[i64]) -> String {
+        let total = self.instructions();
+        let percent = |n: u64| 100.0 * n as f64 / total.max(1) as f64;
+        let mut out = String::new();
+        writeln!(out, "Executed {} instructions", total).unwrap();
+
+        writeln!(out, "\nOpcodes:").unwrap();
+        for (op, n) in self.opcodes() {
+            let name = Instruction {
+                opcode: op,
+                params: vec![],
+            };
+            let name = Instruction::shape(op).map_or("???", |_| name.mnemonic());
+            writeln!(out, "  {:<4} {:>12} {:>6.2}%", name, n, percent(n)).unwrap();
+        }
+
+        let mut blocks = self.blocks();
+        blocks.sort_by_key(|b| (std::cmp::Reverse(b.instructions), b.start));
+        writeln!(out, "\nHottest blocks:").unwrap();
+        writeln!(
+            out,
+            "  {:<11} {:>12} {:>12} {:>7}  first instruction",
+            "range", "entries", "instructions", ""
+        )
+        .unwrap();
+        for b in blocks.iter().take(REPORT_ROWS) {
+            let op = Instruction::decode(tape, b.start)
+                .map_or_else(|| "???".to_owned(), |op| op.to_string());
+            writeln!(
+                out,
+                "  {:04}-{:04}   {:>12} {:>12} {:>6.2}%  {}",
+                b.start,
+                b.end,
+                b.entries,
+                b.instructions,
+                percent(b.instructions),
+                op
+            )
+            .unwrap();
+        }
+
+        writeln!(out, "\nLoop back-edges:").unwrap();
+        for (from, to, n) in self.back_edges().into_iter().take(REPORT_ROWS) {
+            writeln!(out, "  {:04} -> {:04} {:>12}", from, to, n).unwrap();
+        }
+
+        let mut mem = self
+            .reads
+            .keys()
+            .chain(self.writes.keys())
+            .copied()
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .map(|a| (a, self.reads(a), self.writes(a)))
+            .collect::<Vec<_>>();
+        mem.sort_by_key(|(a, r, w)| (std::cmp::Reverse(r + w), *a));
+        writeln!(out, "\nBusiest addresses:").unwrap();
+        writeln!(out, "  {:<6} {:>12} {:>12}", "addr", "reads", "writes").unwrap();
+        for (a, r, w) in mem.into_iter().take(REPORT_ROWS) {
+            writeln!(out, "  {:<6} {:>12} {:>12}", format!("{:04}", a), r, w).unwrap();
+        }
+        out
+    }
+}
+
+impl Vm {
+    /// Starts counting execution statistics, discarding any previous ones
+    pub fn enable_profiling(&mut self) {
+        self.profile = Some(Box::default());
+    }
+
+    /// Stops profiling, returning the collected statistics
+    pub fn take_profile(&mut self) -> Option<Profile> {
+        self.profile.take().map(|p| *p)
+    }
+
+    pub fn profile(&self) -> Option<&Profile> {
+        self.profile.as_deref()
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+
+    // Counts down from the input, outputting the sum
+    const LOOP: &str = "
+                in n
+        loop:   add s, n -> s
+                add n, #-1 -> n
+                jt n, #loop
+                out s
+                hlt
+        n:      .data 0
+        s:      .data 0
+    ";
+
+    #[test]
+    fn counts() {
+        let tape = assemble(LOOP).unwrap();
+        let mut vm = Vm::new(&tape);
+        vm.enable_profiling();
+        vm.input(10);
+        assert_eq!(vm.run(), vec![55]);
+
+        let p = vm.take_profile().unwrap();
+        assert!(vm.profile().is_none());
+        assert_eq!(p.instructions(), 1 + 10 * 3 + 2);
+        assert_eq!(p.hits(2), 10);
+        assert_eq!(p.opcodes()[0], (crate::OP_ADD, 20));
+
+        let n = 16;
+        assert_eq!(tape[n - 1], 99);
+        assert_eq!(p.writes(n), 11);
+        assert_eq!(p.reads(n), 30);
+
+        assert_eq!(p.back_edges(), vec![(10, 2, 9)]);
+        let blocks = p.blocks();
+        assert_eq!(blocks.len(), 3);
+        assert_eq!(
+            blocks[1],
+            Block {
+                start: 2,
+                end: 10,
+                entries: 10,
+                instructions: 30
+            }
+        );
+
+        let report = p.report(&tape);
+        assert!(report.contains("Executed 33 instructions"));
+        assert!(report.contains("0010 -> 0002"));
+    }
+
+    #[test]
+    fn blocked() {
+        let tape = assemble(LOOP).unwrap();
+        let mut vm = Vm::new(&tape);
+        vm.enable_profiling();
+        // Waiting on input doesn't count, and nor does re-running a halted VM
+        for _ in 0..5 {
+            assert_eq!(vm.try_step(), Ok(None));
+        }
+        vm.input(3);
+        assert_eq!(vm.run(), vec![6]);
+        assert_eq!(vm.run(), vec![]);
+        assert_eq!(vm.try_step(), Ok(None));
+
+        let p = vm.profile().unwrap();
+        assert_eq!(p.hits(0), 1);
+        assert_eq!(p.hits(15), 1);
+        assert_eq!(p.instructions(), 1 + 3 * 3 + 2);
+    }
+}
@@ -0,0 +1,240 @@
+//! Saving and restoring machine state.
+//!
+//! Snapshots are plain text, one field per line:
+//! ```text
+//! intcode-snapshot 2
+//! ip 42
+//! base 1000
+//! input 10,110
+//! limit 16777216
+//! mem 109,1000,3,...
+//! sparse 1000000000000=5
+//! ```
+//! `input` lists pending values in the order they'll be consumed, and
+//! `limit` is the memory limit in cells.  The optional `sparse` line lists
+//! non-zero cells far beyond the end of `mem`, as `address=value` pairs.
+//! `limit` and `sparse` were added in version 2; version 1 snapshots (which
+//! never have them) can still be loaded, with the default memory limit.
+use std::collections::VecDeque;
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::memory::Memory;
+use crate::Vm;
+
+const HEADER: &str = "intcode-snapshot";
+
+/// Current snapshot format version, which must be bumped whenever fields
+/// are added so that older readers reject newer files
+const VERSION: u32 = 2;
+
+fn invalid(msg: String) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+fn parse_list(s: &str) -> Result<Vec<i64>, Error> {
+    if s.is_empty() {
+        return Ok(vec![]);
+    }
+    s.split(',')
+        .map(|i| i64::from_str(i).map_err(|e| invalid(format!("Invalid value {:?}: {}", i, e))))
+        .collect()
+}
+
+fn join(vs: impl Iterator<Item = i64>) -> String {
+    vs.map(|i| i.to_string()).collect::<Vec<_>>().join(",")
+}
+
+impl Vm {
+    /// Serializes the machine state into the snapshot format
+    pub fn snapshot(&self) -> String {
+        let mut out = format!(
+            "{} {}\nip {}\nbase {}\ninput {}\nlimit {}\nmem {}\n",
+            HEADER,
+            VERSION,
+            self.ip,
+            self.base,
+            join(self.pending_input()),
+            self.mem.limit(),
+            join(self.mem.dense().iter().copied()),
+        );
+        let sparse = self
+            .mem
+            .sparse()
+            .map(|(a, v)| format!("{}={}", a, v))
+            .collect::<Vec<_>>();
+        if !sparse.is_empty() {
+            out += &format!("sparse {}\n", sparse.join(","));
+        }
+        out
+    }
+
+    /// Restores a machine from the snapshot format
+    pub fn from_snapshot(s: &str) -> Result<Self, Error> {
+        let mut lines = s.lines();
+        let version = lines
+            .next()
+            .and_then(|line| line.strip_prefix(HEADER))
+            .and_then(|line| line.strip_prefix(' '))
+            .ok_or_else(|| invalid("Missing snapshot header".to_owned()))?;
+        let version = u32::from_str(version)
+            .ok()
+            .filter(|v| (1..=VERSION).contains(v))
+            .ok_or_else(|| invalid(format!("Unsupported snapshot version {:?}", version)))?;
+        let mut field = |name: &str| -> Result<&str, Error> {
+            lines
+                .next()
+                .and_then(|line| line.strip_prefix(name))
+                .and_then(|line| line.strip_prefix(' '))
+                .ok_or_else(|| invalid(format!("Missing field {:?}", name)))
+        };
+        let ip = field("ip")?;
+        let ip = usize::from_str(ip).map_err(|e| invalid(format!("Invalid ip: {}", e)))?;
+        let base = field("base")?;
+        let base = i64::from_str(base).map_err(|e| invalid(format!("Invalid base: {}", e)))?;
+        let input = parse_list(field("input")?)?;
+        let limit = if version >= 2 {
+            let limit = field("limit")?;
+            Some(usize::from_str(limit).map_err(|e| invalid(format!("Invalid limit: {}", e)))?)
+        } else {
+            None
+        };
+        let mut mem = Memory::new(&parse_list(field("mem")?)?);
+        if let Some(limit) = limit {
+            mem.set_limit(limit);
+        }
+        let sparse = lines.next().filter(|_| version >= 2);
+        if let Some(sparse) = sparse.and_then(|line| line.strip_prefix("sparse ")) {
+            for cell in sparse.split(',') {
+                let (a, v) = cell
+                    .split_once('=')
+                    .and_then(|(a, v)| Some((usize::from_str(a).ok()?, i64::from_str(v).ok()?)))
+                    .ok_or_else(|| invalid(format!("Invalid sparse cell {:?}", cell)))?;
+                if !mem.write(a, v) {
+                    return Err(invalid(format!("Sparse cell {} exceeds memory limit", a)));
+                }
+            }
+        }
+
+        Ok(Vm {
+            mem,
+            ip,
+            // The queue is consumed from the back
+            input: input.into_iter().rev().collect::<VecDeque<_>>(),
+            base,
+            profile: None,
+        })
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        std::fs::write(path, self.snapshot())
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        Self::from_snapshot(&std::fs::read_to_string(path)?)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let mut vm = Vm::new(&[109, 5, 3, 10, 3, 11, 204, -5, 99]);
+        vm.input(7);
+        vm.input(8);
+        vm.input(9);
+        vm.step();
+        vm.step();
+
+        let s = vm.snapshot();
+        assert_eq!(
+            s,
+            "intcode-snapshot 2\nip 4\nbase 5\ninput 8,9\nlimit 16777216\n\
+             mem 109,5,3,10,3,11,204,-5,99,0,7\n"
+        );
+        let mut restored = Vm::from_snapshot(&s).unwrap();
+        assert_eq!(restored.snapshot(), s);
+        assert_eq!(restored.run(), vm.run());
+    }
+
+    #[test]
+    fn sparse() {
+        let mut vm = Vm::new(&[99]);
+        vm.poke(1_000_000_000_000, 5);
+        let s = vm.snapshot();
+        assert!(s.ends_with("mem 99\nsparse 1000000000000=5\n"));
+        let restored = Vm::from_snapshot(&s).unwrap();
+        assert_eq!(restored.peek(1_000_000_000_000), 5);
+        assert_eq!(restored.snapshot(), s);
+    }
+
+    #[test]
+    fn limit() {
+        let mut vm = Vm::new(&[99]);
+        vm.set_memory_limit(100);
+        let s = vm.snapshot();
+        assert!(s.contains("\nlimit 100\n"));
+        let mut restored = Vm::from_snapshot(&s).unwrap();
+        assert!(restored.try_poke(1_000_000, 1).is_err());
+
+        // Sparse cells are checked against the restored limit
+        let s = "intcode-snapshot 2\nip 0\nbase 0\ninput \nlimit 100\nmem 99\nsparse 5000=1\n";
+        let e = Vm::from_snapshot(s).err().unwrap();
+        assert_eq!(e.to_string(), "Sparse cell 5000 exceeds memory limit");
+        assert!(Vm::from_snapshot(&s.replace("limit 100", "limit x")).is_err());
+        assert!(Vm::from_snapshot(&s.replace("limit 100\n", "")).is_err());
+    }
+
+    #[test]
+    fn empty_input() {
+        let vm = Vm::new(&[99]);
+        let restored = Vm::from_snapshot(&vm.snapshot()).unwrap();
+        assert_eq!(restored.pending_input().count(), 0);
+        assert!(!restored.running());
+    }
+
+    #[test]
+    fn errors() {
+        assert!(Vm::from_snapshot("").is_err());
+        assert!(Vm::from_snapshot("intcode-snapshot 1\nip 0\nbase 0\nmem 99\n").is_err());
+        assert!(Vm::from_snapshot("intcode-snapshot 1\nip x\nbase 0\ninput \nmem 99").is_err());
+    }
+
+    #[test]
+    fn versions() {
+        // Version 1 snapshots are still readable
+        let vm = Vm::from_snapshot("intcode-snapshot 1\nip 0\nbase 0\ninput 3\nmem 3,0,99\n");
+        assert_eq!(
+            vm.unwrap().snapshot(),
+            "intcode-snapshot 2\nip 0\nbase 0\ninput 3\nlimit 16777216\nmem 3,0,99\n"
+        );
+
+        // Newer (or nonsense) versions are rejected rather than misread
+        for v in &["0", "3", "x"] {
+            let s = format!(
+                "intcode-snapshot {}\nip 0\nbase 0\ninput \nlimit 100\nmem 99\n",
+                v
+            );
+            let e = Vm::from_snapshot(&s).err().unwrap();
+            assert_eq!(
+                e.to_string(),
+                format!("Unsupported snapshot version {:?}", v)
+            );
+        }
+    }
+
+    #[test]
+    fn file() {
+        let path = std::env::temp_dir().join(format!("vm-snapshot-{}", std::process::id()));
+        let vm = Vm::new(&[104, 1, 99]);
+        vm.save(&path).unwrap();
+        let mut restored = Vm::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(restored.run(), vec![1]);
+    }
+}
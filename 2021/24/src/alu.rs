@@ -0,0 +1,503 @@
+use std::collections::HashSet;
+use std::str::FromStr;
+
+/// Registers are stored in the order `x, y, z, w`
+pub type Registers = [i64; 4];
+
+const Z: usize = 2;
+
+fn reg_index(s: &str) -> Option<usize> {
+    match s {
+        "x" => Some(0),
+        "y" => Some(1),
+        "z" => Some(2),
+        "w" => Some(3),
+        _ => None,
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Arg {
+    Reg(usize),
+    Imm(i64),
+}
+
+impl Arg {
+    fn get(&self, regs: &Registers) -> i64 {
+        match self {
+            Arg::Reg(r) => regs[*r],
+            Arg::Imm(i) => *i,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Instruction {
+    Inp(usize),
+    Add(usize, Arg),
+    Mul(usize, Arg),
+    Div(usize, Arg),
+    Mod(usize, Arg),
+    Eql(usize, Arg),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseError(pub String);
+
+impl FromStr for Instruction {
+    type Err = ParseError;
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let err = || ParseError(format!("Invalid instruction '{}'", line));
+        let mut words = line.split_whitespace();
+        let op = words.next().ok_or_else(err)?;
+        let a = words.next().and_then(reg_index).ok_or_else(err)?;
+        if op == "inp" {
+            return match words.next() {
+                None => Ok(Instruction::Inp(a)),
+                Some(_) => Err(err()),
+            };
+        }
+        let b = match words.next() {
+            Some(s) => match reg_index(s) {
+                Some(r) => Arg::Reg(r),
+                None => Arg::Imm(s.parse().map_err(|_| err())?),
+            },
+            None => return Err(err()),
+        };
+        if words.next().is_some() {
+            return Err(err());
+        }
+        match op {
+            "add" => Ok(Instruction::Add(a, b)),
+            "mul" => Ok(Instruction::Mul(a, b)),
+            "div" => Ok(Instruction::Div(a, b)),
+            "mod" => Ok(Instruction::Mod(a, b)),
+            "eql" => Ok(Instruction::Eql(a, b)),
+            _ => Err(err()),
+        }
+    }
+}
+
+impl Instruction {
+    /// Executes a single instruction, returning `false` if it's invalid
+    /// (division by zero or a negative modulo), which would crash the ALU
+    fn eval(&self, regs: &mut Registers, input: &mut impl Iterator<Item = i64>) -> bool {
+        match *self {
+            Instruction::Inp(a) => match input.next() {
+                Some(i) => regs[a] = i,
+                None => return false,
+            },
+            Instruction::Add(a, b) => regs[a] += b.get(regs),
+            Instruction::Mul(a, b) => regs[a] *= b.get(regs),
+            Instruction::Div(a, b) => match b.get(regs) {
+                0 => return false,
+                b => regs[a] /= b,
+            },
+            Instruction::Mod(a, b) => match b.get(regs) {
+                b if regs[a] < 0 || b <= 0 => return false,
+                b => regs[a] %= b,
+            },
+            Instruction::Eql(a, b) => regs[a] = (regs[a] == b.get(regs)) as i64,
+        }
+        true
+    }
+
+    /// Returns the registers read and written by this instruction
+    fn uses(&self) -> (Vec<usize>, usize) {
+        match *self {
+            Instruction::Inp(a) => (vec![], a),
+            Instruction::Add(a, b)
+            | Instruction::Mul(a, b)
+            | Instruction::Div(a, b)
+            | Instruction::Mod(a, b)
+            | Instruction::Eql(a, b) => match b {
+                Arg::Reg(r) => (vec![a, r], a),
+                Arg::Imm(_) => (vec![a], a),
+            },
+        }
+    }
+}
+
+/// A program, split into blocks which each start by reading one digit
+pub struct Program {
+    /// Instructions before the first `inp`, if any
+    prefix: Vec<Instruction>,
+    blocks: Vec<Vec<Instruction>>,
+    /// Registers which are live at the start of each block, as a mask.  The
+    /// only thing that matters at the end of the program is `z`.
+    live: Vec<[bool; 4]>,
+}
+
+impl FromStr for Program {
+    type Err = ParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut prefix = vec![];
+        let mut blocks: Vec<Vec<Instruction>> = vec![];
+        for line in s.lines().filter(|line| !line.trim().is_empty()) {
+            let i: Instruction = line.parse()?;
+            if let Instruction::Inp(_) = i {
+                blocks.push(vec![]);
+            }
+            blocks.last_mut().unwrap_or(&mut prefix).push(i);
+        }
+
+        let mut live = vec![[false; 4]; blocks.len() + 1];
+        live[blocks.len()][Z] = true;
+        for (k, block) in blocks.iter().enumerate().rev() {
+            let mut l = live[k + 1];
+            for i in block.iter().rev() {
+                let (read, written) = i.uses();
+                l[written] = false;
+                for r in read {
+                    l[r] = true;
+                }
+            }
+            live[k] = l;
+        }
+        live.pop();
+        Ok(Program {
+            prefix,
+            blocks,
+            live,
+        })
+    }
+}
+
+impl Program {
+    /// Runs the program on a model number, returning the final registers
+    /// (or `None` if the ALU would crash or runs out of digits)
+    pub fn run(&self, digits: &[i64]) -> Option<Registers> {
+        let mut regs = [0; 4];
+        let mut input = digits.iter().cloned();
+        for i in self.prefix.iter().chain(self.blocks.iter().flatten()) {
+            if !i.eval(&mut regs, &mut input) {
+                return None;
+            }
+        }
+        Some(regs)
+    }
+
+    fn run_block(&self, k: usize, mut regs: Registers, digit: i64) -> Option<Registers> {
+        let mut input = std::iter::once(digit);
+        for i in &self.blocks[k] {
+            if !i.eval(&mut regs, &mut input) {
+                return None;
+            }
+        }
+        Some(regs)
+    }
+
+    /// Depth-first search over digits, remembering which (block, registers)
+    /// states are dead ends.  Registers which aren't live at the start of a
+    /// block are cleared, so that equivalent states are merged.
+    fn search(
+        &self,
+        k: usize,
+        mut regs: Registers,
+        digits: &[i64],
+        dead: &mut HashSet<(usize, Registers)>,
+    ) -> Option<Vec<i64>> {
+        if k == self.blocks.len() {
+            return if regs[Z] == 0 { Some(vec![]) } else { None };
+        }
+        for (r, live) in regs.iter_mut().zip(self.live[k].iter()) {
+            if !live {
+                *r = 0;
+            }
+        }
+        if dead.contains(&(k, regs)) {
+            return None;
+        }
+        for &d in digits {
+            if let Some(next) = self.run_block(k, regs, d) {
+                if let Some(mut out) = self.search(k + 1, next, digits, dead) {
+                    out.push(d);
+                    return Some(out);
+                }
+            }
+        }
+        dead.insert((k, regs));
+        None
+    }
+
+    /// Finds the first accepted model number, trying digits in the given
+    /// order at each position
+    fn find(&self, digits: &[i64]) -> Option<usize> {
+        let mut regs = [0; 4];
+        let mut none = std::iter::empty();
+        for i in &self.prefix {
+            if !i.eval(&mut regs, &mut none) {
+                return None;
+            }
+        }
+        let out = self.search(0, regs, digits, &mut HashSet::new())?;
+        Some(out.iter().rev().fold(0, |acc, d| acc * 10 + *d as usize))
+    }
+
+    /// Returns the largest accepted model number
+    pub fn largest(&self) -> Option<usize> {
+        match self.stack() {
+            Some(s) => solve_stack(&s, true),
+            None => self.find(&[9, 8, 7, 6, 5, 4, 3, 2, 1]),
+        }
+    }
+
+    /// Returns the smallest accepted model number
+    pub fn smallest(&self) -> Option<usize> {
+        match self.stack() {
+            Some(s) => solve_stack(&s, false),
+            None => self.find(&[1, 2, 3, 4, 5, 6, 7, 8, 9]),
+        }
+    }
+
+    /// Analyzes each block symbolically, if they all match the usual shape.
+    ///
+    /// Each block computes `x = (z % 26 + a) != w`, then `z /= d` and, if
+    /// `x`, `z = z * 26 + w + b`.  Treating `z` as a stack of base-26
+    /// digits, blocks with `d == 1` and `a >= 10` always push `w + b`, and
+    /// blocks with `d == 26` pop a value and must not push again for `z` to
+    /// end at zero.  This pairs up digits with `w_pop = w_push + b + a`.
+    ///
+    /// The stack only behaves like this if every pushed value `w + b` is a
+    /// base-26 digit other than zero, so push blocks need `b` in `0..=16`
+    /// and pop blocks need `b >= 1`; otherwise, this returns `None`.
+    fn stack(&self) -> Option<Vec<(usize, usize, i64)>> {
+        if !self.prefix.is_empty() {
+            return None;
+        }
+        let template = BLOCK
+            .lines()
+            .map(|line| line.parse().unwrap())
+            .collect::<Vec<Instruction>>();
+        let mut stack = vec![];
+        let mut pairs = vec![];
+        for (k, block) in self.blocks.iter().enumerate() {
+            if block.len() != template.len() {
+                return None;
+            }
+            let mut params = [0; 3];
+            let mut p = 0;
+            for (i, t) in block.iter().zip(&template) {
+                match (i, t) {
+                    (Instruction::Div(a, Arg::Imm(v)), Instruction::Div(b, Arg::Imm(0)))
+                    | (Instruction::Add(a, Arg::Imm(v)), Instruction::Add(b, Arg::Imm(0)))
+                        if a == b =>
+                    {
+                        params[p] = *v;
+                        p += 1;
+                    }
+                    _ if i == t => (),
+                    _ => return None,
+                }
+            }
+            match params {
+                [1, a, b] if a >= 10 && (0..=16).contains(&b) => stack.push((k, b)),
+                [26, a, b] if b >= 1 => {
+                    let (j, b) = stack.pop()?;
+                    pairs.push((j, k, a + b));
+                }
+                _ => return None,
+            }
+        }
+        if stack.is_empty() {
+            Some(pairs)
+        } else {
+            None
+        }
+    }
+}
+
+/// The usual shape of a block, with zeros where the parameters go
+const BLOCK: &str = "inp w
+mul x 0
+add x z
+mod x 26
+div z 0
+add x 0
+eql x w
+eql x 0
+mul y 0
+add y 25
+mul y x
+add y 1
+mul z y
+mul y 0
+add y w
+add y 0
+mul y x
+add z y";
+
+/// Picks digits for each `(push, pop, offset)` pair from `Program::stack`
+fn solve_stack(pairs: &[(usize, usize, i64)], largest: bool) -> Option<usize> {
+    let mut digits = vec![0; pairs.len() * 2];
+    for &(i, j, delta) in pairs {
+        let w = if largest {
+            9.min(9 - delta)
+        } else {
+            1.max(1 - delta)
+        };
+        if !(1..=9).contains(&w) || !(1..=9).contains(&(w + delta)) {
+            return None;
+        }
+        digits[i] = w;
+        digits[j] = w + delta;
+    }
+    Some(digits.iter().fold(0, |acc, d| acc * 10 + *d as usize))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds a program from `(div, add x, add y)` parameters for each block
+    fn monad(params: &[(i64, i64, i64)]) -> String {
+        let mut out = String::new();
+        for (d, a, b) in params {
+            for (i, line) in BLOCK.lines().enumerate() {
+                let line = match i {
+                    4 => format!("div z {}", d),
+                    5 => format!("add x {}", a),
+                    15 => format!("add y {}", b),
+                    _ => line.to_owned(),
+                };
+                out += &line;
+                out += "\n";
+            }
+        }
+        out
+    }
+
+    const PARAMS: [(i64, i64, i64); 14] = [
+        (1, 12, 5),
+        (1, 10, 4),
+        (26, -1, 2),
+        (1, 14, 7),
+        (26, -14, 3),
+        (1, 13, 14),
+        (1, 10, 14),
+        (1, 11, 2),
+        (26, 2, 2),
+        (1, 11, 2),
+        (26, -6, 10),
+        (26, -21, 4),
+        (26, -20, 8),
+        (26, -1, 2),
+    ];
+
+    #[test]
+    fn parse() {
+        assert_eq!("inp w".parse(), Ok(Instruction::Inp(3)));
+        assert_eq!("add x -5".parse(), Ok(Instruction::Add(0, Arg::Imm(-5))));
+        assert_eq!("eql z y".parse(), Ok(Instruction::Eql(2, Arg::Reg(1))));
+        for bad in [
+            "",
+            "foo x 1",
+            "add q 1",
+            "add 1 x",
+            "add x",
+            "add x 1 2",
+            "add x zz",
+            "inp w 3",
+        ] {
+            assert_eq!(
+                bad.parse::<Instruction>(),
+                Err(ParseError(format!("Invalid instruction '{}'", bad)))
+            );
+        }
+        let err = "inp w\nadd x 1\nmul x\n".parse::<Program>().err();
+        assert_eq!(
+            err,
+            Some(ParseError("Invalid instruction 'mul x'".to_owned()))
+        );
+    }
+
+    #[test]
+    fn run() {
+        // Examples from the puzzle
+        let p: Program = "inp x\nmul x -1".parse().unwrap();
+        assert_eq!(p.run(&[7]), Some([-7, 0, 0, 0]));
+
+        let p: Program = "inp z\ninp x\nmul z 3\neql z x".parse().unwrap();
+        assert_eq!(p.run(&[2, 6]).map(|r| r[Z]), Some(1));
+        assert_eq!(p.run(&[2, 5]).map(|r| r[Z]), Some(0));
+
+        let p: Program = "inp w\nadd z w\nmod z 2\ndiv w 2\nadd y w\nmod y 2\n\
+                          div w 2\nadd x w\nmod x 2\ndiv w 2\nmod w 2"
+            .parse()
+            .unwrap();
+        assert_eq!(p.run(&[13]), Some([1, 0, 1, 1]));
+
+        // Invalid operations and missing input crash the ALU
+        let p: Program = "inp w\ndiv w x".parse().unwrap();
+        assert_eq!(p.run(&[1]), None);
+        let p: Program = "inp w\nmod w 2".parse().unwrap();
+        assert_eq!(p.run(&[-1]), None);
+        assert_eq!(p.run(&[]), None);
+    }
+
+    #[test]
+    fn solve() {
+        let p: Program = monad(&PARAMS).parse().unwrap();
+        assert!(p.stack().is_some());
+        let max = p.largest();
+        let min = p.smallest();
+        assert_eq!(max, Some(56992995995239));
+        assert_eq!(min, Some(11481781551115));
+
+        // Pairs which can't be satisfied have no solution
+        let mut params = PARAMS;
+        params[13].1 = -20;
+        let p: Program = monad(&params).parse().unwrap();
+        assert_eq!(p.largest(), None);
+
+        // A negative push doesn't act like a base-26 digit, so this falls
+        // back to the generic search (and 99 is accepted, though the stack
+        // analysis would pair the digits as w_pop = w_push + 5)
+        let p: Program = monad(&[(1, 10, -5), (26, 10, -9)]).parse().unwrap();
+        assert!(p.stack().is_none());
+        assert_eq!(p.largest(), Some(99));
+        assert_eq!(p.run(&[9, 9]).map(|r| r[Z]), Some(0));
+        assert_eq!(p.find(&[9, 8, 7, 6, 5, 4, 3, 2, 1]), Some(99));
+    }
+
+    #[test]
+    fn search() {
+        // The generic search backtracks over whole subtrees when a digit
+        // doesn't match, so it's only fast if that happens near the end.
+        // The first five pairs take equal digits, and the last two are
+        // nested, with offsets of +3 and -4.
+        let params = [
+            (1, 11, 3),
+            (26, -3, 7),
+            (1, 12, 8),
+            (26, -8, 1),
+            (1, 10, 1),
+            (26, -1, 5),
+            (1, 13, 6),
+            (26, -6, 2),
+            (1, 14, 2),
+            (26, -2, 9),
+            (1, 15, 0),
+            (1, 11, 9),
+            (26, -13, 3),
+            (26, 3, 4),
+        ];
+        let p: Program = monad(&params).parse().unwrap();
+        assert!(p.stack().is_some());
+        let max = p.largest();
+        let min = p.smallest();
+        assert_eq!(max, Some(99999999996959));
+        assert_eq!(min, Some(11111111111514));
+
+        // The generic search must agree with the stack analysis
+        assert_eq!(p.find(&[9, 8, 7, 6, 5, 4, 3, 2, 1]), max);
+        assert_eq!(p.find(&[1, 2, 3, 4, 5, 6, 7, 8, 9]), min);
+
+        // An extra no-op breaks the template, so this uses the search
+        let text = monad(&params).replacen("mul y x\n", "mul y x\nadd y 0\n", 1);
+        let p: Program = text.parse().unwrap();
+        assert!(p.stack().is_none());
+        assert_eq!(p.largest(), max);
+        assert_eq!(p.smallest(), min);
+    }
+}
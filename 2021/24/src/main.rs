@@ -1,8 +1,17 @@
+use std::io::Read;
+
+mod alu;
+
+#[cfg(feature = "codegen")]
 use rayon::prelude::*;
 
+#[cfg(feature = "codegen")]
 include!(concat!(env!("OUT_DIR"), "/gen.rs"));
 
-fn main() {
+/// Runs the program compiled from `input` at build time, returning the
+/// largest and smallest accepted model numbers
+#[cfg(feature = "codegen")]
+fn solve_codegen() -> (usize, usize) {
     let mut state = vec![([0; 4], (0, 0))];
 
     for (f, r) in PASSES.iter().zip(INPUTS) {
@@ -42,6 +51,46 @@ fn main() {
         .filter(|(k, _)| k[2] == 0)
         .map(|(_, v)| *v)
         .reduce(|| (usize::MAX, 0), |a, b| (a.0.min(b.0), a.1.max(b.1)));
+    (max, min)
+}
+
+/// Parses and searches the program at runtime
+fn solve_runtime(input: &str) -> (usize, usize) {
+    let program: alu::Program = input
+        .parse()
+        .unwrap_or_else(|e: alu::ParseError| panic!("{}", e.0));
+    let max = program.largest().expect("No valid model number");
+    let min = program.smallest().expect("No valid model number");
+    for n in [max, min] {
+        let digits = n
+            .to_string()
+            .bytes()
+            .map(|b| (b - b'0') as i64)
+            .collect::<Vec<_>>();
+        assert_eq!(program.run(&digits).map(|r| r[2]), Some(0));
+    }
+    (max, min)
+}
+
+fn read_input() -> String {
+    let mut input = String::new();
+    std::io::stdin().read_to_string(&mut input).unwrap();
+    input
+}
+
+fn main() {
+    // The build-time generator is an optional fast path; pass --check to
+    // also run the runtime solver on stdin and compare their results.
+    #[cfg(feature = "codegen")]
+    let (max, min) = {
+        let out = solve_codegen();
+        if std::env::args().any(|a| a == "--check") {
+            assert_eq!(out, solve_runtime(&read_input()), "Solvers disagree");
+        }
+        out
+    };
+    #[cfg(not(feature = "codegen"))]
+    let (max, min) = solve_runtime(&read_input());
     println!("Part 1: {}", max);
     println!("Part 2: {}", min);
 }
@@ -3,8 +3,9 @@ use std::io::Write as _;
 
 use util::get_integers;
 
+/// The 3-bit computer, with registers and a program
 #[derive(Clone)]
-struct Vm {
+pub struct Vm {
     a: u64,
     b: u64,
     c: u64,
@@ -68,7 +69,8 @@ impl Combo {
 }
 
 impl Vm {
-    fn new(s: &str) -> Self {
+    /// Parses registers and a program, in the puzzle's input format
+    pub fn new(s: &str) -> Self {
         let mut iter = s.lines();
         let regs: [u64; 3] = std::array::from_fn(|_| {
             iter.next().and_then(|i| get_integers(i).next()).unwrap()
@@ -88,7 +90,22 @@ impl Vm {
         }
     }
 
-    fn run_to_completion(&mut self) -> Vec<u8> {
+    /// Builds a VM for the given program, with all registers set to zero
+    pub fn from_program(tape: &[u8]) -> Self {
+        Vm {
+            a: 0,
+            b: 0,
+            c: 0,
+            ip: 0,
+            tape: tape.to_vec(),
+        }
+    }
+
+    pub fn program(&self) -> &[u8] {
+        &self.tape
+    }
+
+    pub fn run_to_completion(&mut self) -> Vec<u8> {
         let mut out = vec![];
         loop {
             match self.step() {
@@ -130,6 +147,262 @@ impl Vm {
             Combo::C => self.c,
         }
     }
+
+    /// Checks whether the program is a single loop which shifts `A` right
+    /// by three bits and prints one value per iteration, i.e. shaped like
+    /// `... adv 3 ... out ... jnz 0`
+    ///
+    /// `B` and `C` must not carry state between iterations: each one is
+    /// either never written (so it's a constant), or written from `A` before
+    /// it's read.  This means each iteration's output only depends on `A`.
+    fn is_shift_loop(&self) -> bool {
+        if !self.tape.len().is_multiple_of(2) {
+            return false;
+        }
+        let ops = self
+            .tape
+            .chunks_exact(2)
+            .map(|c| Opcode::new(c[0], c[1]))
+            .collect::<Vec<_>>();
+        let count =
+            |f: fn(&Opcode) -> bool| ops.iter().filter(|o| f(o)).count();
+        if !(matches!(ops.last(), Some(Opcode::Jnz(0)))
+            && count(|o| matches!(o, Opcode::Jnz(_))) == 1
+            && count(|o| matches!(o, Opcode::Adv(_))) == 1
+            && count(|o| matches!(o, Opcode::Adv(Combo::Lit(3)))) == 1
+            && count(|o| matches!(o, Opcode::Out(_))) == 1)
+        {
+            return false;
+        }
+
+        // Registers which are written somewhere in the loop, and registers
+        // which have been written from `A` so far in this iteration
+        let written_b = ops.iter().any(|o| {
+            matches!(
+                o,
+                Opcode::Bxl(_) | Opcode::Bst(_) | Opcode::Bxc | Opcode::Bdv(_)
+            )
+        });
+        let written_c = ops.iter().any(|o| matches!(o, Opcode::Cdv(_)));
+        let (mut fresh_b, mut fresh_c) = (false, false);
+        for op in &ops {
+            let (reads_b, reads_c) = match op {
+                Opcode::Adv(v)
+                | Opcode::Bst(v)
+                | Opcode::Out(v)
+                | Opcode::Bdv(v)
+                | Opcode::Cdv(v) => {
+                    (matches!(v, Combo::B), matches!(v, Combo::C))
+                }
+                Opcode::Bxl(_) => (true, false),
+                Opcode::Bxc => (true, true),
+                Opcode::Jnz(_) => (false, false),
+            };
+            if (reads_b && written_b && !fresh_b)
+                || (reads_c && written_c && !fresh_c)
+            {
+                return false;
+            }
+            match op {
+                Opcode::Bst(_) | Opcode::Bdv(_) => fresh_b = true,
+                Opcode::Cdv(_) => fresh_c = true,
+                _ => (),
+            }
+        }
+        true
+    }
+
+    /// Returns a fresh copy of this VM with `A` set and `B` / `C` optionally
+    /// replaced (otherwise, they keep their current values)
+    fn with_regs(&self, a: u64, b: Option<u64>, c: Option<u64>) -> Self {
+        Vm {
+            a,
+            b: b.unwrap_or(self.b),
+            c: c.unwrap_or(self.c),
+            ip: 0,
+            tape: self.tape.clone(),
+        }
+    }
+
+    /// Finds the smallest `A` which makes the program print `target`
+    ///
+    /// `B` and `C` are set to the given values, or keep the VM's values if
+    /// they're `None`.  Shift loops (see `is_shift_loop`) are solved
+    /// natively; anything else is handed to an external `z3` process.
+    pub fn find_a(
+        &self,
+        target: &[u8],
+        b: Option<u64>,
+        c: Option<u64>,
+    ) -> Option<u64> {
+        let vm = self.with_regs(0, b, c);
+        if !vm.is_shift_loop() {
+            return vm.solve_z3(target);
+        }
+        let mut out = None;
+        vm.search_from(target, 0, 0, &mut |a| {
+            out = Some(a);
+            false
+        });
+        out
+    }
+
+    /// Finds every `A` within the range which makes the program print
+    /// `target`, in increasing order
+    ///
+    /// Shift loops are searched three bits at a time; other programs are
+    /// checked by running every value in the range.
+    pub fn find_a_in(
+        &self,
+        target: &[u8],
+        b: Option<u64>,
+        c: Option<u64>,
+        range: std::ops::Range<u64>,
+    ) -> Vec<u64> {
+        let vm = self.with_regs(0, b, c);
+        let mut out = vec![];
+        if vm.is_shift_loop() {
+            vm.search_from(target, 0, 0, &mut |a| {
+                if range.contains(&a) {
+                    out.push(a);
+                }
+                a < range.end
+            });
+        } else {
+            out.extend(range.filter(|a| {
+                let mut vm = vm.with_regs(*a, None, None);
+                vm.run_to_completion() == target
+            }));
+        }
+        out
+    }
+
+    /// Searches for values of `A` which make the program print `target`,
+    /// calling `f` on each one (in increasing order) until it returns false.
+    /// Returns false if the search was stopped early.
+    ///
+    /// Each loop iteration consumes the low three bits of `A`, so the last
+    /// output only depends on the top three bits, the last two outputs on
+    /// the top six bits, etc.  We build up `A` three bits at a time, working
+    /// backwards from the last output.  This assumes a shift loop (see
+    /// `is_shift_loop`) which doesn't carry `B` or `C` between iterations.
+    ///
+    /// A shift loop always prints at least once, so nothing matches an empty
+    /// target.
+    fn search_from(
+        &self,
+        target: &[u8],
+        a: u64,
+        depth: usize,
+        f: &mut impl FnMut(u64) -> bool,
+    ) -> bool {
+        if target.is_empty() {
+            return true;
+        } else if depth == target.len() {
+            return f(a);
+        }
+        let suffix = &target[target.len() - depth - 1..];
+        for d in 0..8 {
+            let Some(next) = a.checked_mul(8).and_then(|a| a.checked_add(d))
+            else {
+                break;
+            };
+            let mut vm = self.with_regs(next, None, None);
+            if vm.run_to_completion() == suffix
+                && !self.search_from(target, next, depth + 1, f)
+            {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Encodes the search for the smallest `A` which prints `target` as an
+    /// SMT-LIB2 script, which can be run with `z3 -in`
+    pub fn to_smt2(&self, target: &[u8]) -> String {
+        let mut w = Writer {
+            ia: 0,
+            ib: 0,
+            ic: 0,
+            t: 0,
+            ip: 0,
+            tape: &self.tape,
+            target,
+            lines: vec![],
+        };
+        w.run();
+        let mut smt = String::new();
+        for i in 0..=w.ia {
+            writeln!(&mut smt, "(declare-const a{i} (_ BitVec 64))").unwrap();
+        }
+        for i in 0..=w.ib {
+            writeln!(&mut smt, "(declare-const b{i} (_ BitVec 64))").unwrap();
+        }
+        for i in 0..=w.ic {
+            writeln!(&mut smt, "(declare-const c{i} (_ BitVec 64))").unwrap();
+        }
+        for line in w.lines {
+            writeln!(&mut smt, "{line}").unwrap();
+        }
+        indoc::writedoc!(
+            &mut smt,
+            "
+                (assert (= b0 {}))
+                (assert (= c0 {}))
+                (minimize a0)
+                (check-sat)
+                (eval a0)
+            ",
+            Writer::hex(self.b),
+            Writer::hex(self.c),
+        )
+        .unwrap();
+        smt
+    }
+
+    /// Writes the script from [`Vm::to_smt2`] to a `.smt2` file, for
+    /// cross-checking with an external solver
+    pub fn write_smt2<P: AsRef<std::path::Path>>(
+        &self,
+        path: P,
+        target: &[u8],
+        b: Option<u64>,
+        c: Option<u64>,
+    ) -> std::io::Result<()> {
+        std::fs::write(path, self.with_regs(0, b, c).to_smt2(target))
+    }
+
+    /// Solves for `A` using an external `z3` process
+    fn solve_z3(&self, target: &[u8]) -> Option<u64> {
+        let smt = self.to_smt2(target);
+
+        use std::process::{Command, Stdio};
+        let mut z3 = Command::new("z3")
+            .arg("-in")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .expect("failed to call `z3`; is it installed?");
+
+        let mut stdin = z3.stdin.take().expect("Failed to open stdin");
+        stdin
+            .write_all(smt.as_bytes())
+            .expect("failed to write to z3");
+        drop(stdin);
+        let output = z3.wait_with_output().expect("Failed to read stdout");
+        let out = String::from_utf8(output.stdout).unwrap();
+        if out.starts_with("unsat") {
+            return None;
+        }
+        let Some(v) = out
+            .lines()
+            .nth(1)
+            .and_then(|i| u64::from_str_radix(&i[2..], 16).ok())
+        else {
+            panic!("z3 failed:\n{}", out);
+        };
+        Some(v)
+    }
 }
 
 struct Writer<'a> {
@@ -139,6 +412,7 @@ struct Writer<'a> {
     t: usize,
     ip: usize,
     tape: &'a [u8],
+    target: &'a [u8],
     lines: Vec<String>,
 }
 
@@ -187,7 +461,7 @@ impl Writer<'_> {
                 ));
                 self.ib += 1;
             }
-            Opcode::Jnz(_) if self.t == self.tape.len() => self
+            Opcode::Jnz(_) if self.t == self.target.len() => self
                 .lines
                 .push(format!("(assert (= a{} {}))", self.ia, Self::hex(0))),
             Opcode::Jnz(v) => {
@@ -207,12 +481,17 @@ impl Writer<'_> {
                 ));
                 self.ib += 1;
             }
+            Opcode::Out(_) if self.t == self.target.len() => {
+                // Printing too much can't match the target
+                self.lines.push("(assert false)".to_owned());
+                self.ip = self.tape.len();
+            }
             Opcode::Out(v) => {
                 self.lines.push(format!(
                     "(assert (= (bvand {} {}) {}))",
                     self.combo(v),
                     Self::hex(0b111),
-                    Self::hex(self.tape[self.t]),
+                    Self::hex(self.target[self.t]),
                 ));
                 self.t += 1;
             }
@@ -256,66 +535,9 @@ pub fn solve(s: &str) -> (String, u64) {
     }
     s.pop();
 
-    let mut w = Writer {
-        ia: 0,
-        ib: 0,
-        ic: 0,
-        t: 0,
-        ip: 0,
-        tape: &vm.tape,
-        lines: vec![],
-    };
-    w.run();
-    let mut smt = String::new();
-    for i in 0..=w.ia {
-        writeln!(&mut smt, "(declare-const a{i} (_ BitVec 64))").unwrap();
-    }
-    for i in 0..=w.ib {
-        writeln!(&mut smt, "(declare-const b{i} (_ BitVec 64))").unwrap();
-    }
-    for i in 0..=w.ic {
-        writeln!(&mut smt, "(declare-const c{i} (_ BitVec 64))").unwrap();
-    }
-    for line in w.lines {
-        writeln!(&mut smt, "{line}").unwrap();
-    }
-    indoc::writedoc!(
-        &mut smt,
-        "
-            (assert (= b0 {}))
-            (assert (= c0 {}))
-            (minimize a0)
-            (check-sat)
-            (eval a0)
-        ",
-        Writer::hex(vm.b),
-        Writer::hex(vm.c),
-    )
-    .unwrap();
-
-    use std::process::{Command, Stdio};
-    let mut z3 = Command::new("z3")
-        .arg("-in")
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .spawn()
-        .expect("failed to call `z3`; is it installed?");
-
-    let mut stdin = z3.stdin.take().expect("Failed to open stdin");
-    stdin
-        .write_all(smt.as_bytes())
-        .expect("failed to write to z3");
-    drop(stdin);
-    let output = z3.wait_with_output().expect("Failed to read stdout");
-    let out = String::from_utf8(output.stdout).unwrap();
-    let Some(v) = out
-        .lines()
-        .nth(1)
-        .and_then(|i| u64::from_str_radix(&i[2..], 16).ok())
-    else {
-        panic!("z3 failed:\n{}", out);
-    };
-
+    // Shift loops are searched natively, and only fall back to z3 if the
+    // program has some other shape
+    let v = vm.find_a(&vm.tape, None, None).expect("no quine exists");
     (s, v)
 }
 
@@ -346,4 +568,101 @@ mod test {
         "};
         assert_eq!(solve(QUINE).1, 117440);
     }
+
+    #[test]
+    fn shift_loop() {
+        // A typical puzzle input, which recomputes B and C from A each loop
+        const INPUT: &str = indoc::indoc! {"
+            Register A: 0
+            Register B: 0
+            Register C: 0
+
+            Program: 2,4,1,5,7,5,1,6,0,3,4,0,5,5,3,0
+        "};
+        let vm = Vm::new(INPUT);
+        assert!(vm.is_shift_loop());
+        let a = solve(INPUT).1;
+        let mut check = vm.clone();
+        check.a = a;
+        assert_eq!(check.run_to_completion(), vm.tape);
+
+        // The search only applies to programs with one loop
+        let vm = Vm::new("A: 0\nB: 0\nC: 0\n\nProgram: 0,3,3,0,5,4,3,0");
+        assert!(!vm.is_shift_loop());
+    }
+
+    #[test]
+    fn find_a() {
+        // Prints each octal digit of A, after shifting it by three bits
+        let vm = Vm::from_program(&[0, 3, 5, 4, 3, 0]);
+        assert_eq!(vm.find_a(&[5, 5, 3, 0], None, None), Some(1896));
+        assert_eq!(
+            vm.find_a_in(&[5, 5, 3, 0], None, None, 0..4096),
+            (1896..1904).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vm.find_a_in(&[5, 5, 3, 0], None, None, 1900..1902),
+            [1900, 1901]
+        );
+
+        // Prints each octal digit of A, XOR'd with C
+        let vm = Vm::from_program(&[2, 4, 4, 0, 5, 5, 0, 3, 3, 0]);
+        assert_eq!(vm.find_a(&[1, 2, 3], None, Some(0)), Some(0o321));
+        assert_eq!(vm.find_a(&[1, 2, 3], None, Some(7)), Some(0o456));
+
+        // Not a shift loop, so this is checked by brute force
+        let vm = Vm::from_program(&[0, 1, 5, 4, 3, 0]);
+        assert!(!vm.is_shift_loop());
+        let target = [4, 6, 3, 5, 6, 3, 5, 2, 1, 0];
+        assert_eq!(vm.find_a_in(&target, None, None, 700..800), [728, 729]);
+
+        // A shift loop always prints something, even when A = 0
+        let vm = Vm::from_program(&[0, 3, 5, 4, 3, 0]);
+        assert_eq!(vm.with_regs(0, None, None).run_to_completion(), [0]);
+        assert_eq!(vm.find_a(&[], None, None), None);
+        assert!(vm.find_a_in(&[], None, None, 0..4096).is_empty());
+    }
+
+    #[test]
+    fn smt2() {
+        let vm = Vm::from_program(&[2, 4, 4, 0, 5, 5, 0, 3, 3, 0]);
+        let path = std::env::temp_dir().join("day17-smt2-test.smt2");
+        vm.write_smt2(&path, &[1, 2, 3], None, Some(7)).unwrap();
+        let smt = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(smt.contains("(declare-const a0 (_ BitVec 64))"));
+        assert!(smt.contains("(assert (= c0 #x0000000000000007))"));
+        assert!(smt.contains("(minimize a0)"));
+    }
+
+    #[test]
+    fn carried_registers() {
+        // `bxl 1; out B; adv 3; jnz 0` toggles B each iteration, so outputs
+        // depend on earlier iterations and the loop can't be solved natively
+        let vm = Vm::from_program(&[1, 1, 5, 5, 0, 3, 3, 0]);
+        assert!(!vm.is_shift_loop());
+        assert_eq!(
+            vm.find_a_in(&[1, 0], None, None, 0..64),
+            (8..64).collect::<Vec<_>>()
+        );
+
+        // Reading B before `bst` recomputes it also carries state
+        let vm = Vm::from_program(&[5, 5, 2, 4, 0, 3, 3, 0]);
+        assert!(!vm.is_shift_loop());
+
+        // A constant C is fine, as is B after it's recomputed from A
+        let vm = Vm::from_program(&[2, 4, 4, 0, 1, 3, 5, 5, 0, 3, 3, 0]);
+        assert!(vm.is_shift_loop());
+
+        // A shift loop with no solution doesn't need z3
+        let vm = Vm::from_program(&[0, 3, 5, 4, 3, 0]);
+        assert_eq!(vm.find_a(&[1], None, None), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "no quine exists")]
+    fn no_quine() {
+        // Always prints 0, so it can never print its own program
+        solve("A: 0\nB: 0\nC: 0\n\nProgram: 0,3,5,0,3,0");
+    }
 }